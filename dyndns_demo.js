@@ -1,12 +1,27 @@
 bindAddress("0.0.0.0", 53535);
 // No upstream servers
 
+// Keep the dynamic records across restarts
+openStore("dyndns_store.json");
+
 const OWN_ROOT = ["dyn", "domain", "tld"];
 const OWN_ROOT_J = OWN_ROOT.join('.');
-const KEYS = {
-    subdomain: 'secret_key1',
-}
-let dynamicBindings = {};
+// Maps each subdomain to its update key, e.g. `subdomain=secret_key1`. Must not be world-readable.
+const KEYS = secrets(env('DYNDNS_KEYS_FILE', 'dyndns_keys.env'));
+// Dynamic records not refreshed within this many seconds are dropped
+const RECORD_LIFETIME = 24 * 3600;
+const DYNAMIC_PREFIX = "dynamic/";
+
+setInterval(function expireDynamic(){
+    const now = Math.floor(new Date().getTime() / 1000);
+    for(let key of store.keys()){
+        if(!key.startsWith(DYNAMIC_PREFIX)) continue;
+        if(now - store.get(key).updated > RECORD_LIFETIME){
+            console.log(`Dynamic record for domain ${key.substring(DYNAMIC_PREFIX.length)} expired`);
+            store.delete(key);
+        }
+    }
+}, 60 * 1000);
 
 function checkIfValid(nameTokens){
     if(nameTokens.length <= OWN_ROOT.length) return false;
@@ -25,7 +40,7 @@ addUniversalBinding(function getDynamic(name, rrtype){
     if(tokens.length !== OWN_ROOT.length + 1) return null;
     if(rrtype !== RR_A) return null;
 
-    const record = dynamicBindings[getFromLast(tokens, 0)];
+    const record = store.get(DYNAMIC_PREFIX + getFromLast(tokens, 0));
     if(record === undefined){
         return (
             {
@@ -42,13 +57,13 @@ addUniversalBinding(function getDynamic(name, rrtype){
         {
             type: "A",
             ttl: 3600,
-            ip: record,
+            ip: record.ip,
             authoritative: true,
         }
     ]
 })
 
-addUniversalBinding(function configure(name, rrtype, rrclass, peerAddress){
+addUniversalBinding(function configure(name, rrtype, rrclass, peerAddress, ownAddress, context){
     const tokens = name.split(".");
     // If valid request...
     if(!checkIfValid(tokens)) return null;
@@ -69,16 +84,14 @@ addUniversalBinding(function configure(name, rrtype, rrclass, peerAddress){
     if(Math.abs(providedTimestamp - currentTimestamp) > 60) return null;
     
     const key = KEYS[domain];
-    const toSha = key + '/' + timestamp;
-    const validChecksum = sha256(toSha).substring(0, 16);
+    const validChecksum = hmac('sha256', key, domain + '/' + timestamp).substring(0, 16);
 
     // If checksums match
-    if(validChecksum.toLowerCase() !== checksum.toLowerCase()) return null;
+    if(!timingSafeEqual(validChecksum, checksum.toLowerCase())) return null;
 
     // Set the dynamic remap table
-    if(peerAddress.includes(':')) peerAddress = peerAddress.substring(0, peerAddress.indexOf(":"));
-    dynamicBindings[domain] = peerAddress;
-    console.log(`Updated dynamic record for domain ${domain} to ${peerAddress}`);
+    store.set(DYNAMIC_PREFIX + domain, { ip: context.clientIp, updated: currentTimestamp });
+    console.log(`Updated dynamic record for domain ${domain} to ${context.clientIp}`);
     return [
         {
             type: "CNAME",
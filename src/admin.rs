@@ -0,0 +1,276 @@
+// Optional admin REST API. Requests are handled by the HTTP server's own thread, and
+// forwarded to the main thread, which owns the JS bridge.
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use num_traits::FromPrimitive;
+use quick_js::JsValue;
+use rustdns::{Class, Question, Type};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{oneshot, Mutex};
+use tracing::{error, info, warn};
+
+use crate::crypto::timing_safe_equal;
+use crate::jsbridge::JSBridge;
+use crate::jsbridge::QueryContext;
+use crate::messages::{SUPPORTED_RR, SUPPORTED_RR_NAMES};
+use crate::querylog::describe_record;
+use crate::server::{answer_question, cache_size, flush_cache, upstream_health};
+use crate::stats::STATS;
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+pub enum AdminCommand {
+    ListBindings,
+    AddRecord(Value),
+    RemoveBinding { name: String, rrtype: String },
+    FlushCache,
+    ListUpstreams,
+    SetBlocking(bool),
+    Reload,
+    Stats,
+    Lookup { name: String, rrtype: String },
+}
+
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub reply: oneshot::Sender<Result<Value, String>>,
+}
+
+// Runs an admin function that changes what the bindings answer. The cache is flushed afterwards,
+// as it may hold answers from before the change - blocked ones for a very long time.
+async fn change_bindings(
+    bridge: &Rc<Mutex<JSBridge>>,
+    function: &str,
+    args: Vec<JsValue>,
+) -> Result<Value, String> {
+    let result = bridge.lock().await.call_admin(function, args);
+    if result.is_ok() {
+        flush_cache().await;
+    }
+    result
+}
+
+// Runs every command except Reload, which needs the server set and is handled by the reload loop.
+pub async fn execute(command: AdminCommand, bridge: &Rc<Mutex<JSBridge>>) -> Result<Value, String> {
+    match command {
+        AdminCommand::ListBindings => bridge
+            .lock()
+            .await
+            .call_admin("badns_adminListBindings", vec![]),
+        AdminCommand::AddRecord(record) => {
+            let args = vec![JsValue::String(record.to_string())];
+            change_bindings(bridge, "badns_adminAddRecord", args).await
+        }
+        AdminCommand::RemoveBinding { name, rrtype } => {
+            let args = vec![JsValue::String(name), JsValue::String(rrtype)];
+            change_bindings(bridge, "badns_adminRemoveBinding", args).await
+        }
+        AdminCommand::FlushCache => Ok(json!({ "flushed": flush_cache().await })),
+        AdminCommand::ListUpstreams => {
+            let upstreams = bridge.lock().await.upstreams.lock().unwrap().clone();
+            Ok(upstream_health(&upstreams))
+        }
+        AdminCommand::SetBlocking(enabled) => {
+            let args = vec![JsValue::Bool(enabled)];
+            change_bindings(bridge, "badns_adminSetBlocking", args).await
+        }
+        AdminCommand::Reload => Err("Reload must be handled by the reload loop".to_string()),
+        AdminCommand::Stats => {
+            let mut instance = bridge.lock().await;
+            let bindings = instance.call_admin("badns_adminListBindings", vec![])?;
+            let upstreams = instance.upstreams.lock().unwrap().clone();
+            let mut stats = STATS.lock().unwrap().to_json();
+            stats["cacheSize"] = json!(cache_size().await);
+            stats["upstreams"] = upstream_health(&upstreams);
+            stats["blockingEnabled"] = bindings["blockingEnabled"].clone();
+            Ok(stats)
+        }
+        AdminCommand::Lookup { name, rrtype } => lookup(name, &rrtype, bridge).await,
+    }
+}
+
+// Answers a question the same way a DNS client would get it answered.
+async fn lookup(
+    mut name: String,
+    rrtype: &str,
+    bridge: &Rc<Mutex<JSBridge>>,
+) -> Result<Value, String> {
+    let r#type = SUPPORTED_RR_NAMES
+        .iter()
+        .position(|e| e.strip_prefix("RR_") == Some(rrtype))
+        .and_then(|i| Type::from_u16(SUPPORTED_RR[i]))
+        .ok_or(format!("Unsupported type {}", rrtype))?;
+    if !name.ends_with('.') {
+        name.push('.');
+    }
+    let question = Question {
+        name,
+        r#type,
+        class: Class::Internet,
+    };
+    let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let context = QueryContext {
+        peer: local,
+        local,
+        listener: "admin".to_string(),
+        transport: "admin",
+        id: 0,
+        recursion_desired: true,
+        checking_disabled: false,
+        dnssec_ok: false,
+        client_subnet: None,
+    };
+    let answer = answer_question(&question, bridge, &context).await;
+    Ok(json!({
+        "name": question.name,
+        "type": rrtype,
+        "records": answer.records.iter().map(describe_record).collect::<Vec<_>>(),
+        "authorities": answer.authorities.iter().map(describe_record).collect::<Vec<_>>(),
+        "rcode": format!("{:?}", answer.rcode),
+        "source": answer.source,
+    }))
+}
+
+fn respond(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    respond(status, json!({ "error": message }))
+}
+
+fn is_authorized(request: &Request<Body>, token: &str) -> bool {
+    let header = match request.headers().get(AUTHORIZATION) {
+        Some(e) => e.to_str().unwrap_or(""),
+        None => return false,
+    };
+    match header.strip_prefix("Bearer ") {
+        Some(given) => timing_safe_equal(given.to_string(), token.to_string()),
+        None => false,
+    }
+}
+
+async fn read_json(request: Request<Body>) -> Result<Value, String> {
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        .map_err(|e| e.to_string())?;
+    serde_json::from_slice(&body).map_err(|e| format!("Invalid JSON body ({})", e))
+}
+
+async fn parse_command(request: Request<Body>) -> Result<AdminCommand, (StatusCode, String)> {
+    let segments: Vec<String> = request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|e| !e.is_empty())
+        .map(|e| e.to_string())
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(|e| e.as_str()).collect();
+    let method = request.method().clone();
+    let bad_request = |e: String| (StatusCode::BAD_REQUEST, e);
+    let command = match (&method, segments.as_slice()) {
+        (&Method::GET, ["bindings"]) => AdminCommand::ListBindings,
+        (&Method::POST, ["records"]) => {
+            AdminCommand::AddRecord(read_json(request).await.map_err(bad_request)?)
+        }
+        (&Method::DELETE, ["records", rrtype, name]) => AdminCommand::RemoveBinding {
+            name: name.to_string(),
+            rrtype: rrtype.to_string(),
+        },
+        (&Method::POST, ["cache", "flush"]) => AdminCommand::FlushCache,
+        (&Method::GET, ["upstreams"]) => AdminCommand::ListUpstreams,
+        (&Method::PUT, ["blocking"]) => {
+            let body = read_json(request).await.map_err(bad_request)?;
+            match body["enabled"].as_bool() {
+                Some(enabled) => AdminCommand::SetBlocking(enabled),
+                None => return Err(bad_request("Expected {\"enabled\": boolean}".to_string())),
+            }
+        }
+        (&Method::POST, ["reload"]) => AdminCommand::Reload,
+        (&Method::GET, ["stats"]) => AdminCommand::Stats,
+        (&Method::GET, ["lookup", rrtype, name]) => AdminCommand::Lookup {
+            name: name.to_string(),
+            rrtype: rrtype.to_string(),
+        },
+        _ => return Err((StatusCode::NOT_FOUND, "Unknown endpoint".to_string())),
+    };
+    Ok(command)
+}
+
+async fn admin_service(
+    request: Request<Body>,
+    peer_address: SocketAddr,
+    token: &'static str,
+    commands: UnboundedSender<AdminRequest>,
+) -> Result<Response<Body>, Infallible> {
+    // The dashboard itself holds no data, it asks for the token before calling the API.
+    if request.method() == Method::GET && request.uri().path() == "/" {
+        return Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(DASHBOARD))
+            .unwrap());
+    }
+    if !is_authorized(&request, token) {
+        warn!(target: "admin", peer = %peer_address, "Unauthorized request");
+        return Ok(error_response(StatusCode::UNAUTHORIZED, "Unauthorized"));
+    }
+    info!(target: "admin", peer = %peer_address, "{} {}", request.method(), request.uri().path());
+    let command = match parse_command(request).await {
+        Ok(e) => e,
+        Err((status, message)) => return Ok(error_response(status, &message)),
+    };
+    let (reply, response) = oneshot::channel();
+    if commands.send(AdminRequest { command, reply }).is_err() {
+        return Ok(error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "baDNS is shutting down",
+        ));
+    }
+    Ok(match response.await {
+        Ok(Ok(result)) => respond(StatusCode::OK, result),
+        Ok(Err(e)) => error_response(StatusCode::BAD_REQUEST, &e),
+        Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "No reply from baDNS"),
+    })
+}
+
+#[tokio::main]
+pub async fn run_admin_server(
+    address: SocketAddr,
+    token: String,
+    commands: UnboundedSender<AdminRequest>,
+) {
+    let token: &'static str = Box::leak(token.into_boxed_str());
+    let make_service = make_service_fn(|conn: &AddrStream| {
+        let peer_address = conn.remote_addr();
+        let commands = commands.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                admin_service(request, peer_address, token, commands.clone())
+            }))
+        }
+    });
+    let server = match Server::try_bind(&address) {
+        Ok(e) => e.serve(make_service),
+        Err(e) => {
+            error!(target: "admin", "Cannot bind {} ({})", address, e);
+            return;
+        }
+    };
+
+    info!(target: "admin", "Running admin API on {}", address);
+
+    if let Err(e) = server.await {
+        error!(target: "admin", "Server error: {}", e);
+    }
+}
@@ -0,0 +1,95 @@
+use rustdns::Question;
+use serde_json::Value;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::futures::Notified;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{Mutex, Notify};
+use tracing::error;
+
+use crate::convert::record_to_json;
+use crate::fetch::fetch;
+use crate::jsbridge::{Address, JSBridge};
+use crate::server::resolve;
+use crate::validator::Validator;
+
+pub enum Call {
+    Fetch {
+        url: String,
+        options: Value,
+    },
+    Resolve {
+        question: Question,
+        upstreams: Vec<Address>,
+        validator: Option<Arc<Validator>>,
+    },
+}
+
+pub struct CallRequest {
+    pub id: i32,
+    pub call: Call,
+}
+
+// Results of finished calls, waiting to be handed to JS.
+#[derive(Default)]
+pub struct Completions {
+    finished: StdMutex<Vec<(i32, Result<String, String>)>>,
+    notify: Notify,
+}
+
+impl Completions {
+    fn complete(&self, id: i32, result: Result<String, String>) {
+        self.finished.lock().unwrap().push((id, result));
+        self.notify.notify_waiters();
+    }
+
+    pub fn take(&self) -> Vec<(i32, Result<String, String>)> {
+        std::mem::take(&mut self.finished.lock().unwrap())
+    }
+
+    // Resolves once another call finished. Create it before checking for results, so that none
+    // slip in between.
+    pub fn finished(&self) -> Notified<'_> {
+        self.notify.notified()
+    }
+}
+
+async fn run(call: Call) -> Result<String, String> {
+    match call {
+        Call::Fetch { url, options } => fetch(&url, &options).await.map(|e| e.to_string()),
+        Call::Resolve {
+            question,
+            upstreams,
+            validator,
+        } => {
+            let records = resolve(&question, &upstreams, validator.as_deref()).await;
+            Ok(Value::Array(records.iter().filter_map(record_to_json).collect()).to_string())
+        }
+    }
+}
+
+// Runs the fetch() and resolve() calls made from JS, each in a task of its own so that nothing
+// blocks the listeners meanwhile. A handler waiting for a result takes it from `completions`
+// itself; otherwise it's handed over once the bridge is free. Like timers, calls made by a
+// bridge a reload replaced are dropped.
+pub async fn run_calls(
+    bridge: Rc<Mutex<JSBridge>>,
+    mut requests: UnboundedReceiver<CallRequest>,
+    completions: Arc<Completions>,
+    generation: u64,
+) {
+    while let Some(request) = requests.recv().await {
+        let bridge_reference = bridge.clone();
+        let completions = completions.clone();
+        tokio::task::spawn_local(async move {
+            completions.complete(request.id, run(request.call).await);
+            let mut instance = bridge_reference.lock().await;
+            if instance.generation != generation {
+                return;
+            }
+            if let Err(e) = instance.deliver_completions() {
+                error!(target: "js", "Failed handing over a fetch() or resolve() result! ({})", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,110 @@
+// Typed conversions between the JSON values exchanged with the JS context and rustdns' types.
+// Everything coming from JS goes through here, so that a malformed handler response turns into
+// an error naming the offending field instead of a panic.
+use num_traits::cast::FromPrimitive;
+use rustdns::{Class, Question, Record, Resource, Type};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub struct ConversionError {
+    pub field: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "field `{}`: {}", self.field, self.reason)
+    }
+}
+
+fn fail<T>(field: &str, reason: &str) -> Result<T, ConversionError> {
+    Err(ConversionError {
+        field: field.to_string(),
+        reason: reason.to_string(),
+    })
+}
+
+pub fn field_str<'a>(value: &'a Value, field: &str) -> Result<&'a str, ConversionError> {
+    match &value[field] {
+        Value::String(e) => Ok(e),
+        Value::Null => fail(field, "missing"),
+        _ => fail(field, "expected a string"),
+    }
+}
+
+pub fn field_u64(value: &Value, field: &str) -> Result<u64, ConversionError> {
+    match value[field].as_u64() {
+        Some(e) => Ok(e),
+        None if value[field].is_null() => fail(field, "missing"),
+        None => fail(field, "expected a non-negative integer"),
+    }
+}
+
+pub fn field_parse<T: FromStr>(value: &Value, field: &str) -> Result<T, ConversionError> {
+    let string = field_str(value, field)?;
+    match string.parse() {
+        Ok(e) => Ok(e),
+        Err(_) => fail(field, &format!("cannot parse {:?}", string)),
+    }
+}
+
+pub fn field_type(value: &Value, field: &str) -> Result<Type, ConversionError> {
+    let number = field_u64(value, field)?;
+    match i32::try_from(number).ok().and_then(Type::from_i32) {
+        Some(e) => Ok(e),
+        None => fail(field, &format!("unknown rrtype {}", number)),
+    }
+}
+
+pub fn field_class(value: &Value, field: &str) -> Result<Class, ConversionError> {
+    let number = field_u64(value, field)?;
+    match i32::try_from(number).ok().and_then(Class::from_i32) {
+        Some(e) => Ok(e),
+        None => fail(field, &format!("unknown rrclass {}", number)),
+    }
+}
+
+// Converts a record into the same shape handlers return, so resolved answers can be passed through.
+pub fn record_to_json(record: &Record) -> Option<Value> {
+    let (type_name, field, value) = match &record.resource {
+        Resource::A(ip) => ("A", "ip", ip.to_string()),
+        Resource::AAAA(ip) => ("AAAA", "ip", ip.to_string()),
+        Resource::CNAME(target) => ("CNAME", "target", target.clone()),
+        _ => return None,
+    };
+    let mut object = Map::new();
+    object.insert("name".to_string(), Value::from(record.name.clone()));
+    object.insert("type".to_string(), Value::from(type_name));
+    object.insert("ttl".to_string(), Value::from(record.ttl.as_secs()));
+    object.insert(field.to_string(), Value::from(value));
+    Some(Value::Object(object))
+}
+
+pub fn parse_record(resp: &Value, question: &Question) -> Result<Record, ConversionError> {
+    if !resp.is_object() {
+        return fail("<record>", "expected an object");
+    }
+    let name = match &resp["name"] {
+        Value::Null => question.name.clone(),
+        _ => field_str(resp, "name")?.to_string(),
+    };
+    let ttl = Duration::from_secs(field_u64(resp, "ttl")?);
+    let class = question.class;
+
+    let resource = match field_str(resp, "type")? {
+        "A" => Resource::A(field_parse(resp, "ip")?),
+        "AAAA" => Resource::AAAA(field_parse(resp, "ip")?),
+        "CNAME" => Resource::CNAME(field_str(resp, "target")?.to_string()),
+        type_name => return fail("type", &format!("unsupported type {:?}", type_name)),
+    };
+
+    Ok(Record {
+        name,
+        class,
+        ttl,
+        resource,
+    })
+}
@@ -0,0 +1,72 @@
+use data_encoding::{BASE32, BASE32_NOPAD, BASE64, HEXLOWER, HEXLOWER_PERMISSIVE};
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use subtle::ConstantTimeEq;
+
+// Binary data crosses the JS boundary as strings in one of these encodings:
+// 'utf8', 'hex', 'base64' or 'base32'.
+fn decode(data: &str, encoding: &str) -> Result<Vec<u8>, String> {
+    let result = match encoding {
+        "utf8" => return Ok(data.as_bytes().to_vec()),
+        "hex" => HEXLOWER_PERMISSIVE.decode(data.as_bytes()),
+        "base64" => BASE64.decode(data.as_bytes()),
+        // Base32 secrets are commonly shared without padding and in lowercase.
+        "base32" => BASE32_NOPAD.decode(data.trim_end_matches('=').to_uppercase().as_bytes()),
+        _ => return Err(format!("Unknown encoding {}!", encoding)),
+    };
+    result.map_err(|e| format!("Cannot decode {}: {}", encoding, e))
+}
+
+fn encode(bytes: &[u8], encoding: &str) -> Result<String, String> {
+    match encoding {
+        "utf8" => String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string()),
+        "hex" => Ok(HEXLOWER.encode(bytes)),
+        "base64" => Ok(BASE64.encode(bytes)),
+        "base32" => Ok(BASE32.encode(bytes)),
+        _ => Err(format!("Unknown encoding {}!", encoding)),
+    }
+}
+
+pub fn convert(data: String, from: String, to: String) -> Result<String, String> {
+    encode(&decode(&data, &from)?, &to)
+}
+
+fn compute<M: Mac + KeyInit>(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Returns the HMAC of the UTF-8 `data` as hex.
+pub fn hmac(
+    algorithm: String,
+    key: String,
+    data: String,
+    key_encoding: String,
+) -> Result<String, String> {
+    let key = decode(&key, &key_encoding)?;
+    let digest = match algorithm.to_lowercase().as_str() {
+        "sha1" => compute::<Hmac<Sha1>>(&key, data.as_bytes()),
+        "sha256" => compute::<Hmac<Sha256>>(&key, data.as_bytes()),
+        "sha512" => compute::<Hmac<Sha512>>(&key, data.as_bytes()),
+        _ => return Err(format!("Unsupported HMAC algorithm {}!", algorithm)),
+    };
+    encode(&digest, "hex")
+}
+
+pub fn random_bytes(count: i32, encoding: String) -> Result<String, String> {
+    if !(0..=65536).contains(&count) {
+        return Err("randomBytes() can generate between 0 and 65536 bytes!".to_string());
+    }
+    let mut bytes = vec![0u8; count as usize];
+    OsRng.fill_bytes(&mut bytes);
+    encode(&bytes, &encoding)
+}
+
+pub fn timing_safe_equal(a: String, b: String) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
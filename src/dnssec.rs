@@ -0,0 +1,746 @@
+// DNSSEC building blocks (RFC 4034, RFC 5155) working on raw wire data: RRsets in canonical
+// form with the signatures covering them, DNSKEY and DS records, and NSEC/NSEC3 data.
+use data_encoding::{BASE32HEX_NOPAD, HEXUPPER};
+use ring::rand::SystemRandom;
+use ring::signature::{
+    self, EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaPublicKeyComponents, UnparsedPublicKey,
+    ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING, ECDSA_P384_SHA384_FIXED, ED25519,
+};
+use sha1::{Digest, Sha1};
+use sha2::{Sha256, Sha384};
+use std::cmp::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::wire::{
+    build_message, parse_records, read_name, read_u16, read_u32, write_name, write_raw_record,
+    RawQuestion, RawRecord, Section, FLAG_CD, FLAG_RD, RR_OPT,
+};
+use crate::zone::normalize;
+
+pub const RR_DS: u16 = 43;
+pub const RR_RRSIG: u16 = 46;
+pub const RR_NSEC: u16 = 47;
+pub const RR_DNSKEY: u16 = 48;
+pub const RR_NSEC3: u16 = 50;
+// Marks names that don't exist in compact denial of existence (RFC 9824)
+pub const RR_NXNAME: u16 = 128;
+pub const ALGORITHM_ECDSAP256SHA256: u8 = 13;
+pub const ALGORITHM_ED25519: u8 = 15;
+pub const FLAGS_ZSK: u16 = 0x0100;
+pub const FLAGS_KSK: u16 = 0x0101;
+// Signed answers rarely fit in the classic 512 bytes
+const PAYLOAD_SIZE: u16 = 4096;
+const EDNS_DO: u32 = 0x8000;
+const NSEC3_OPT_OUT: u8 = 0x01;
+
+// A recursive query with the DO bit set, so that the answer carries its signatures, and the CD
+// bit set, so that a validating upstream passes bogus data on instead of failing.
+pub fn query(name: &str, r#type: u16) -> Vec<u8> {
+    let question = RawQuestion {
+        name: name.to_string(),
+        r#type,
+        class: 1,
+    };
+    let mut opt = Vec::new();
+    write_raw_record(&mut opt, ".", RR_OPT, PAYLOAD_SIZE, EDNS_DO, &[]);
+    build_message(
+        rand::random(),
+        FLAG_RD | FLAG_CD,
+        Some(&question),
+        [(&[], 0), (&[], 0), (&opt, 1)],
+    )
+}
+
+// Reads a name like read_name, but keeps it in wire format: uncompressed, and lowercased if
+// asked to, as canonical form wants (RFC 4034 section 6.2).
+fn read_wire_name(buffer: &[u8], position: usize, lowercase: bool) -> Option<(Vec<u8>, usize)> {
+    let mut name = Vec::new();
+    let mut current = position;
+    let mut end = None;
+    for _ in 0..128 {
+        let length = *buffer.get(current)? as usize;
+        match length & 0xC0 {
+            0x00 if length == 0 => {
+                name.push(0);
+                return Some((name, end.unwrap_or(current + 1)));
+            }
+            0x00 => {
+                let label = buffer.get(current + 1..current + 1 + length)?;
+                name.push(length as u8);
+                if lowercase {
+                    name.extend(label.iter().map(|e| e.to_ascii_lowercase()));
+                } else {
+                    name.extend_from_slice(label);
+                }
+                current += 1 + length;
+            }
+            0xC0 => {
+                let pointer = (read_u16(buffer, current)? & 0x3FFF) as usize;
+                end.get_or_insert(current + 2);
+                current = pointer;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+pub fn name_to_wire(name: &str) -> Vec<u8> {
+    let mut output = Vec::new();
+    write_name(&mut output, &normalize(name));
+    output
+}
+
+// Splits a wire name into its labels, without the root.
+fn labels(name: &[u8]) -> Vec<&[u8]> {
+    let mut labels = Vec::new();
+    let mut position = 0;
+    while let Some(&length) = name.get(position) {
+        if length == 0 {
+            break;
+        }
+        labels.extend(name.get(position + 1..position + 1 + length as usize));
+        position += 1 + length as usize;
+    }
+    labels
+}
+
+// The record's data in canonical form: the names in it uncompressed and lowercased.
+fn canonical_rdata(buffer: &[u8], record: &RawRecord) -> Option<Vec<u8>> {
+    expand_rdata(buffer, record, true)
+}
+
+// The record's data with the names in it uncompressed, so that it can be moved to another
+// message.
+fn expand_rdata(buffer: &[u8], record: &RawRecord, lowercase: bool) -> Option<Vec<u8>> {
+    // (bytes before the first name, number of names) of the types whose data holds names
+    let (prefix, names) = match record.r#type {
+        // NS, CNAME, PTR, DNAME
+        2 | 5 | 12 | 39 => (0, 1),
+        // MX, AFSDB, RT, KX
+        15 | 18 | 21 | 36 => (2, 1),
+        // SRV
+        33 => (6, 1),
+        // SOA, MINFO, RP
+        6 | 14 | 17 => (0, 2),
+        _ => return Some(record.rdata.to_vec()),
+    };
+    let end = record.rdata_start + record.rdata.len();
+    let mut output = record.rdata.get(..prefix)?.to_vec();
+    let mut position = record.rdata_start + prefix;
+    for _ in 0..names {
+        let (name, next) = read_wire_name(buffer, position, lowercase)?;
+        output.extend(name);
+        position = next;
+    }
+    output.extend_from_slice(buffer.get(position..end)?);
+    Some(output)
+}
+
+// A whole record, uncompressed.
+pub fn uncompressed_record(buffer: &[u8], record: &RawRecord) -> Option<Vec<u8>> {
+    let (owner, _) = read_wire_name(buffer, record.start, false)?;
+    let rdata = expand_rdata(buffer, record, false)?;
+    let mut output = Vec::new();
+    write_wire_record(
+        &mut output,
+        &owner,
+        record.r#type,
+        record.class,
+        record.ttl,
+        &rdata,
+    );
+    Some(output)
+}
+
+pub struct Signature {
+    pub type_covered: u16,
+    pub algorithm: u8,
+    // Owner name labels, fewer than the owner's own for answers synthesized from a wildcard
+    pub labels: u8,
+    original_ttl: u32,
+    expiration: u32,
+    inception: u32,
+    pub key_tag: u16,
+    // Normalized
+    pub signer: String,
+    // The RRSIG data up to the signature, with the signer name in canonical form
+    header: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+fn parse_signature(buffer: &[u8], record: &RawRecord) -> Option<Signature> {
+    let data = record.rdata;
+    let (signer_wire, signature_start) = read_wire_name(buffer, record.rdata_start + 18, true)?;
+    let (signer, _) = read_name(buffer, record.rdata_start + 18)?;
+    let mut header = data.get(..18)?.to_vec();
+    header.extend_from_slice(&signer_wire);
+    Some(Signature {
+        type_covered: read_u16(data, 0)?,
+        algorithm: data[2],
+        labels: data[3],
+        original_ttl: read_u32(data, 4)?,
+        expiration: read_u32(data, 8)?,
+        inception: read_u32(data, 12)?,
+        key_tag: read_u16(data, 16)?,
+        signer: normalize(&signer),
+        header,
+        signature: buffer
+            .get(signature_start..record.rdata_start + data.len())?
+            .to_vec(),
+    })
+}
+
+// All records of a section with the same owner, type and class.
+pub struct RrSet {
+    // Normalized
+    pub name: String,
+    // Canonical wire form
+    owner: Vec<u8>,
+    pub r#type: u16,
+    class: u16,
+    pub ttl: u32,
+    // In canonical form, without duplicates
+    pub rdatas: Vec<Vec<u8>>,
+    pub signatures: Vec<Signature>,
+}
+
+impl RrSet {
+    // An unsigned RRset of class IN, from data already in canonical form.
+    pub fn new(name: &str, r#type: u16, ttl: u32, rdatas: Vec<Vec<u8>>) -> RrSet {
+        RrSet {
+            name: normalize(name),
+            owner: name_to_wire(name),
+            r#type,
+            class: 1,
+            ttl,
+            rdatas,
+            signatures: Vec::new(),
+        }
+    }
+
+    // Appends the records to a message section, returning how many there were.
+    pub fn write(&self, output: &mut Vec<u8>) -> u16 {
+        for rdata in &self.rdatas {
+            write_wire_record(
+                output,
+                &self.owner,
+                self.r#type,
+                self.class,
+                self.ttl,
+                rdata,
+            );
+        }
+        self.rdatas.len() as u16
+    }
+
+    // Appends an RRSIG record for the RRset to a message section.
+    pub fn write_signature(&self, output: &mut Vec<u8>, rdata: &[u8]) {
+        write_wire_record(output, &self.owner, RR_RRSIG, self.class, self.ttl, rdata);
+    }
+}
+
+fn write_wire_record(
+    output: &mut Vec<u8>,
+    owner: &[u8],
+    r#type: u16,
+    class: u16,
+    ttl: u32,
+    rdata: &[u8],
+) {
+    output.extend_from_slice(owner);
+    output.extend_from_slice(&r#type.to_be_bytes());
+    output.extend_from_slice(&class.to_be_bytes());
+    output.extend_from_slice(&ttl.to_be_bytes());
+    output.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    output.extend_from_slice(rdata);
+}
+
+// Groups the records of a section into RRsets, each with the RRSIGs covering it.
+pub fn rrsets(buffer: &[u8], section: Section) -> Option<Vec<RrSet>> {
+    let records = parse_records(buffer)?;
+    let mut sets: Vec<RrSet> = Vec::new();
+    let mut signatures = Vec::new();
+    for record in records.iter().filter(|e| e.section == section) {
+        let (owner, _) = read_wire_name(buffer, record.start, true)?;
+        match record.r#type {
+            RR_OPT => continue,
+            RR_RRSIG => {
+                signatures.extend(parse_signature(buffer, record).map(|e| (owner, e)));
+                continue;
+            }
+            _ => {}
+        }
+        let rdata = canonical_rdata(buffer, record)?;
+        let existing = sets
+            .iter_mut()
+            .find(|e| e.owner == owner && e.r#type == record.r#type && e.class == record.class);
+        match existing {
+            Some(set) => {
+                set.ttl = set.ttl.min(record.ttl);
+                if !set.rdatas.contains(&rdata) {
+                    set.rdatas.push(rdata);
+                }
+            }
+            None => sets.push(RrSet {
+                name: normalize(&read_name(buffer, record.start)?.0),
+                owner,
+                r#type: record.r#type,
+                class: record.class,
+                ttl: record.ttl,
+                rdatas: vec![rdata],
+                signatures: Vec::new(),
+            }),
+        }
+    }
+    for (owner, signature) in signatures {
+        let covered = sets
+            .iter_mut()
+            .find(|e| e.owner == owner && e.r#type == signature.type_covered);
+        if let Some(set) = covered {
+            set.signatures.push(signature);
+        }
+    }
+    Some(sets)
+}
+
+// The owner name a signature was made over: the RRset's own, or for answers synthesized from a
+// wildcard, the wildcard with as many labels as the signature says.
+fn signing_owner(owner: &[u8], label_count: u8) -> Option<Vec<u8>> {
+    let labels = labels(owner);
+    let count = label_count as usize;
+    if count > labels.len() {
+        return None;
+    }
+    if count == labels.len() {
+        return Some(owner.to_vec());
+    }
+    let mut output = vec![1, b'*'];
+    for label in &labels[labels.len() - count..] {
+        output.push(label.len() as u8);
+        output.extend_from_slice(label);
+    }
+    output.push(0);
+    Some(output)
+}
+
+// The data an RRSIG signs (RFC 4034 section 3.1.8.1): the RRSIG's own data up to the
+// signature, then the RRset in canonical form.
+fn rrset_data(header: &[u8], owner: &[u8], set: &RrSet, original_ttl: u32) -> Vec<u8> {
+    let mut rdatas = set.rdatas.clone();
+    rdatas.sort();
+    let mut output = header.to_vec();
+    for rdata in rdatas {
+        output.extend_from_slice(owner);
+        output.extend_from_slice(&set.r#type.to_be_bytes());
+        output.extend_from_slice(&set.class.to_be_bytes());
+        output.extend_from_slice(&original_ttl.to_be_bytes());
+        output.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        output.extend_from_slice(&rdata);
+    }
+    output
+}
+
+fn signed_data(set: &RrSet, signature: &Signature) -> Option<Vec<u8>> {
+    let owner = signing_owner(&set.owner, signature.labels)?;
+    Some(rrset_data(
+        &signature.header,
+        &owner,
+        set,
+        signature.original_ttl,
+    ))
+}
+
+#[derive(Clone)]
+pub struct DnsKey {
+    pub flags: u16,
+    pub algorithm: u8,
+    pub key_tag: u16,
+    public_key: Vec<u8>,
+    rdata: Vec<u8>,
+}
+
+impl DnsKey {
+    pub fn parse(rdata: &[u8]) -> Option<DnsKey> {
+        Some(DnsKey {
+            flags: read_u16(rdata, 0)?,
+            algorithm: *rdata.get(3)?,
+            key_tag: key_tag(rdata),
+            public_key: rdata.get(4..)?.to_vec(),
+            rdata: rdata.to_vec(),
+        })
+    }
+
+    // Only zone keys that weren't revoked (RFC 5011) sign RRsets.
+    fn is_zone_key(&self) -> bool {
+        self.flags & 0x0100 != 0 && self.flags & 0x0080 == 0
+    }
+
+    pub fn same_as(&self, rdata: &[u8]) -> bool {
+        self.rdata == rdata
+    }
+
+    // Whether the DS record (given as its data) at `owner` refers to this key.
+    pub fn matches_ds(&self, owner: &str, ds: &[u8]) -> bool {
+        if read_u16(ds, 0) != Some(self.key_tag) || ds.get(2) != Some(&self.algorithm) {
+            return false;
+        }
+        let (digest_type, digest) = match (ds.get(3), ds.get(4..)) {
+            (Some(digest_type), Some(digest)) => (*digest_type, digest),
+            _ => return false,
+        };
+        let mut data = name_to_wire(owner);
+        data.extend_from_slice(&self.rdata);
+        match digest_type {
+            1 => Sha1::digest(&data).as_slice() == digest,
+            2 => Sha256::digest(&data).as_slice() == digest,
+            4 => Sha384::digest(&data).as_slice() == digest,
+            _ => false,
+        }
+    }
+}
+
+enum PrivateKey {
+    Ecdsa(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+// A private key a zone is signed with.
+pub struct SigningKey {
+    pub algorithm: u8,
+    pub key_tag: u16,
+    // The data of the key's DNSKEY record
+    pub dnskey: Vec<u8>,
+    key: PrivateKey,
+}
+
+impl SigningKey {
+    // Reads a PKCS#8 ECDSA P-256 or Ed25519 private key. `flags` are the DNSKEY flags,
+    // FLAGS_KSK or FLAGS_ZSK.
+    pub fn from_pkcs8(document: &[u8], flags: u16) -> Result<SigningKey, String> {
+        let ecdsa = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            document,
+            &SystemRandom::new(),
+        );
+        let (algorithm, public_key, key) = match ecdsa {
+            // Without the uncompressed point marker, as RFC 6605 has it
+            Ok(pair) => (
+                ALGORITHM_ECDSAP256SHA256,
+                pair.public_key().as_ref()[1..].to_vec(),
+                PrivateKey::Ecdsa(pair),
+            ),
+            Err(_) => match Ed25519KeyPair::from_pkcs8_maybe_unchecked(document) {
+                Ok(pair) => (
+                    ALGORITHM_ED25519,
+                    pair.public_key().as_ref().to_vec(),
+                    PrivateKey::Ed25519(pair),
+                ),
+                Err(_) => return Err("not a PKCS#8 ECDSA P-256 or Ed25519 key".to_string()),
+            },
+        };
+        // Protocol is always 3
+        let mut dnskey = flags.to_be_bytes().to_vec();
+        dnskey.extend([3, algorithm]);
+        dnskey.extend(public_key);
+        Ok(SigningKey {
+            algorithm,
+            key_tag: key_tag(&dnskey),
+            dnskey,
+            key,
+        })
+    }
+
+    // A new PKCS#8 private key.
+    pub fn generate(algorithm: u8) -> Result<Vec<u8>, String> {
+        let random = SystemRandom::new();
+        let document = match algorithm {
+            ALGORITHM_ECDSAP256SHA256 => {
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &random)
+            }
+            ALGORITHM_ED25519 => Ed25519KeyPair::generate_pkcs8(&random),
+            _ => return Err(format!("cannot generate keys for algorithm {}", algorithm)),
+        };
+        document
+            .map(|e| e.as_ref().to_vec())
+            .map_err(|_| "failed to generate a key".to_string())
+    }
+
+    // The DS record the parent zone needs to publish for this key, in zone file format.
+    pub fn ds(&self, owner: &str) -> String {
+        let mut data = name_to_wire(owner);
+        data.extend_from_slice(&self.dnskey);
+        format!(
+            "{} IN DS {} {} 2 {}",
+            normalize(owner),
+            self.key_tag,
+            self.algorithm,
+            HEXUPPER.encode(&Sha256::digest(&data))
+        )
+    }
+
+    // The data of an RRSIG record for the RRset, made by the zone `signer` and valid between
+    // the given times.
+    pub fn sign(
+        &self,
+        set: &RrSet,
+        signer: &str,
+        inception: u32,
+        expiration: u32,
+    ) -> Option<Vec<u8>> {
+        // Wildcard owners' `*` label doesn't count
+        let mut labels = labels(&set.owner);
+        if labels.first() == Some(&&b"*"[..]) {
+            labels.remove(0);
+        }
+        let mut header = set.r#type.to_be_bytes().to_vec();
+        header.extend([self.algorithm, labels.len() as u8]);
+        header.extend_from_slice(&set.ttl.to_be_bytes());
+        header.extend_from_slice(&expiration.to_be_bytes());
+        header.extend_from_slice(&inception.to_be_bytes());
+        header.extend_from_slice(&self.key_tag.to_be_bytes());
+        header.extend(name_to_wire(signer));
+        let data = rrset_data(&header, &set.owner, set, set.ttl);
+        let signature = match &self.key {
+            PrivateKey::Ecdsa(pair) => pair.sign(&SystemRandom::new(), &data).ok()?,
+            PrivateKey::Ed25519(pair) => pair.sign(&data),
+        };
+        header.extend_from_slice(signature.as_ref());
+        Some(header)
+    }
+}
+
+// RFC 4034 appendix B.
+pub fn key_tag(rdata: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for (i, byte) in rdata.iter().enumerate() {
+        sum += if i % 2 == 0 {
+            (*byte as u32) << 8
+        } else {
+            *byte as u32
+        };
+    }
+    sum += (sum >> 16) & 0xFFFF;
+    (sum & 0xFFFF) as u16
+}
+
+// Signing algorithms we can check signatures of.
+pub fn supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 14 | 15)
+}
+
+// Whether a DS record (given as its data) uses an algorithm and digest we support. Zones that
+// only have unsupported ones are treated as unsigned (RFC 4035 section 5.2).
+pub fn supported_ds(ds: &[u8]) -> bool {
+    matches!(ds.get(2), Some(&e) if supported_algorithm(e)) && matches!(ds.get(3), Some(1 | 2 | 4))
+}
+
+// RSA public keys are stored as RFC 3110 has it: exponent length, exponent, modulus.
+fn rsa_components(key: &[u8]) -> Option<RsaPublicKeyComponents<&[u8]>> {
+    let (exponent_length, rest) = match *key.first()? {
+        0 => (read_u16(key, 1)? as usize, key.get(3..)?),
+        length => (length as usize, key.get(1..)?),
+    };
+    let e = rest.get(..exponent_length)?;
+    let n = rest.get(exponent_length..)?;
+    let leading_zeros = n.iter().take_while(|e| **e == 0).count();
+    Some(RsaPublicKeyComponents {
+        n: &n[leading_zeros..],
+        e,
+    })
+}
+
+fn verify_signature(algorithm: u8, key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    match algorithm {
+        5 | 7 | 8 | 10 => {
+            let parameters = match algorithm {
+                8 => &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                10 => &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY,
+                _ => &signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY,
+            };
+            rsa_components(key)
+                .map(|components| components.verify(parameters, message, signature).is_ok())
+                .unwrap_or(false)
+        }
+        13 | 14 => {
+            // ring wants the uncompressed point marker in front of the coordinates
+            let mut point = vec![4];
+            point.extend_from_slice(key);
+            let curve = if algorithm == 13 {
+                &ECDSA_P256_SHA256_FIXED
+            } else {
+                &ECDSA_P384_SHA384_FIXED
+            };
+            UnparsedPublicKey::new(curve, point)
+                .verify(message, signature)
+                .is_ok()
+        }
+        15 => UnparsedPublicKey::new(&ED25519, key)
+            .verify(message, signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+// Validity periods use serial number arithmetic, so they survive 2106.
+fn current(signature: &Signature) -> bool {
+    let now = now();
+    now.wrapping_sub(signature.inception) as i32 >= 0
+        && signature.expiration.wrapping_sub(now) as i32 >= 0
+}
+
+// Finds one of the RRset's signatures that is current and checks out against one of the keys
+// of the zone `signer`.
+pub fn verify_rrset<'a>(set: &'a RrSet, keys: &[DnsKey], signer: &str) -> Option<&'a Signature> {
+    set.signatures
+        .iter()
+        .filter(|signature| signature.signer == signer && current(signature))
+        .find(|signature| {
+            let data = match signed_data(set, signature) {
+                Some(e) => e,
+                None => return false,
+            };
+            keys.iter()
+                .filter(|key| key.is_zone_key())
+                .filter(|key| key.key_tag == signature.key_tag)
+                .filter(|key| key.algorithm == signature.algorithm)
+                .any(|key| {
+                    verify_signature(key.algorithm, &key.public_key, &data, &signature.signature)
+                })
+        })
+}
+
+// Compares names in canonical DNS order (RFC 4034 section 6.1): label by label from the root.
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let a = name_to_wire(a);
+    let b = name_to_wire(b);
+    labels(&a).iter().rev().cmp(labels(&b).iter().rev())
+}
+
+// The type bitmap of an NSEC/NSEC3 record listing the types (RFC 4034 section 4.1.2).
+pub fn type_bitmap(types: &[u16]) -> Vec<u8> {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+    let mut output = Vec::new();
+    let mut window: Option<(u8, Vec<u8>)> = None;
+    for r#type in types {
+        let (number, bit) = ((r#type >> 8) as u8, (r#type & 0xFF) as usize);
+        match &mut window {
+            Some((current, _)) if *current == number => {}
+            _ => {
+                if let Some((current, bits)) = window.take() {
+                    output.extend([current, bits.len() as u8]);
+                    output.extend(bits);
+                }
+                window = Some((number, Vec::new()));
+            }
+        }
+        if let Some((_, bits)) = &mut window {
+            if bits.len() <= bit / 8 {
+                bits.resize(bit / 8 + 1, 0);
+            }
+            bits[bit / 8] |= 0x80 >> (bit % 8);
+        }
+    }
+    if let Some((current, bits)) = window {
+        output.extend([current, bits.len() as u8]);
+        output.extend(bits);
+    }
+    output
+}
+
+// Whether an NSEC/NSEC3 type bitmap lists the type.
+pub fn has_type(bitmap: &[u8], r#type: u16) -> bool {
+    let window = (r#type >> 8) as u8;
+    let bit = (r#type & 0xFF) as usize;
+    let mut position = 0;
+    while position + 2 <= bitmap.len() {
+        let length = bitmap[position + 1] as usize;
+        if bitmap[position] == window {
+            return bit / 8 < length
+                && bitmap
+                    .get(position + 2 + bit / 8)
+                    .map(|e| e & (0x80 >> (bit % 8)) != 0)
+                    .unwrap_or(false);
+        }
+        position += 2 + length;
+    }
+    false
+}
+
+pub struct Nsec<'a> {
+    // Normalized
+    pub next: String,
+    pub bitmap: &'a [u8],
+}
+
+// NSEC data is never compressed, so it's read on its own.
+pub fn parse_nsec(rdata: &[u8]) -> Option<Nsec<'_>> {
+    let (next, end) = read_name(rdata, 0)?;
+    Some(Nsec {
+        next: normalize(&next),
+        bitmap: rdata.get(end..)?,
+    })
+}
+
+pub struct Nsec3<'a> {
+    pub opt_out: bool,
+    pub iterations: u16,
+    pub salt: &'a [u8],
+    pub next_hash: &'a [u8],
+    pub bitmap: &'a [u8],
+}
+
+pub fn parse_nsec3(rdata: &[u8]) -> Option<Nsec3<'_>> {
+    // SHA-1 is the only hash algorithm defined
+    if *rdata.first()? != 1 {
+        return None;
+    }
+    let salt_length = *rdata.get(4)? as usize;
+    let salt = rdata.get(5..5 + salt_length)?;
+    let hash_length = *rdata.get(5 + salt_length)? as usize;
+    let hash_start = 6 + salt_length;
+    Some(Nsec3 {
+        opt_out: rdata.get(1)? & NSEC3_OPT_OUT != 0,
+        iterations: read_u16(rdata, 2)?,
+        salt,
+        next_hash: rdata.get(hash_start..hash_start + hash_length)?,
+        bitmap: rdata.get(hash_start + hash_length..)?,
+    })
+}
+
+// The hashed owner name of NSEC3 records (RFC 5155 section 5).
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut hash = Sha1::new()
+        .chain_update(name_to_wire(name))
+        .chain_update(salt)
+        .finalize()
+        .to_vec();
+    for _ in 0..iterations {
+        hash = Sha1::new()
+            .chain_update(&hash)
+            .chain_update(salt)
+            .finalize()
+            .to_vec();
+    }
+    hash
+}
+
+// The owner name of the NSEC3 record for a hash in a zone.
+pub fn nsec3_owner(hash: &[u8], zone: &str) -> String {
+    format!("{}.{}", BASE32HEX_NOPAD.encode(hash).to_lowercase(), zone)
+}
+
+// The hash in an NSEC3 record's owner name, e.g. `2vptu5timamqttgl4luu9kg21e0aor3s.example.`
+pub fn owner_hash(name: &str) -> Option<Vec<u8>> {
+    let label = name.split('.').next()?;
+    BASE32HEX_NOPAD.decode(label.to_uppercase().as_bytes()).ok()
+}
@@ -0,0 +1,283 @@
+// dnstap (https://dnstap.info) output over Frame Streams, to a file or a Unix socket.
+// The protobuf messages are small enough to be encoded by hand here.
+use lazy_static::lazy_static;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 0x01;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy)]
+pub enum MessageType {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+#[derive(Clone, Copy)]
+pub enum Transport {
+    Udp = 1,
+    Tcp = 2,
+}
+
+pub struct DnstapMessage<'a> {
+    pub kind: MessageType,
+    pub transport: Transport,
+    pub query_address: Option<SocketAddr>,
+    pub response_address: Option<SocketAddr>,
+    pub query_time: Option<SystemTime>,
+    pub response_time: Option<SystemTime>,
+    pub query_message: Option<&'a [u8]>,
+    pub response_message: Option<&'a [u8]>,
+}
+
+fn put_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn put_key(buffer: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buffer, (field << 3) | wire_type);
+}
+
+fn put_uint(buffer: &mut Vec<u8>, field: u64, value: u64) {
+    put_key(buffer, field, 0);
+    put_varint(buffer, value);
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, field: u64, value: &[u8]) {
+    put_key(buffer, field, 2);
+    put_varint(buffer, value.len() as u64);
+    buffer.extend_from_slice(value);
+}
+
+fn put_fixed32(buffer: &mut Vec<u8>, field: u64, value: u32) {
+    put_key(buffer, field, 5);
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_time(buffer: &mut Vec<u8>, seconds_field: u64, time: Option<SystemTime>) {
+    if let Some(time) = time {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        put_uint(buffer, seconds_field, since_epoch.as_secs());
+        put_fixed32(buffer, seconds_field + 1, since_epoch.subsec_nanos());
+    }
+}
+
+fn ip_bytes(address: &SocketAddr) -> Vec<u8> {
+    match address.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+impl DnstapMessage<'_> {
+    fn encode(&self, identity: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        put_uint(&mut message, 1, self.kind as u64);
+        let family_source = self.query_address.or(self.response_address);
+        if let Some(address) = family_source {
+            put_uint(&mut message, 2, if address.is_ipv4() { 1 } else { 2 });
+        }
+        put_uint(
+            &mut message,
+            3,
+            match self.transport {
+                Transport::Udp => 1,
+                Transport::Tcp => 2,
+            },
+        );
+        if let Some(address) = &self.query_address {
+            put_bytes(&mut message, 4, &ip_bytes(address));
+        }
+        if let Some(address) = &self.response_address {
+            put_bytes(&mut message, 5, &ip_bytes(address));
+        }
+        if let Some(address) = &self.query_address {
+            put_uint(&mut message, 6, address.port() as u64);
+        }
+        if let Some(address) = &self.response_address {
+            put_uint(&mut message, 7, address.port() as u64);
+        }
+        put_time(&mut message, 8, self.query_time);
+        if let Some(data) = self.query_message {
+            put_bytes(&mut message, 10, data);
+        }
+        put_time(&mut message, 12, self.response_time);
+        if let Some(data) = self.response_message {
+            put_bytes(&mut message, 14, data);
+        }
+
+        let mut frame = Vec::new();
+        put_bytes(&mut frame, 1, identity);
+        put_bytes(
+            &mut frame,
+            2,
+            concat!("baDNS ", env!("CARGO_PKG_VERSION")).as_bytes(),
+        );
+        put_bytes(&mut frame, 14, &message);
+        // Dnstap.Type = MESSAGE
+        put_uint(&mut frame, 15, 1);
+        frame
+    }
+}
+
+fn control_frame(control_type: u32, with_content_type: bool) -> Vec<u8> {
+    let mut payload = control_type.to_be_bytes().to_vec();
+    if with_content_type {
+        payload.extend_from_slice(&CONTROL_FIELD_CONTENT_TYPE.to_be_bytes());
+        payload.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        payload.extend_from_slice(CONTENT_TYPE);
+    }
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+fn read_control_frame(stream: &mut UnixStream) -> std::io::Result<u32> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header)?;
+    let length = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload)?;
+    match payload.get(0..4) {
+        Some(e) => Ok(u32::from_be_bytes([e[0], e[1], e[2], e[3]])),
+        None => Err(std::io::ErrorKind::InvalidData.into()),
+    }
+}
+
+enum Output {
+    File(String),
+    Socket(String),
+}
+
+impl Output {
+    // Opens the output and performs the Frame Streams handshake - bidirectional for sockets.
+    fn open(&self) -> std::io::Result<Box<dyn Write + Send>> {
+        match self {
+            Output::File(path) => {
+                let mut file = File::create(path)?;
+                file.write_all(&control_frame(CONTROL_START, true))?;
+                Ok(Box::new(file))
+            }
+            Output::Socket(path) => {
+                let mut stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(RECONNECT_DELAY))?;
+                stream.write_all(&control_frame(CONTROL_READY, true))?;
+                if read_control_frame(&mut stream)? != CONTROL_ACCEPT {
+                    return Err(std::io::ErrorKind::InvalidData.into());
+                }
+                stream.write_all(&control_frame(CONTROL_START, true))?;
+                Ok(Box::new(stream))
+            }
+        }
+    }
+
+    fn describe(&self) -> &str {
+        match self {
+            Output::File(e) | Output::Socket(e) => e,
+        }
+    }
+}
+
+// Writes frames received from the channel until every sender is gone. Frames arriving while
+// the socket is disconnected are dropped.
+fn run_writer(output: Output, frames: Receiver<Vec<u8>>) {
+    let mut writer: Option<Box<dyn Write + Send>> = None;
+    let mut last_attempt: Option<SystemTime> = None;
+    for frame in frames.iter() {
+        if writer.is_none() {
+            let can_retry = last_attempt
+                .and_then(|e| e.elapsed().ok())
+                .map(|e| e >= RECONNECT_DELAY)
+                .unwrap_or(true);
+            if !can_retry {
+                continue;
+            }
+            last_attempt = Some(SystemTime::now());
+            match output.open() {
+                Ok(e) => {
+                    info!(target: "dnstap", "Writing dnstap to {}", output.describe());
+                    writer = Some(e);
+                }
+                Err(e) => {
+                    warn!(target: "dnstap", "Cannot open {} ({})", output.describe(), e);
+                    continue;
+                }
+            }
+        }
+        let mut data = (frame.len() as u32).to_be_bytes().to_vec();
+        data.extend(frame);
+        if let Some(stream) = writer.as_mut() {
+            if let Err(e) = stream.write_all(&data) {
+                warn!(target: "dnstap", "Lost {} ({})", output.describe(), e);
+                writer = None;
+            }
+        }
+    }
+    if let Some(mut stream) = writer {
+        let _ = stream.write_all(&control_frame(CONTROL_STOP, false));
+    }
+}
+
+pub struct Dnstap {
+    identity: Vec<u8>,
+    frames: Sender<Vec<u8>>,
+}
+
+lazy_static! {
+    static ref DNSTAP: Mutex<Option<Dnstap>> = Mutex::new(None);
+}
+
+// Sets up the output according to dnstap()'s options object. Nothing is written until it's
+// installed.
+pub fn open(options: &Value) -> Result<Option<Dnstap>, String> {
+    let output = match (options["socket"].as_str(), options["file"].as_str()) {
+        (Some(path), None) => Some(Output::Socket(path.to_string())),
+        (None, Some(path)) => Some(Output::File(path.to_string())),
+        (None, None) => None,
+        _ => return Err("dnstap() takes either a socket or a file, not both!".to_string()),
+    };
+    Ok(output.map(|output| {
+        let (frames, receiver) = channel();
+        thread::spawn(move || run_writer(output, receiver));
+        Dnstap {
+            identity: options["identity"]
+                .as_str()
+                .unwrap_or("badns")
+                .as_bytes()
+                .to_vec(),
+            frames,
+        }
+    }))
+}
+
+// Replaces the output, or turns dnstap off with None.
+pub fn install(dnstap: Option<Dnstap>) {
+    // Dropping the previous sender stops its writer thread.
+    *DNSTAP.lock().unwrap() = dnstap;
+}
+
+pub fn emit(message: &DnstapMessage) {
+    if let Some(dnstap) = DNSTAP.lock().unwrap().as_ref() {
+        let _ = dnstap.frames.send(message.encode(&dnstap.identity));
+    }
+}
@@ -0,0 +1,68 @@
+use hyper::body::to_bytes;
+use hyper::{Body, Client, Method, Request};
+use serde_json::{json, Map, Value};
+use std::str::FromStr;
+use tokio::time::{timeout, Duration};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Performs an HTTP request described by fetch()'s options object, and returns the response
+// as `{ status, headers, body }`. Only plain HTTP is supported.
+pub async fn fetch(url: &str, options: &Value) -> Result<Value, String> {
+    let method = match options["method"].as_str() {
+        Some(e) => Method::from_str(&e.to_uppercase()).map_err(|e| e.to_string())?,
+        None => Method::GET,
+    };
+    let mut builder = Request::builder().method(method).uri(url);
+    if let Some(headers) = options["headers"].as_object() {
+        for (name, value) in headers {
+            let value = match value {
+                Value::String(e) => e.clone(),
+                other => other.to_string(),
+            };
+            builder = builder.header(name.as_str(), value);
+        }
+    }
+    let body = match &options["body"] {
+        Value::Null => Body::empty(),
+        Value::String(e) => Body::from(e.clone()),
+        _ => return Err("fetch() body must be a string!".to_string()),
+    };
+    let request = builder.body(body).map_err(|e| e.to_string())?;
+    let duration = match options["timeout"].as_u64() {
+        Some(e) => Duration::from_millis(e),
+        None => DEFAULT_TIMEOUT,
+    };
+
+    let exchange = async {
+        let response = Client::new()
+            .request(request)
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = response.status().as_u16();
+        let mut headers = Map::new();
+        for (name, value) in response.headers() {
+            headers.insert(
+                name.to_string(),
+                Value::from(String::from_utf8_lossy(value.as_bytes()).to_string()),
+            );
+        }
+        let body = to_bytes(response.into_body())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok::<Value, String>(json!({
+            "status": status,
+            "headers": headers,
+            "body": String::from_utf8_lossy(&body),
+        }))
+    };
+
+    match timeout(duration, exchange).await {
+        Ok(result) => result,
+        Err(_) => Err(format!(
+            "Request to {} timed out after {}ms",
+            url,
+            duration.as_millis()
+        )),
+    }
+}
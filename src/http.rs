@@ -8,8 +8,10 @@ use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Client, Request, Response, Server};
 use hyper_reverse_proxy::call;
+use tracing::{error, info, warn};
 
 use crate::jsbridge::Address;
+use crate::metrics;
 
 type Bindings = HashMap<String, String>;
 fn full<T: Into<Bytes>>(chunk: T) -> Body where Body: From<T>{
@@ -22,8 +24,9 @@ async fn main_service(
     bindings: &'static Bindings,
 ) -> Result<Response<hyper::Body>, Infallible> {
     macro_rules! send_and_log {
-        ($log:expr) => {
-            println!("[HTTP]: {}", $log);
+        ($outcome:expr, $log:expr) => {
+            metrics::count("badns_http_proxy_requests_total", &[("outcome", $outcome)]);
+            warn!(target: "http", "{}", $log);
             return Ok(Response::new(full($log)));
         };
     }
@@ -31,7 +34,10 @@ async fn main_service(
     let mut host: String = match request.headers().get("Host") {
         Some(e) => e.to_str().unwrap_or("op").to_string(),
         None => {
-            send_and_log!(format!("Peer {} omitted host header!", peer_address));
+            send_and_log!(
+                "missing_host",
+                format!("Peer {} omitted host header!", peer_address)
+            );
         }
     };
 
@@ -40,20 +46,20 @@ async fn main_service(
     }
 
     if !bindings.contains_key(&host) {
-        send_and_log!(format!(
-            "Peer {} queried a non-bound host {}",
-            peer_address, host
-        ));
+        send_and_log!(
+            "unbound_host",
+            format!("Peer {} queried a non-bound host {}", peer_address, host)
+        );
     }
     let rebound_host = bindings.get(&host).unwrap();
 
     match call(peer_address.ip(), rebound_host, request, &Client::new()).await {
-        Ok(e) => Ok(e),
+        Ok(e) => {
+            metrics::count("badns_http_proxy_requests_total", &[("outcome", "proxied")]);
+            Ok(e)
+        }
         Err(z) => {
-            send_and_log!(format!(
-                "Proxy error: {:?}",
-                z
-            ));
+            send_and_log!("error", format!("Proxy error: {:?}", z));
         }
     }
 }
@@ -76,9 +82,9 @@ pub async fn run_http_server(address: &Address, bindings: Bindings) {
     let server = Server::bind(&addr).serve(make_service);
 
 
-    println!("Running http server on {:?}", addr);
+    info!(target: "http", "Running http server on {:?}", addr);
 
     if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+        error!(target: "http", "Server error: {}", e);
     }
 }
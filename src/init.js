@@ -7,7 +7,18 @@
 // The functions described below assume the following types:
 // 
 // type RRConstant = RR_A | RR_AAAA | RR_CNAME
-// type Handler = (name: string, rrtype: RRConstant, rrclass: number, peerAddress: string, ownAddress: string) => Response[] | Response
+// type Handler = (name: string, rrtype: RRConstant, rrclass: number, peerAddress: string, ownAddress: string, context: QueryContext) => Response[] | Response | Promise<Response[] | Response>
+// interface QueryContext {
+//     clientIp: string,
+//     clientPort: number,
+//     ownIp: string,
+//     ownPort: number,
+//     listener: string,             // The address passed to bindAddress(), as `address:port`
+//     transport: 'udp' | 'tcp' | 'admin',  // 'admin' for test lookups made from the dashboard
+//     id: number,                   // The query's message ID
+//     flags: { rd: boolean, cd: boolean, do: boolean },
+//     clientSubnet: string | null,  // EDNS client subnet, as `address/prefix`
+// }
 // type Response = NormalResponse | SpecialResponse;
 // type SpecialType = 'queryUpstream';
 // type NormalResponseType = 'A' | 'AAAA' | 'CNAME'
@@ -35,14 +46,35 @@
 // };
 // 
 // The available non-internal functions are:
+// - log(...values) / log.debug / log.info / log.warn / log.error(...values) => undefined
+//   Logs the values at the given level (`log` itself logs at info) under the `js` target.
+//   `console.log` and friends are aliases.
+//
 // - [1] bindAddress(address: string, port = 53) => undefined
-//   Binds the UDP address and starts listening on it.
+//   Binds the address and starts listening on it, over both UDP and TCP.
 //   There can be multiple interfaces open at once.
 //
-// - [1] upstream(address: string, port = 53) => undefined
+// - [1] upstream(address: string, port = 53, options: UpstreamOptions = {}) => undefined
 //   Adds an upstream server. If the JS config doesn't have a response for a given question,
 //   the baDNS server will query all upstream servers in the order they were added in until it finds
 //   one with at least one answer
+//   interface UpstreamOptions {
+//       tsigKey?: string,  // Signs the queries with this key and drops unsigned answers
+//   }
+//
+// - [1] tsigKey(name: string, algorithm: string, secret: string) => undefined
+//   Registers a TSIG key, with algorithm 'hmac-sha256', 'hmac-sha512' or 'hmac-sha1' and a base64
+//   secret. Queries and transfer requests signed with a registered key get signed answers, ones
+//   with a bad signature are refused. Other functions refer to registered keys by name.
+//
+// - [1] dnssecValidation(options: ValidationOptions = {}) => undefined
+//   Validates upstream answers with DNSSEC, following DS and DNSKEY records down from the trust
+//   anchors. Validated answers get the AD bit, ones that fail validation get SERVFAIL unless the
+//   client set the CD bit, and answers from unsigned zones are passed on as before.
+//   interface ValidationOptions {
+//       // DS or DNSKEY records in zone file format, the root zone's keys by default
+//       trustAnchors?: string[],
+//   }
 //
 // - [1] setupHTTPRedirectServer(address: string, port: number, recordTarget = ip) => undefined
 //   Sets up the HTTP reverse proxy. The HTTP server will bind on `address:port`.
@@ -79,6 +111,136 @@
 // - exec(filename: string) => any
 //   Evaluates the contents of the file passed as the argument
 //
+// - onUpstreamResponse(hook: UpstreamHook) => undefined
+//   Adds a hook that gets called with the records received from upstream, before they're cached
+//   and sent to the client. Hooks are called in the order they were added in, each one receiving
+//   the previous one's result. Answers to queryUpstream special responses go through them too.
+//   type UpstreamHook = (question: Question, client: Client, records: NormalResponse[]) => NormalResponse[] | undefined
+//   interface Question { name: string, rrtype: RRConstant, rrclass: number }
+//   interface Client extends QueryContext { peerAddress: string, ownAddress: string }
+//   Returning undefined keeps the (possibly modified in place) records, returning null drops all of them.
+//   Note that the result is cached for all clients.
+//
+// - resolve(name: string, rrtype: RRConstant = RR_A) => Promise<NormalResponse[]>
+//   Resolves the name through the cache and the upstream servers, skipping all JS bindings.
+//   The returned records can be modified and returned from an async handler.
+//
+// - queryLog(options: QueryLogOptions) => undefined
+//   Records every query (client, name, type, answers, RCODE, source and latency) as JSON lines.
+//   Calling it again replaces the previous configuration, calling it with {} turns the log off.
+//   interface QueryLogOptions {
+//       file?: string,     // Path of the log file
+//       maxSize?: number,  // Size in bytes after which the file is rotated, 10 MiB by default
+//       keep?: number,     // How many rotated files (file.1, file.2, ...) to keep, 5 by default
+//       syslog?: boolean,  // Also send the entries to the local syslog
+//   }
+//
+// - loadZone(origin: string, filename: string) => undefined
+//   Serves the RFC 1035 master file authoritatively. Names inside the zone are answered by named
+//   bindings first, then by the zone, and never reach the universal bindings or the upstreams.
+//   Loading a zone with the same origin again replaces it.
+//
+// - declareZone(name: string, soa: SOAOptions = {}, ns: string | string[] = []) => undefined
+//   Makes baDNS authoritative for the zone, whose records come from the bindings. Names inside it
+//   that no binding answers get NXDOMAIN, or NODATA if the name has bindings for other types, instead
+//   of being forwarded upstream. The apex answers SOA and NS queries itself.
+//   interface SOAOptions {
+//       mname?: string,    // Primary nameserver, the first of `ns` by default
+//       rname?: string,    // Responsible mailbox, `hostmaster.<name>` by default
+//       serial?: number, refresh?: number, retry?: number, expire?: number,
+//       minimum?: number,  // Negative answers' TTL, 300 by default
+//       ttl?: number,      // TTL of the SOA and NS records, 3600 by default
+//   }
+//
+// - allowTransfer(zone: string, clients: string | string[]) => undefined
+//   Lets the clients (addresses, CIDR ranges, or 'key:<name>' for requests signed with a TSIG key)
+//   transfer the zone with AXFR/IXFR over TCP. Only the zone's own records are sent, not the
+//   bindings. Nobody may transfer a zone by default.
+//
+// - secondaryZone(name: string, primary: string, port = 53, options: SecondaryOptions = {}) => undefined
+//   Serves the zone as a secondary: it's transferred from the primary and refreshed whenever the
+//   primary's SOA serial changes, at the pace of the SOA's refresh/retry intervals.
+//   interface SecondaryOptions {
+//       tsigKey?: string,  // Signs the requests to the primary and requires signed answers
+//   }
+//
+// - allowUpdate(zone: string, options: UpdateOptions) => undefined
+//   Accepts RFC 2136 dynamic updates (e.g. from nsupdate) for a zone declared with declareZone().
+//   Updates must be signed with TSIG using one of the keys. Updated records are served after the
+//   bindings, and the SOA serial goes up with each update. If `file` is set, the zone's contents
+//   are saved there after each update and restored from it on startup.
+//   interface UpdateOptions {
+//       // Names of keys registered with tsigKey(), or key names and their base64 HMAC-SHA256 secrets
+//       keys: string[] | { [keyName: string]: string },
+//       file?: string,  // Master file to save the zone to
+//   }
+//
+// - onUpdate(hook: UpdateHook) => undefined
+//   Adds a hook called with each dynamic update before it's applied. Returning false refuses it.
+//   type UpdateHook = (zone: string, update: Update) => boolean | undefined
+//   interface Update { key: string, client: string, added: UpdateRecord[], removed: UpdateRecord[] }
+//   interface UpdateRecord { name: string, type: string, ttl: number, data: string }  // data as in a zone file
+//
+// - signZone(zone: string, options: SigningOptions) => undefined
+//   Signs the answers from a loaded, declared or secondary zone with DNSSEC as they're sent, for
+//   clients that set the DO bit: RRSIGs, NSEC or NSEC3 records proving what doesn't exist (names
+//   that don't exist get NOERROR with an NXNAME proof, as in RFC 9824) and the DNSKEY RRset at
+//   the apex. Keys are PKCS#8 PEM files, generated if missing. The DS record for the parent zone
+//   is logged at startup. Delegations from the zone are proven insecure.
+//   interface SigningOptions {
+//       ksk: string,        // Key signing key file, which signs everything if there's no ZSK
+//       zsk?: string,       // Zone signing key file
+//       algorithm?: string, // For generated keys, 'ecdsap256sha256' (default) or 'ed25519'
+//       nsec3?: { iterations?: number, salt?: string },  // NSEC3 instead of NSEC, salt in hex
+//   }
+//
+// - dnstap(options: DnstapOptions) => undefined
+//   Sends client queries/responses and forwarded queries/responses as dnstap over Frame Streams.
+//   Calling it again replaces the previous output, calling it with {} turns dnstap off.
+//   interface DnstapOptions {
+//       socket?: string,   // Path of a Unix socket to connect to (reconnects when it goes away)
+//       file?: string,     // Path of a file to write to instead
+//       identity?: string, // Identity sent with each message, "badns" by default
+//   }
+//
+// - [1] openStore(filename: string) => undefined
+//   Backs the `store` with a JSON file. Existing contents are loaded, and every change is written
+//   back atomically. Without calling this, the store only lives in memory.
+//
+// - store.get(key: string) => any
+//   Returns the value saved under key, or undefined if there's none.
+//
+// - store.set(key: string, value: any) => undefined
+//   Saves a JSON-serializable value under key.
+//
+// - store.delete(key: string) => boolean
+//   Removes the value saved under key. Returns whether there was one.
+//
+// - store.keys() => string[]
+//   Returns all keys currently in the store.
+//
+// - fetch(url: string, options: FetchOptions = {}) => Promise<FetchResponse>
+//   Performs an HTTP request. The promise rejects if the timeout runs out first. Handlers can be
+//   async functions that await it. Only http:// URLs are supported.
+//   interface FetchOptions { method?: string, headers?: object, body?: string, timeout?: number /* ms, 5000 by default */ }
+//   interface FetchResponse { url: string, status: number, ok: boolean, headers: object, text(): string, json(): any }
+//
+// - setTimeout(handler: Function, delay = 0, ...args) => number
+//   Calls handler with args once, after delay milliseconds. Returns the timer's id.
+//
+// - setInterval(handler: Function, delay = 0, ...args) => number
+//   Calls handler with args every delay milliseconds. Returns the timer's id.
+//
+// - clearTimeout(id: number) / clearInterval(id: number) => undefined
+//   Cancels the timer with the given id.
+//
+// - Config files as ES modules
+//   If the config file uses `import` or `export` statements (or is named *.mjs), it's loaded as a
+//   module. Relative imports are resolved against the importing file's directory, and all of the
+//   functions above (plus the extensions below) can be imported from the 'badns' module:
+//     import { addABinding, STUB } from 'badns';
+//     import { blocklist } from './blocklist.js';
+//
 //   -----------------------------baDNS extensions-----------------------------
 // 
 // - sha256(data: string) => string
@@ -87,42 +249,161 @@
 // - readFile(filename: string) => string
 //   Reads the file whose path was provided as the argument, and returns its contents parsed as UTF8
 //
+// - env(name: string, fallback: string | null = null) => string | null
+//   Returns the value of the environment variable name, or fallback if it isn't set
+//
+// - secrets(filename: string) => object
+//   Reads a secrets file - either a JSON object, or `KEY=VALUE` lines (with `#` comments).
+//   Throws if the file is world-readable, so keep it at `chmod 600`
+//
+// - hmac(algorithm: 'sha1' | 'sha256' | 'sha512', key: string, data: string, keyEncoding: Encoding = 'utf8') => string
+//   Generates the HMAC of data (taken as UTF8) and returns it as hex
+//
+// - randomBytes(count: number, encoding: Encoding = 'hex') => string
+//   Generates count cryptographically secure random bytes, returned in the given encoding
+//
+// - base64Encode / base32Encode / hexEncode(data: string, inputEncoding: Encoding = 'utf8') => string
+// - base64Decode / base32Decode / hexDecode(data: string, outputEncoding: Encoding = 'utf8') => string
+//   Convert between encodings. Decoding into 'utf8' throws if the bytes aren't valid UTF8
+//
+// - timingSafeEqual(a: string, b: string) => boolean
+//   Compares two strings in constant time - use it when checking tokens and digests
+//
+// type Encoding = 'utf8' | 'hex' | 'base64' | 'base32'
+//
 // [1] - Can only be executed on initial loading of the config file.
 
 
 // =========================================== Core methods ============================================
-const log = (...e) => badns_log(e.map(q => q === undefined ? '<undefined>' : q === null ? '<null>' : q.toString()).join("\n"));
-const console = { log };
+const badns_format = e => e.map(q => q === undefined ? '<undefined>' : q === null ? '<null>' : q.toString()).join("\n");
+const log = (...e) => badns_log('info', badns_format(e));
+log.debug = (...e) => badns_log('debug', badns_format(e));
+log.info = log;
+log.warn = (...e) => badns_log('warn', badns_format(e));
+log.error = (...e) => badns_log('error', badns_format(e));
+const console = { log, debug: log.debug, info: log.info, warn: log.warn, error: log.error };
 
 
 // ================================= Rust-exposed functions and fields =================================
-function badns_getResponse(name, rrtype, rrclass, peerAddress, ownAddress) {
-    log(`Requested JS response for ${name} (${RRrevs[rrtype]})`);
-    // Construct a terrible name in the bindings
-    const bindingName = rrtype + "_" + name;
-    const potentialResponders = [bindings[bindingName], ...unnamedBindings];
-    for (let responder of potentialResponders){
-        if(!responder) continue;
-        let response = responder?.(name, rrtype, rrclass, peerAddress, ownAddress) ?? null;
-        if (response) {
-            if(!Array.isArray(response)){
-                const recursedCName = response.type === "CNAME" ? recurse(response.target, rrtype, rrclass, peerAddress, ownAddress) : [];
-                response = [ response, ...recursedCName ];
-            }
-            log(`Responder ${responder.name || '<anon>'} replied!`);
-            if (!response.every(e => validateResponse(e))){
-                log("Validation fail - returning nonexistent");
-                return '[]';
-            }
-            return JSON.stringify(response);
+function badns_getResponse(name, rrtype, rrclass, peerAddress, ownAddress, serializedContext, zoneType = '') {
+    const response = badns_respond(name, rrtype, rrclass, peerAddress, ownAddress, serializedContext, zoneType);
+    if (!(response instanceof Promise)) return JSON.stringify(response);
+    // Async handlers answer later - Rust waits for the response under this id
+    const id = badns_nextResponseId;
+    badns_nextResponseId = (badns_nextResponseId + 1) % 0x7fffffff;
+    badns_pendingResponses.set(id, null);
+    response.then(
+        e => badns_pendingResponses.has(id) && badns_pendingResponses.set(id, { response: JSON.stringify(e) }),
+        error => badns_pendingResponses.has(id) && badns_pendingResponses.set(id, { error }),
+    );
+    return id;
+}
+
+// Returns the response of an async handler as JSON once it settled (throwing what the handler
+// threw), or null while it's still pending.
+function badns_takeResponse(id) {
+    const settled = badns_pendingResponses.get(id);
+    if (!settled) return null;
+    badns_pendingResponses.delete(id);
+    if ('error' in settled) throw settled.error;
+    return settled.response;
+}
+
+// Forgets a response Rust stopped waiting for.
+function badns_dropResponse(id) {
+    badns_pendingResponses.delete(id);
+}
+
+// Completes a fetch() or resolve() call with its JSON result, or its error.
+function badns_complete(id, ok, result) {
+    const call = badns_pendingCalls.get(id);
+    if (!call) return;
+    badns_pendingCalls.delete(id);
+    if (ok) call.resolve(result);
+    else call.reject(Error(result));
+}
+
+function badns_onUpstreamResponse(name, rrtype, rrclass, peerAddress, ownAddress, serializedContext, records) {
+    let response = JSON.parse(records);
+    const client = { peerAddress, ownAddress, ...JSON.parse(serializedContext) };
+    for (let hook of upstreamResponseHooks){
+        const rewritten = hook({ name, rrtype, rrclass }, client, response);
+        if (rewritten === undefined) continue;
+        response = rewritten === null ? [] : Array.isArray(rewritten) ? rewritten : [ rewritten ];
+    }
+    return JSON.stringify(response.filter(e => {
+        if (e?.special || !validateResponse(e)) {
+            log.warn(`Upstream hook returned an invalid record for ${name} - dropping it`);
+            return false;
         }
+        return true;
+    }));
+}
+
+function badns_onUpdate(zone, update) {
+    update = JSON.parse(update);
+    return updateHooks.every(hook => hook(zone, update) !== false);
+}
+
+function badns_fireTimer(id) {
+    const timer = timers[id];
+    if (!timer) return;
+    // Reschedule first, so that a throwing handler doesn't stop the interval
+    if (timer.interval) badns_scheduleTimer(id, timer.delay);
+    else delete timers[id];
+    timer.handler(...timer.args);
+}
+
+// Used by the admin API
+function badns_adminListBindings() {
+    const named = Object.entries(bindings).map(([bindingName, handler]) => {
+        const separator = bindingName.indexOf('_');
+        const rrtype = bindingName.substring(0, separator);
+        return {
+            name: bindingName.substring(separator + 1),
+            rrtype: RRrevs[rrtype]?.substring(3) ?? Number(rrtype),
+            handler: handler.name || '<anon>',
+            blocking: handler === STUB,
+            records: handler.badns_records ?? null,
+        };
+    });
+    return JSON.stringify({
+        bindings: named,
+        universalBindings: unnamedBindings.map(e => e.name || '<anon>'),
+        blockingEnabled: badns_blockingEnabled,
+    });
+}
+
+function badns_adminAddRecord(serializedRecord) {
+    const { name, ...record } = JSON.parse(serializedRecord);
+    const rrtype = RRs['RR_' + record.type];
+    if (typeof name !== 'string' || !rrtype || !validateResponse(record)) {
+        throw Error("Invalid record!");
     }
-    return '[]';
+    const bindingName = rrtype + "_" + name;
+    const records = [ ...(bindings[bindingName]?.badns_records ?? []), record ];
+    const staticRecord = () => records;
+    staticRecord.badns_records = records;
+    bindings[bindingName] = staticRecord;
+    return JSON.stringify({ name, records });
+}
+
+function badns_adminRemoveBinding(name, type) {
+    const bindingName = RRs['RR_' + type] + "_" + name;
+    const existed = bindingName in bindings;
+    delete bindings[bindingName];
+    return JSON.stringify({ removed: existed });
+}
+
+function badns_adminSetBlocking(enabled) {
+    badns_blockingEnabled = enabled;
+    return JSON.stringify({ blockingEnabled: badns_blockingEnabled });
 }
 
 let badns_httpRedirectHost = "";
 let badns_httpRedirectPort = 0;
 let badns_afterInit = false;
+let badns_blockingEnabled = true;
 
 let badns_httpRedirectRecordTarget = "127.0.0.1";
 
@@ -132,9 +413,19 @@ function bindAddress(address, port){
     badns_bindAddress(address, port || 53);
 }
 
-function upstream(address, port){
+function upstream(address, port, options = {}){
     assertInitIsntComplete();
-    badns_upstream(address, port || 53);
+    badns_upstream(address, port || 53, JSON.stringify(options));
+}
+
+function tsigKey(name, algorithm, secret){
+    assertInitIsntComplete();
+    badns_tsigKey(name, algorithm, secret);
+}
+
+function dnssecValidation(options = {}){
+    assertInitIsntComplete();
+    badns_dnssecValidation(JSON.stringify(options));
 }
 
 function setupHTTPRedirectServer(ip, port, recordTarget = undefined){
@@ -144,6 +435,58 @@ function setupHTTPRedirectServer(ip, port, recordTarget = undefined){
     badns_httpRedirectRecordTarget = recordTarget ?? ip;
 }
 
+function queryLog(options){
+    badns_queryLog(JSON.stringify(options ?? {}));
+}
+
+function dnstap(options){
+    badns_dnstap(JSON.stringify(options ?? {}));
+}
+
+function loadZone(origin, filename){
+    badns_loadZone(origin, filename);
+}
+
+function declareZone(name, soa = {}, ns = []){
+    const nameservers = Array.isArray(ns) ? ns : [ ns ];
+    badns_declareZone(name, JSON.stringify({
+        mname: nameservers[0] ?? `ns.${name}`,
+        rname: `hostmaster.${name}`,
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 604800,
+        minimum: 300,
+        ttl: 3600,
+        ...soa,
+    }), JSON.stringify(nameservers));
+}
+
+function allowTransfer(zone, clients){
+    badns_allowTransfer(zone, JSON.stringify(Array.isArray(clients) ? clients : [ clients ]));
+}
+
+function secondaryZone(name, primary, port = 53, options = {}){
+    badns_secondaryZone(name, primary, port, JSON.stringify(options));
+}
+
+function allowUpdate(zone, options){
+    badns_allowUpdate(zone, JSON.stringify(options ?? {}));
+}
+
+function signZone(zone, options){
+    badns_signZone(zone, JSON.stringify(options ?? {}));
+}
+
+function onUpdate(hook) {
+    updateHooks.push(hook);
+}
+
+function openStore(filename){
+    assertInitIsntComplete();
+    badns_storeOpen(filename);
+}
+
 function addHTTPRedirect(target, name){
     assertInitIsntComplete();
     if(badns_afterInit){
@@ -151,7 +494,7 @@ function addHTTPRedirect(target, name){
     }
 
     if(badns_httpRedirectPort === 0){
-        log("A HTTP redirection is being added, but HTTP redirect service isn't configured!");
+        log.warn("A HTTP redirection is being added, but HTTP redirect service isn't configured!");
     }
 
     // Assert target is a URL:
@@ -166,6 +509,31 @@ function addHTTPRedirect(target, name){
 
 const bindings = {};
 const unnamedBindings = [];
+const upstreamResponseHooks = [];
+const updateHooks = [];
+const timers = {};
+let nextTimerId = 1;
+
+function createTimer(handler, delay, args, interval){
+    if (typeof handler !== 'function') {
+        throw Error("Timer handler must be a function!");
+    }
+    const id = nextTimerId++;
+    // A zero-delay interval would starve the DNS servers
+    delay = Math.max(interval ? 1 : 0, Math.floor(Number(delay) || 0));
+    timers[id] = { handler, delay, args, interval };
+    badns_scheduleTimer(id, delay);
+    return id;
+}
+
+// Whether any named binding exists for the name or a name below it.
+function hasBindingsFor(name){
+    const below = '.' + name;
+    return Object.keys(bindings).some(bindingName => {
+        const bound = bindingName.substring(bindingName.indexOf('_') + 1);
+        return bound === name || bound.endsWith(below);
+    });
+}
 
 function assertInitIsntComplete(){
     if(badns_afterInit){
@@ -173,20 +541,61 @@ function assertInitIsntComplete(){
     }
 }
 
-function recurse(name, rrtype, rrclass, peerAddress, ownAddress){
-    const own = JSON.parse(badns_getResponse(name, rrtype, rrclass, peerAddress, ownAddress));
-    if(own.length) return own;
-    return [{
-        special: true,
-        specialType: 'queryUpstream',
-        name, rrtype, rrclass
-    }];
+// Asks the bindings for a response, or a promise of one if an async handler answers.
+function badns_respond(name, rrtype, rrclass, peerAddress, ownAddress, serializedContext, zoneType = '') {
+    const context = JSON.parse(serializedContext);
+    log.debug(`Requested JS response for ${name} (${RRrevs[rrtype]})`);
+    // Construct a terrible name in the bindings
+    const bindingName = rrtype + "_" + name;
+    // Names inside loaded zones are answered by the zone before the universal bindings
+    const potentialResponders = [bindings[bindingName], ...(zoneType === 'loaded' ? [] : unnamedBindings)]
+        .filter(responder => responder && (responder !== STUB || badns_blockingEnabled));
+    const ask = index => {
+        const responder = potentialResponders[index];
+        if (!responder) {
+            // Inside zones, tells the zone whether to answer NODATA or NXDOMAIN
+            return zoneType ? { records: [], nameExists: hasBindingsFor(name) } : { records: [] };
+        }
+        return badns_then(responder(name, rrtype, rrclass, peerAddress, ownAddress, context), response => {
+            if (!response) return ask(index + 1);
+            const handler = responder.name || '<anon>';
+            const isCName = !Array.isArray(response) && response.type === "CNAME";
+            const recursedCName = isCName ? recurse(response.target, rrtype, rrclass, peerAddress, ownAddress, serializedContext) : { records: [] };
+            return badns_then(recursedCName, recursed => {
+                if (recursed.error) return recursed;
+                const records = Array.isArray(response) ? response : [ response, ...recursed.records ];
+                log.debug(`Responder ${handler} replied!`);
+                if (!records.every(e => validateResponse(e))){
+                    log.warn("Validation fail - returning SERVFAIL");
+                    return { handler, error: "validation failed" };
+                }
+                return { handler, records };
+            });
+        });
+    };
+    return ask(0);
+}
+
+// Calls `next` with the value, or with what it resolves to if it's a promise.
+function badns_then(value, next) {
+    return value instanceof Promise ? value.then(next) : next(value);
+}
+
+function recurse(name, rrtype, rrclass, peerAddress, ownAddress, serializedContext){
+    return badns_then(badns_respond(name, rrtype, rrclass, peerAddress, ownAddress, serializedContext), own => {
+        if(own.error || own.records.length) return own;
+        return { records: [{
+            special: true,
+            specialType: 'queryUpstream',
+            name, rrtype, rrclass
+        }] };
+    });
 }
 
 function validateResponse(response) {
     // Check 1 - is an object
     if (typeof response !== 'object') {
-        log("Validate: Not an object!");
+        log.warn("Validate: Not an object!");
         return false;
     }
 
@@ -206,7 +615,7 @@ function validateResponse(response) {
     }
 
     if (!_validate(response, globalRequiredFieldsAndTypes)) {
-        log(`Generic contents: required fields ${Object.keys(globalRequiredFieldsAndTypes)}`);
+        log.warn(`Generic contents: required fields ${Object.keys(globalRequiredFieldsAndTypes)}`);
         return false;
     }
 
@@ -215,7 +624,7 @@ function validateResponse(response) {
             'ip': 'string'
         };
         if (!_validate(response, aFields)) {
-            log(`A contents: required fields ${Object.keys(aFields)}`);
+            log.warn(`A contents: required fields ${Object.keys(aFields)}`);
             return false;
         }
     } else if(response.type === 'CNAME') {
@@ -223,7 +632,7 @@ function validateResponse(response) {
             'target': 'string',
         };
         if (!_validate(response, aFields)) {
-            log(`CNAME contents: required fields ${Object.keys(aFields)}`);
+            log.warn(`CNAME contents: required fields ${Object.keys(aFields)}`);
             return false;
         }
     }
@@ -255,6 +664,108 @@ function addUniversalBinding(handler) {
     unnamedBindings.push(handler);
 }
 
+function onUpstreamResponse(hook) {
+    upstreamResponseHooks.push(hook);
+}
+
+function setTimeout(handler, delay = 0, ...args) {
+    return createTimer(handler, delay, args, false);
+}
+
+function setInterval(handler, delay = 0, ...args) {
+    return createTimer(handler, delay, args, true);
+}
+
+function clearTimeout(id) {
+    delete timers[id];
+}
+
+const clearInterval = clearTimeout;
+
+const store = {
+    get(key) {
+        const value = badns_storeGet(String(key));
+        return value === '' ? undefined : JSON.parse(value);
+    },
+    set(key, value) {
+        if (value === undefined) {
+            throw Error("Cannot store undefined - use store.delete() instead!");
+        }
+        badns_storeSet(String(key), JSON.stringify(value));
+    },
+    delete(key) {
+        return badns_storeDelete(String(key));
+    },
+    keys() {
+        return JSON.parse(badns_storeKeys());
+    },
+};
+
+// fetch() and resolve() calls waiting for Rust to complete them, by id
+const badns_pendingCalls = new Map();
+let badns_nextCallId = 0;
+
+// Responses of async handlers by id, null until they settle
+const badns_pendingResponses = new Map();
+let badns_nextResponseId = 0;
+
+// Starts a call that Rust completes through badns_complete() once its task is done.
+function badns_call(start) {
+    return new Promise((resolve, reject) => {
+        const id = badns_nextCallId;
+        badns_nextCallId = (badns_nextCallId + 1) % 0x7fffffff;
+        start(id);
+        badns_pendingCalls.set(id, { resolve, reject });
+    });
+}
+
+function fetch(url, options = {}) {
+    const serializedOptions = JSON.stringify({
+        method: options.method,
+        headers: options.headers,
+        body: options.body,
+        timeout: options.timeout,
+    });
+    return badns_call(id => badns_fetch(id, String(url), serializedOptions)).then(serialized => {
+        const raw = JSON.parse(serialized);
+        return {
+            url: String(url),
+            status: raw.status,
+            ok: raw.status >= 200 && raw.status < 300,
+            headers: raw.headers,
+            text: () => raw.body,
+            json: () => JSON.parse(raw.body),
+        };
+    });
+}
+
+function env(name, fallback = null) {
+    return JSON.parse(badns_env(String(name))) ?? fallback;
+}
+
+function secrets(filename) {
+    return JSON.parse(badns_secrets(String(filename)));
+}
+
+function hmac(algorithm, key, data, keyEncoding = 'utf8') {
+    return badns_hmac(String(algorithm), String(key), String(data), keyEncoding);
+}
+
+function randomBytes(count, encoding = 'hex') {
+    return badns_randomBytes(count, encoding);
+}
+
+const base64Encode = (data, inputEncoding = 'utf8') => badns_convert(String(data), inputEncoding, 'base64');
+const base64Decode = (data, outputEncoding = 'utf8') => badns_convert(String(data), 'base64', outputEncoding);
+const base32Encode = (data, inputEncoding = 'utf8') => badns_convert(String(data), inputEncoding, 'base32');
+const base32Decode = (data, outputEncoding = 'utf8') => badns_convert(String(data), 'base32', outputEncoding);
+const hexEncode = (data, inputEncoding = 'utf8') => badns_convert(String(data), inputEncoding, 'hex');
+const hexDecode = (data, outputEncoding = 'utf8') => badns_convert(String(data), 'hex', outputEncoding);
+
+function timingSafeEqual(a, b) {
+    return badns_timingSafeEqual(String(a), String(b));
+}
+
 function STUB(){
     return {
         "type": "A",
@@ -279,6 +790,45 @@ function exec(fname){
     eval(readFile(fname));
 }
 
+function resolve(name, rrtype = RR_A){
+    return badns_call(id => badns_resolve(id, name, rrtype)).then(JSON.parse);
+}
+
+// ============================================ ES modules =============================================
+// Config files can be ES modules, loaded by QuickJS itself. Rust resolves and reads the files they
+// import, except for 'badns', whose source is built here from the API below.
+
+const badns_extensionNames = [];
+
+const BADNS_API = [
+    'bindAddress', 'upstream', 'tsigKey', 'dnssecValidation', 'setupHTTPRedirectServer', 'addHTTPRedirect', 'openStore', 'queryLog', 'dnstap', 'loadZone', 'declareZone',
+    'allowTransfer', 'secondaryZone', 'allowUpdate', 'onUpdate', 'signZone',
+    'addBinding', 'addABinding', 'addAAAABinding', 'addCNAMEBinding', 'addUniversalBinding',
+    'onUpstreamResponse', 'STUB', 'permanentBinding', 'ban', 'exec', 'resolve', 'fetch',
+    'setTimeout', 'setInterval', 'clearTimeout', 'clearInterval', 'store', 'log', 'console',
+    'env', 'secrets', 'hmac', 'randomBytes', 'base64Encode', 'base64Decode', 'base32Encode', 'base32Decode',
+    'hexEncode', 'hexDecode', 'timingSafeEqual',
+    'RRs', 'RRrevs', ...Object.keys(RRs),
+];
+
+function badns_registerExtension(name) {
+    badns_extensionNames.push(name);
+}
+
+function badns_getAPIModule() {
+    const api = {};
+    for (let name of [...BADNS_API, ...badns_extensionNames]) {
+        api[name] = globalThis[name] ?? eval(name);
+    }
+    return api;
+}
+
+function badns_apiModuleSource() {
+    // QuickJS doesn't pick up exports declared by destructuring, so each name gets its own declaration
+    const exports = [...BADNS_API, ...badns_extensionNames].map(e => `${e} = badns_api.${e}`);
+    return `const badns_api = badns_getAPIModule();\nexport default badns_api;\nexport const ${exports.join(', ')};\n`;
+}
+
 /*
 baDNS response type:
 For A / AAAA bindings: 
@@ -1,28 +1,107 @@
+use data_encoding::HEXLOWER_PERMISSIVE;
 use num_traits::cast::FromPrimitive;
-use quick_js::{Callback, Context, JsValue};
-use rustdns::{Class, Question, Record, Resource, Type};
-use serde_json::Value;
+use quick_js::{Callback, ExecutionError, JsValue};
+use rustdns::{Class, Question, Record, Type};
+use serde_json::{json, Value};
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
-use std::time::Duration;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use std::{
     collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
     sync::{Arc, Mutex},
 };
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::time::timeout_at;
+use tracing::{debug, error, info, warn};
 
+use crate::calls::{Call, CallRequest, Completions};
+use crate::convert::{
+    field_class, field_str, field_type, parse_record, record_to_json, ConversionError,
+};
+use crate::crypto::{convert, hmac, random_bytes, timing_safe_equal};
+use crate::dnstap::{self, Dnstap};
 use crate::messages::{SUPPORTED_RR, SUPPORTED_RR_NAMES};
+use crate::metrics;
+use crate::querylog::{QueryLog, QUERY_LOG};
+use crate::quickjs::{is_module, Context};
+use crate::secrets::{env, read_secrets};
 use crate::server::query_upstream;
+use crate::signer::{parse_algorithm, Denial, ZoneSigner};
+use crate::store::{Store, STORE};
+use crate::timers::TimerRequest;
+use crate::transfer::run_secondary;
+use crate::tsig::TsigKey;
+use crate::update::UpdatePolicy;
+use crate::validator::{Security, Validator};
+use crate::zone::{declare_zone, load_zone, normalize, ZoneKind, ZoneSet};
 
 #[derive(Debug, Clone)]
 pub struct Address {
     pub address: String,
     pub port: u16,
+    // Upstreams only: queries to them are signed with this key.
+    pub tsig_key: Option<TsigKey>,
+}
+
+// Resource limits applied to every JS context.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    // Handlers running longer than this are interrupted, and their query fails. Timers, admin
+    // calls and the other calls into JS are interrupted after as long.
+    pub handler_timeout: Option<Duration>,
+    // Maximum size of the JS heap in bytes. Allocations over it throw inside the handler.
+    pub memory: Option<usize>,
 }
 
 pub struct JSResponse {
     pub records: Vec<Record>,
     pub authoritative: bool,
+    // Set when the handler's response couldn't be converted - the query should fail with SERVFAIL.
+    pub failed: bool,
+    // Name of the binding that answered.
+    pub handler: Option<String>,
+    // Set when nothing answered, but some binding exists for the name or a name below it.
+    pub name_exists: bool,
+}
+
+// Everything known about a query besides the question itself.
+pub struct QueryContext {
+    pub peer: SocketAddr,
+    pub local: SocketAddr,
+    // The address the server was bound to with bindAddress().
+    pub listener: String,
+    pub transport: &'static str,
+    pub id: u16,
+    pub recursion_desired: bool,
+    pub checking_disabled: bool,
+    pub dnssec_ok: bool,
+    pub client_subnet: Option<String>,
+}
+
+impl QueryContext {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "clientIp": self.peer.ip().to_string(),
+            "clientPort": self.peer.port(),
+            "ownIp": self.local.ip().to_string(),
+            "ownPort": self.local.port(),
+            "listener": self.listener,
+            "transport": self.transport,
+            "id": self.id,
+            "flags": {
+                "rd": self.recursion_desired,
+                "cd": self.checking_disabled,
+                "do": self.dnssec_ok,
+            },
+            "clientSubnet": self.client_subnet,
+        })
+    }
 }
 
 impl Address {
@@ -31,64 +110,218 @@ impl Address {
     }
 }
 
+// Resolves a relative import against the directory of the importing module. 'badns' is the API.
+fn resolve_module_path(importer: &str, specifier: &str) -> Result<String, String> {
+    if specifier == "badns" {
+        return Ok(specifier.to_string());
+    }
+    if !(specifier.starts_with("./") || specifier.starts_with("../") || specifier.starts_with('/'))
+    {
+        return Err(format!(
+            "Cannot import {} - only relative paths and 'badns' can be imported!",
+            specifier
+        ));
+    }
+    let directory = Path::new(importer).parent().unwrap_or(Path::new("/"));
+    match directory.join(specifier).canonicalize() {
+        Ok(e) => Ok(e.to_string_lossy().to_string()),
+        Err(reason) => Err(format!(
+            "Cannot import {} from {}! ({})",
+            specifier, importer, reason
+        )),
+    }
+}
+
+// Looks up a key added with tsigKey() by the configuration.
+fn registered_key(keys: &Mutex<Vec<TsigKey>>, name: &str) -> Result<TsigKey, String> {
+    let name = normalize(name);
+    keys.lock()
+        .unwrap()
+        .iter()
+        .find(|e| e.name == name)
+        .cloned()
+        .ok_or_else(|| format!("TSIG key {} must be added with tsigKey() first", name))
+}
+
+// Strips the trailing dot, handlers see names the way they were bound.
+fn js_name(question: &Question) -> String {
+    let mut name = question.name.chars();
+    name.next_back();
+    name.as_str().to_string()
+}
+
 impl JSResponse {
     pub fn default() -> JSResponse {
         JSResponse {
             records: Vec::default(),
             authoritative: false,
+            failed: false,
+            handler: None,
+            name_exists: false,
         }
     }
 }
 
+// Why calling a handler failed.
+enum HandlerError {
+    // It ran past the handler timeout, and was interrupted if it hadn't returned by then.
+    Timeout(String),
+    Exception(String),
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::Timeout(e) | HandlerError::Exception(e) => f.write_str(e),
+        }
+    }
+}
+
+// Process-wide outputs set up by the configuration. They're only swapped in once it has fully
+// loaded, so that one failing halfway leaves the running configuration's outputs alone.
+#[derive(Default)]
+struct Outputs {
+    // Once the configuration has loaded, changes apply right away
+    applied: bool,
+    query_log: Option<QueryLog>,
+    dnstap: Option<Dnstap>,
+    // Passed to openStore(), with the store read from it unless it's already the open one
+    store_file: Option<String>,
+    store: Option<Store>,
+}
+
+// Runs `action` on the store the configuration opened, or on the process-wide one.
+fn with_store<T>(outputs: &Mutex<Outputs>, action: impl FnOnce(&mut Store) -> T) -> T {
+    let mut outputs = outputs.lock().unwrap();
+    match outputs.store.as_mut() {
+        Some(store) => action(store),
+        None => action(&mut STORE.lock().unwrap()),
+    }
+}
+
 pub struct JSBridge {
     pub bound_addresses: Arc<Mutex<Vec<Address>>>,
     pub upstreams: Arc<Mutex<Vec<Address>>>,
     pub http_redirects: Arc<Mutex<HashMap<String, String>>>,
+    pub zones: Arc<Mutex<ZoneSet>>,
+    // Registered with tsigKey(), for signed queries and transfers
+    pub tsig_keys: Arc<Mutex<Vec<TsigKey>>>,
+    // Set by dnssecValidation(), checks upstream answers
+    pub validator: Arc<Mutex<Option<Arc<Validator>>>>,
+    pub timer_requests: Option<UnboundedReceiver<TimerRequest>>,
+    // fetch() and resolve() calls, and their results once they're done
+    pub call_requests: Option<UnboundedReceiver<CallRequest>>,
+    pub completions: Arc<Completions>,
+    // Tells apart bridges swapped in by config reloads.
+    pub generation: u64,
+    outputs: Arc<Mutex<Outputs>>,
+    limits: Limits,
     context: Context,
 }
 
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
 impl JSBridge {
-    pub fn new() -> JSBridge {
-        let context = Context::new().unwrap();
+    pub fn new(limits: Limits) -> Result<JSBridge, String> {
+        let mut context = Context::new(limits.memory)?;
+        context.set_timeout(limits.handler_timeout);
+        let (timer_sender, timer_receiver) = unbounded_channel();
+        let (call_sender, call_receiver) = unbounded_channel();
 
         let mut this = JSBridge {
             context,
             bound_addresses: Arc::new(Mutex::new(Vec::new())),
             http_redirects: Arc::new(Mutex::new(HashMap::new())),
             upstreams: Arc::new(Mutex::new(Vec::new())),
+            zones: Arc::new(Mutex::new(ZoneSet::default())),
+            tsig_keys: Arc::new(Mutex::new(Vec::new())),
+            validator: Arc::new(Mutex::new(None)),
+            timer_requests: Some(timer_receiver),
+            call_requests: Some(call_receiver),
+            completions: Arc::new(Completions::default()),
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+            outputs: Arc::new(Mutex::new(Outputs::default())),
+            limits,
         };
 
         let addresses_ref = this.bound_addresses.clone();
         this.context
             .add_callback(
                 "badns_bindAddress",
-                move |address: String, port: i32| -> i32 {
+                move |address: String, port: i32| -> Result<i32, String> {
                     let real_port: u16 = match port.try_into() {
                         Ok(e) => e,
-                        Err(_x) => panic!("Cannot bind on port that's out of bounds!"),
+                        Err(_x) => return Err("Cannot bind on port that's out of bounds!".into()),
                     };
                     addresses_ref.lock().unwrap().push(Address {
                         address,
                         port: real_port,
+                        tsig_key: None,
                     });
-                    0
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        let tsig_keys_ref = this.tsig_keys.clone();
+        this.context
+            .add_callback(
+                "badns_tsigKey",
+                move |name: String, algorithm: String, secret: String| -> Result<i32, String> {
+                    let key = TsigKey::new(&name, &algorithm, &secret)?;
+                    let mut keys = tsig_keys_ref.lock().unwrap();
+                    keys.retain(|e| e.name != key.name);
+                    keys.push(key);
+                    Ok(0)
                 },
             )
             .unwrap();
         let upstreams_ref = this.upstreams.clone();
+        let tsig_keys_ref = this.tsig_keys.clone();
         this.context
-            .add_callback("badns_upstream", move |address: String, port: i32| -> i32 {
-                let real_port: u16 = match port.try_into() {
-                    Ok(e) => e,
-                    Err(_x) => panic!("Cannot use a port that"),
-                };
-                upstreams_ref.lock().unwrap().push(Address {
-                    address,
-                    port: real_port,
-                });
+            .add_callback(
+                "badns_upstream",
+                move |address: String, port: i32, options: String| -> Result<i32, String> {
+                    let real_port: u16 = match port.try_into() {
+                        Ok(e) => e,
+                        Err(_x) => return Err("Cannot use a port that's out of bounds!".into()),
+                    };
+                    let options: Value =
+                        serde_json::from_str(&options).map_err(|e| e.to_string())?;
+                    let tsig_key = match options["tsigKey"].as_str() {
+                        Some(name) => Some(registered_key(&tsig_keys_ref, name)?),
+                        None => None,
+                    };
+                    upstreams_ref.lock().unwrap().push(Address {
+                        address,
+                        port: real_port,
+                        tsig_key,
+                    });
 
-                0
-            })
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        let validator_ref = this.validator.clone();
+        this.context
+            .add_callback(
+                "badns_dnssecValidation",
+                move |options: String| -> Result<i32, String> {
+                    let options: Value =
+                        serde_json::from_str(&options).map_err(|e| e.to_string())?;
+                    let anchors: Vec<String> = match &options["trustAnchors"] {
+                        Value::Null => Vec::new(),
+                        Value::Array(anchors) => anchors
+                            .iter()
+                            .map(|e| e.as_str().map(str::to_string))
+                            .collect::<Option<_>>()
+                            .ok_or_else(|| "trust anchors must be strings".to_string())?,
+                        _ => return Err("trustAnchors must be an array".to_string()),
+                    };
+                    let validator = Validator::new(&anchors)?;
+                    *validator_ref.lock().unwrap() = Some(Arc::new(validator));
+                    Ok(0)
+                },
+            )
             .unwrap();
         let http_redirects_ref = this.http_redirects.clone();
         this.context
@@ -101,9 +334,332 @@ impl JSBridge {
             )
             .unwrap();
 
+        let resolve_upstreams_ref = this.upstreams.clone();
+        let resolve_validator_ref = this.validator.clone();
+        let resolve_sender = call_sender.clone();
+        this.context
+            .add_callback(
+                "badns_resolve",
+                move |id: i32, name: String, rrtype: i32| -> Result<i32, String> {
+                    let r#type = Type::from_i32(rrtype)
+                        .ok_or_else(|| format!("Cannot resolve unknown rrtype {}!", rrtype))?;
+                    let mut name = name;
+                    if !name.ends_with('.') {
+                        name.push('.');
+                    }
+                    let call = Call::Resolve {
+                        question: Question {
+                            name,
+                            r#type,
+                            class: Class::Internet,
+                        },
+                        upstreams: resolve_upstreams_ref.lock().unwrap().clone(),
+                        validator: resolve_validator_ref.lock().unwrap().clone(),
+                    };
+                    resolve_sender
+                        .send(CallRequest { id, call })
+                        .map_err(|_| "Call loop isn't running!".to_string())?;
+                    Ok(0)
+                },
+            )
+            .unwrap();
+
         this.context
-            .add_callback("badns_log", |x: String| -> i32 {
-                x.split('\n').for_each(|x| println!("[JS]: {}", x));
+            .add_callback(
+                "badns_fetch",
+                move |id: i32, url: String, options: String| -> Result<i32, String> {
+                    let options: Value =
+                        serde_json::from_str(&options).map_err(|e| e.to_string())?;
+                    call_sender
+                        .send(CallRequest {
+                            id,
+                            call: Call::Fetch { url, options },
+                        })
+                        .map_err(|_| "Call loop isn't running!".to_string())?;
+                    Ok(0)
+                },
+            )
+            .unwrap();
+
+        this.context
+            .add_callback("badns_scheduleTimer", move |id: i32, delay: i32| -> i32 {
+                let request = TimerRequest {
+                    id,
+                    delay: Duration::from_millis(delay.max(0) as u64),
+                };
+                if timer_sender.send(request).is_err() {
+                    warn!(target: "bridge", "Timer loop isn't running - timer {} dropped!", id);
+                }
+                0
+            })
+            .unwrap();
+
+        let outputs_ref = this.outputs.clone();
+        this.context
+            .add_callback(
+                "badns_storeOpen",
+                move |path: String| -> Result<i32, String> {
+                    let mut outputs = outputs_ref.lock().unwrap();
+                    // The open store is already what's in the file
+                    outputs.store = if STORE.lock().unwrap().is_backed_by(&path) {
+                        None
+                    } else {
+                        Some(Store::open(&path)?)
+                    };
+                    outputs.store_file = Some(path);
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        let outputs_ref = this.outputs.clone();
+        this.context
+            .add_callback("badns_storeGet", move |key: String| -> String {
+                with_store(&outputs_ref, |store| match store.get(&key) {
+                    Some(value) => value.to_string(),
+                    None => String::new(),
+                })
+            })
+            .unwrap();
+        let outputs_ref = this.outputs.clone();
+        this.context
+            .add_callback(
+                "badns_storeSet",
+                move |key: String, value: String| -> Result<i32, String> {
+                    let value: Value = serde_json::from_str(&value).map_err(|e| e.to_string())?;
+                    with_store(&outputs_ref, |store| store.set(key, value));
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        let outputs_ref = this.outputs.clone();
+        this.context
+            .add_callback("badns_storeDelete", move |key: String| -> bool {
+                with_store(&outputs_ref, |store| store.delete(&key))
+            })
+            .unwrap();
+        let outputs_ref = this.outputs.clone();
+        this.context
+            .add_callback("badns_storeKeys", move || -> String {
+                with_store(&outputs_ref, |store| Value::from(store.keys()).to_string())
+            })
+            .unwrap();
+
+        let outputs_ref = this.outputs.clone();
+        this.context
+            .add_callback(
+                "badns_queryLog",
+                move |options: String| -> Result<i32, String> {
+                    let options: Value =
+                        serde_json::from_str(&options).map_err(|e| e.to_string())?;
+                    let query_log = QueryLog::new(&options)?;
+                    let mut outputs = outputs_ref.lock().unwrap();
+                    if outputs.applied {
+                        *QUERY_LOG.lock().unwrap() = query_log;
+                    } else {
+                        outputs.query_log = Some(query_log);
+                    }
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        let zones_ref = this.zones.clone();
+        this.context
+            .add_callback(
+                "badns_loadZone",
+                move |origin: String, file: String| -> Result<i32, String> {
+                    let zone = load_zone(&origin, &file)?;
+                    info!(target: "zone", zone = %zone.origin, "Loaded {}", file);
+                    zones_ref.lock().unwrap().insert(zone);
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        let zones_ref = this.zones.clone();
+        this.context
+            .add_callback(
+                "badns_declareZone",
+                move |origin: String, soa: String, nameservers: String| -> Result<i32, String> {
+                    let soa: Value = serde_json::from_str(&soa).map_err(|e| e.to_string())?;
+                    let nameservers: Vec<String> =
+                        serde_json::from_str(&nameservers).map_err(|e| e.to_string())?;
+                    let zone = declare_zone(&origin, &soa, &nameservers)?;
+                    info!(target: "zone", zone = %zone.origin, "Declared zone");
+                    zones_ref.lock().unwrap().insert(zone);
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        let zones_ref = this.zones.clone();
+        let tsig_keys_ref = this.tsig_keys.clone();
+        this.context
+            .add_callback(
+                "badns_allowTransfer",
+                move |origin: String, clients: String| -> Result<i32, String> {
+                    let clients: Vec<String> =
+                        serde_json::from_str(&clients).map_err(|e| e.to_string())?;
+                    for key in clients.iter().filter_map(|e| e.strip_prefix("key:")) {
+                        registered_key(&tsig_keys_ref, key)?;
+                    }
+                    zones_ref
+                        .lock()
+                        .unwrap()
+                        .allow_transfer(&origin, &clients)?;
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        let zones_ref = this.zones.clone();
+        let tsig_keys_ref = this.tsig_keys.clone();
+        this.context
+            .add_callback(
+                "badns_allowUpdate",
+                move |origin: String, options: String| -> Result<i32, String> {
+                    let options: Value =
+                        serde_json::from_str(&options).map_err(|e| e.to_string())?;
+                    // Either names of registered keys, or names mapped to HMAC-SHA256 secrets
+                    let keys = match &options["keys"] {
+                        Value::Array(names) => names
+                            .iter()
+                            .map(|name| match name.as_str() {
+                                Some(name) => registered_key(&tsig_keys_ref, name),
+                                None => Err("Key names must be strings".to_string()),
+                            })
+                            .collect::<Result<Vec<_>, String>>()?,
+                        Value::Object(secrets) => secrets
+                            .iter()
+                            .map(|(name, secret)| match secret.as_str() {
+                                Some(secret) => TsigKey::new(name, "hmac-sha256", secret),
+                                None => Err(format!("The secret of key {} isn't base64", name)),
+                            })
+                            .collect::<Result<Vec<_>, String>>()?,
+                        _ => Vec::new(),
+                    };
+                    if keys.is_empty() {
+                        return Err("allowUpdate() needs at least one TSIG key".into());
+                    }
+                    let file = options["file"].as_str().map(|e| e.to_string());
+                    zones_ref
+                        .lock()
+                        .unwrap()
+                        .allow_update(&origin, UpdatePolicy { keys, file })?;
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        let zones_ref = this.zones.clone();
+        this.context
+            .add_callback(
+                "badns_signZone",
+                move |origin: String, options: String| -> Result<i32, String> {
+                    let options: Value =
+                        serde_json::from_str(&options).map_err(|e| e.to_string())?;
+                    let ksk = options["ksk"]
+                        .as_str()
+                        .ok_or_else(|| "signZone() needs a `ksk` key file".to_string())?;
+                    let algorithm =
+                        parse_algorithm(options["algorithm"].as_str().unwrap_or("ecdsap256sha256"))?;
+                    let denial = match &options["nsec3"] {
+                        Value::Null => Denial::Nsec,
+                        nsec3 => {
+                            let salt = match nsec3["salt"].as_str().unwrap_or("-") {
+                                "-" => Vec::new(),
+                                salt => HEXLOWER_PERMISSIVE
+                                    .decode(salt.as_bytes())
+                                    .map_err(|_| "The NSEC3 salt must be hex".to_string())?,
+                            };
+                            let iterations = nsec3["iterations"].as_u64().unwrap_or(0);
+                            Denial::Nsec3 {
+                                iterations: iterations
+                                    .try_into()
+                                    .map_err(|_| "Too many NSEC3 iterations".to_string())?,
+                                salt,
+                            }
+                        }
+                    };
+                    let signer =
+                        ZoneSigner::new(ksk, options["zsk"].as_str(), algorithm, denial)?;
+                    info!(target: "dnssec", zone = %normalize(&origin), "Publish in the parent zone: {}", signer.ds(&origin));
+                    zones_ref.lock().unwrap().sign_zone(&origin, signer);
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        let zones_ref = Arc::downgrade(&this.zones);
+        let tsig_keys_ref = this.tsig_keys.clone();
+        let secondary_runtime = tokio::runtime::Handle::current();
+        this.context
+            .add_callback(
+                "badns_secondaryZone",
+                move |origin: String,
+                      primary: String,
+                      port: i32,
+                      options: String|
+                      -> Result<i32, String> {
+                    let address: IpAddr = primary
+                        .parse()
+                        .map_err(|_| format!("{} isn't an IP address!", primary))?;
+                    let port: u16 = match port.try_into() {
+                        Ok(e) => e,
+                        Err(_x) => return Err("Cannot use a port that's out of bounds!".into()),
+                    };
+                    let options: Value =
+                        serde_json::from_str(&options).map_err(|e| e.to_string())?;
+                    let key = match options["tsigKey"].as_str() {
+                        Some(name) => Some(registered_key(&tsig_keys_ref, name)?),
+                        None => None,
+                    };
+                    info!(target: "zone", zone = %origin, primary = %primary, "Serving as a secondary");
+                    secondary_runtime.spawn(run_secondary(
+                        origin,
+                        SocketAddr::new(address, port),
+                        key,
+                        zones_ref.clone(),
+                    ));
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        let outputs_ref = this.outputs.clone();
+        this.context
+            .add_callback(
+                "badns_dnstap",
+                move |options: String| -> Result<i32, String> {
+                    let options: Value =
+                        serde_json::from_str(&options).map_err(|e| e.to_string())?;
+                    let output = dnstap::open(&options)?;
+                    let mut outputs = outputs_ref.lock().unwrap();
+                    if outputs.applied {
+                        dnstap::install(output);
+                    } else {
+                        outputs.dnstap = output;
+                    }
+                    Ok(0)
+                },
+            )
+            .unwrap();
+        this.context.add_callback("badns_env", env).unwrap();
+        this.context
+            .add_callback("badns_secrets", read_secrets)
+            .unwrap();
+        this.context.add_callback("badns_hmac", hmac).unwrap();
+        this.context.add_callback("badns_convert", convert).unwrap();
+        this.context
+            .add_callback("badns_randomBytes", random_bytes)
+            .unwrap();
+        this.context
+            .add_callback("badns_timingSafeEqual", timing_safe_equal)
+            .unwrap();
+
+        this.context
+            .add_callback("badns_log", |level: String, x: String| -> i32 {
+                for line in x.split('\n') {
+                    match level.as_str() {
+                        "debug" => debug!(target: "js", "{}", line),
+                        "warn" => warn!(target: "js", "{}", line),
+                        "error" => error!(target: "js", "{}", line),
+                        _ => info!(target: "js", "{}", line),
+                    }
+                }
                 0
             })
             .unwrap();
@@ -125,9 +681,27 @@ impl JSBridge {
         this.context.set_global("RRs", rrs).unwrap();
         this.context.set_global("RRrevs", reverse_rrs).unwrap();
 
-        this.eval(include_str!("init.js"));
+        // Fails when the memory limit is too small for the API itself
+        this.context
+            .eval(include_str!("init.js"))
+            .map_err(|e| format!("Couldn't run init.js! ({})", e))?;
 
-        this
+        Ok(this)
+    }
+
+    // Swaps in the query log, dnstap output and store set up by the configuration. Outputs it
+    // didn't set up are turned off, and the store is no longer saved without openStore().
+    pub fn apply_outputs(&self) {
+        let mut outputs = self.outputs.lock().unwrap();
+        outputs.applied = true;
+        *QUERY_LOG.lock().unwrap() = outputs.query_log.take().unwrap_or_default();
+        dnstap::install(outputs.dnstap.take());
+        let mut store = STORE.lock().unwrap();
+        match (outputs.store.take(), &outputs.store_file) {
+            (Some(opened), _) => *store = opened,
+            (None, None) => store.detach(),
+            (None, Some(_)) => {}
+        }
     }
 
     pub fn mark_http_as_frozen(&mut self) {
@@ -136,151 +710,731 @@ impl JSBridge {
 
     pub fn add_extension<F>(&mut self, name: &str, callback: impl Callback<F> + 'static) {
         self.context.add_callback(name, callback).unwrap();
+        self.context
+            .call_function(
+                "badns_registerExtension",
+                vec![JsValue::String(name.to_string())],
+            )
+            .unwrap();
     }
 
-    pub fn evaulate_file(&mut self, file_name: &str) {
+    pub fn evaulate_file(&mut self, file_name: &str) -> Result<(), String> {
         let mut init_file: File = match File::open(Path::new(file_name)) {
-            Err(reason) => panic!("Couldn't open {}! ({})", file_name, reason),
+            Err(reason) => return Err(format!("Couldn't open {}! ({})", file_name, reason)),
             Ok(file) => file,
         };
         let mut init_contents = String::new();
-        match init_file.read_to_string(&mut init_contents) {
-            Err(reason) => panic!("Couldn't read {}! ({})", file_name, reason),
-            Ok(e) => e,
+        if let Err(reason) = init_file.read_to_string(&mut init_contents) {
+            return Err(format!("Couldn't read {}! ({})", file_name, reason));
+        }
+
+        // Built now, so that the 'badns' module includes the extensions added since init.js ran
+        let api = match self.context.call_function("badns_apiModuleSource", vec![]) {
+            Ok(JsValue::String(e)) => e,
+            _ => return Err("Couldn't build the 'badns' module!".to_string()),
         };
+        self.context
+            .set_module_loader(resolve_module_path, move |name: &str| {
+                if name == "badns" {
+                    return Ok(api.clone());
+                }
+                std::fs::read_to_string(name)
+                    .map_err(|reason| format!("Couldn't read module {}! ({})", name, reason))
+            });
 
-        match self.context.eval(&init_contents) {
-            Err(reason) => panic!("Error while executing file! ({})", reason),
-            Ok(e) => e,
+        let result = if file_name.ends_with(".mjs") || is_module(&init_contents) {
+            let canonical = match Path::new(file_name).canonicalize() {
+                Ok(e) => e.to_string_lossy().to_string(),
+                Err(reason) => return Err(format!("Couldn't resolve {}! ({})", file_name, reason)),
+            };
+            self.context.eval_module(&canonical, &init_contents)
+        } else {
+            self.context.eval(&init_contents)
         };
+        match result {
+            Err(reason) => Err(format!("Error while executing file! ({})", reason)),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    // Returns the HTTP redirection proxy's address (port 0 if it's disabled) and its bindings.
+    pub fn http_settings(&mut self) -> (Address, HashMap<String, String>) {
+        let address = self
+            .eval("badns_httpRedirectHost")
+            .as_str()
+            .unwrap()
+            .to_string();
+        let port = match self.eval("badns_httpRedirectPort") {
+            JsValue::Int(e) => e as u16,
+            _ => 0,
+        };
+        (
+            Address {
+                address,
+                port,
+                tsig_key: None,
+            },
+            self.http_redirects.lock().unwrap().clone(),
+        )
+    }
+
+    pub fn fire_timer(&mut self, id: i32) {
+        if let Err(e) = self
+            .context
+            .call_function("badns_fireTimer", vec![JsValue::Int(id)])
+        {
+            error!(target: "js", "Timer {} failed! ({})", id, e);
+        }
+    }
+
+    // Hands the results of finished fetch() and resolve() calls to the JS waiting for them.
+    pub fn deliver_completions(&mut self) -> Result<(), ExecutionError> {
+        for (id, result) in self.completions.take() {
+            let (ok, result) = match result {
+                Ok(e) => (true, e),
+                Err(e) => (false, e),
+            };
+            let args = vec![JsValue::Int(id), JsValue::Bool(ok), JsValue::String(result)];
+            self.context.call_function("badns_complete", args)?;
+        }
+        Ok(())
+    }
+
+    // Calls one of the badns_admin* functions, which all return JSON.
+    pub fn call_admin(&mut self, function: &str, args: Vec<JsValue>) -> Result<Value, String> {
+        match self.context.call_function(function, args) {
+            Ok(JsValue::String(str)) => serde_json::from_str(&str).map_err(|e| e.to_string()),
+            Ok(_) => Err(format!("{} didn't return a string", function)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    // Runs the onUpdate hooks, which can refuse a dynamic update.
+    pub fn accept_update(&mut self, zone: &str, update: &Value) -> Result<bool, String> {
+        let args = vec![
+            JsValue::String(zone.to_string()),
+            JsValue::String(update.to_string()),
+        ];
+        match self.context.call_function("badns_onUpdate", args) {
+            Ok(JsValue::Bool(e)) => Ok(e),
+            Ok(_) => Err("badns_onUpdate didn't return a boolean".to_string()),
+            Err(e) => Err(e.to_string()),
+        }
     }
 
     pub fn eval(&mut self, data: &str) -> JsValue {
         self.context.eval(data).unwrap()
     }
 
-    async fn response_handle_special(&self, special_value: &Value, response: &mut JSResponse) {
-        match special_value["specialType"].as_str() {
-            Some("queryUpstream") => {
-                macro_rules! type_fail {
-                    () => {{
-                        println!("[JS->RS]: Type conversion error!");
-                        return;
-                    }};
+    // Calls a JS function, interrupting it once it runs past the handler deadline. Async handlers
+    // answer with the id of their response instead, which is waited for until the same deadline.
+    async fn call_with_deadline(
+        bridge: &Rc<AsyncMutex<JSBridge>>,
+        function: &str,
+        args: Vec<JsValue>,
+        question: &Question,
+    ) -> Result<JsValue, HandlerError> {
+        let (mut result, start, limits, generation) = {
+            let instance = bridge.lock().await;
+            let start = Instant::now();
+            let deadline = instance.limits.handler_timeout.map(|e| start + e);
+            instance.context.set_deadline(deadline);
+            let result = instance.context.call_function(function, args);
+            instance.context.set_deadline(None);
+            (result, start, instance.limits, instance.generation)
+        };
+        if let Ok(JsValue::Int(id)) = result {
+            let deadline = limits.handler_timeout.map(|e| start + e);
+            result = JSBridge::wait_for_response(bridge, id, deadline, generation).await;
+            // Responses that didn't settle in time are thrown away once they do
+            let instance = bridge.lock().await;
+            if instance.generation == generation {
+                let _ = instance
+                    .context
+                    .call_function("badns_dropResponse", vec![JsValue::Int(id)]);
+            }
+        }
+        let elapsed = start.elapsed();
+        metrics::observe(
+            "badns_js_duration_seconds",
+            &[("function", function)],
+            elapsed,
+        );
+        if let Some(timeout) = limits.handler_timeout {
+            if elapsed > timeout {
+                metrics::count(
+                    "badns_js_errors_total",
+                    &[("function", function), ("reason", "timeout")],
+                );
+                return Err(HandlerError::Timeout(format!(
+                    "{} ran for {}ms while handling {}, over the {}ms limit",
+                    function,
+                    elapsed.as_millis(),
+                    question.name,
+                    timeout.as_millis()
+                )));
+            }
+        }
+        result.map_err(|e| {
+            metrics::count(
+                "badns_js_errors_total",
+                &[("function", function), ("reason", "exception")],
+            );
+            HandlerError::Exception(e.to_string())
+        })
+    }
+
+    // Hands finished calls over to JS until the async handler's response settled. The bridge is
+    // only locked to do so, and other queries are answered while the calls run.
+    async fn wait_for_response(
+        bridge: &Rc<AsyncMutex<JSBridge>>,
+        id: i32,
+        deadline: Option<Instant>,
+        generation: u64,
+    ) -> Result<JsValue, ExecutionError> {
+        let completions = bridge.lock().await.completions.clone();
+        loop {
+            let finished = completions.finished();
+            {
+                let mut instance = bridge.lock().await;
+                // A reload swapped in another context, which knows nothing of the response
+                if instance.generation != generation {
+                    return Err(ExecutionError::Internal(
+                        "the configuration was reloaded".to_string(),
+                    ));
+                }
+                instance.context.set_deadline(deadline);
+                let response = instance.deliver_completions().and_then(|_| {
+                    instance
+                        .context
+                        .call_function("badns_takeResponse", vec![JsValue::Int(id)])
+                });
+                instance.context.set_deadline(None);
+                match response? {
+                    JsValue::Null => {}
+                    response => return Ok(response),
+                }
+            }
+            match deadline {
+                Some(deadline) => {
+                    if timeout_at(deadline.into(), finished).await.is_err() {
+                        return Err(ExecutionError::Internal(
+                            "the handler's response didn't settle in time".to_string(),
+                        ));
+                    }
                 }
+                None => finished.await,
+            }
+        }
+    }
 
-                let name = match special_value["name"].as_str() {
-                    Some(x) => String::from(x),
-                    None => type_fail!(),
-                };
-                let r#type: Type = match special_value["rrtype"].as_i64() {
-                    Some(x) => Type::from_i32(x as i32).unwrap(),
-                    None => type_fail!(),
+    async fn response_handle_special(
+        bridge: &Rc<AsyncMutex<JSBridge>>,
+        special_value: &Value,
+        context: &QueryContext,
+        response: &mut JSResponse,
+    ) -> Result<(), ConversionError> {
+        match field_str(special_value, "specialType")? {
+            "queryUpstream" => {
+                let name = field_str(special_value, "name")?.to_string();
+                let r#type: Type = field_type(special_value, "rrtype")?;
+                let class: Class = field_class(special_value, "rrclass")?;
+                let (upstreams, validator) = {
+                    let instance = bridge.lock().await;
+                    let upstreams = instance.upstreams.lock().unwrap().clone();
+                    let validator = instance.validator.lock().unwrap().clone();
+                    (upstreams, validator)
                 };
-                let class: Class = match special_value["rrclass"].as_i64() {
-                    Some(x) => Class::from_i32(x as i32).unwrap(),
-                    None => type_fail!(),
+                let question = Question {
+                    name,
+                    r#type,
+                    class,
                 };
-                let upstream_response = query_upstream(
-                    &Question {
-                        name,
-                        r#type,
-                        class,
-                    },
-                    self,
+                let upstream_response =
+                    query_upstream(&question, &upstreams, validator.as_deref()).await;
+                // Bogus answers are dropped, as resolve() does
+                if upstream_response.security == Security::Bogus {
+                    return Ok(());
+                }
+                // The onUpstreamResponse hooks see these answers too, as they do the servers'
+                let records = JSBridge::filter_upstream_response(
+                    bridge,
+                    &question,
+                    context,
+                    upstream_response.records,
                 )
-                .await;
-                response.records.extend(upstream_response);
+                .await?;
+                response.records.extend(records);
+                Ok(())
             }
-            Some(x) => println!("[JS->RS]: Invalid special value type {}!", x),
-            None => println!("[JS->RS]: Invalid special value type - not a string!"),
+            x => Err(ConversionError {
+                field: "specialType".to_string(),
+                reason: format!("invalid special value type {:?}", x),
+            }),
         }
     }
 
+    // Asks the JS bindings for an answer. Universal bindings are skipped for names inside
+    // a loaded zone, which answers those instead.
     pub async fn get_response(
-        &mut self,
+        bridge: &Rc<AsyncMutex<JSBridge>>,
         message: &Question,
-        addr: &str,
-        bind_addr: &str,
+        context: &QueryContext,
+        zone: Option<ZoneKind>,
     ) -> JSResponse {
         let args: Vec<JsValue> = vec![
-            JsValue::String({
-                let mut name = message.name.chars();
-                name.next_back();
-                name.as_str().to_string()
-            }),
+            JsValue::String(js_name(message)),
             JsValue::Int(message.r#type as i32),
             JsValue::Int(message.class as i32),
-            JsValue::String(addr.to_owned()),
-            JsValue::String(bind_addr.to_owned()),
+            JsValue::String(context.peer.to_string()),
+            JsValue::String(context.local.to_string()),
+            JsValue::String(context.to_json().to_string()),
+            JsValue::String(zone.map(|e| e.as_str()).unwrap_or("").to_string()),
         ];
-        let json = match self.context.call_function("badns_getResponse", args) {
-            Ok(JsValue::String(str)) => match serde_json::from_str(&str) {
-                Ok(e) => e,
+        let json =
+            match JSBridge::call_with_deadline(bridge, "badns_getResponse", args, message).await {
+                Ok(JsValue::String(str)) => match serde_json::from_str(&str) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        error!(
+                            target: "bridge",
+                            "Cannot deserialize data received from getResponse ({})", e
+                        );
+                        Value::Null
+                    }
+                },
                 Err(e) => {
-                    println!(
-                        "[JS->RS]: Cannot deserialize data received from getResponse ({})",
-                        e
-                    );
-                    Value::Null
+                    error!(target: "js", name = %message.name, "Failed to run function! ({})", e);
+                    return JSResponse {
+                        failed: matches!(e, HandlerError::Timeout(_)),
+                        ..JSResponse::default()
+                    };
                 }
-            },
-            Err(e) => {
-                println!("[JS]: Failed to run function! ({})", e);
+                _ => Value::Null,
+            };
+
+        let handler = json["handler"].as_str().unwrap_or("<anon>").to_string();
+
+        let mut response = JSResponse::default();
+        if json["handler"].is_string() {
+            response.handler = Some(handler.clone());
+        }
+        response.name_exists = json["nameExists"].as_bool() == Some(true);
+        if let Some(error) = json["error"].as_str() {
+            error!(
+                target: "bridge",
+                handler = %handler,
+                name = %message.name,
+                "Handler returned an invalid response ({})", error
+            );
+            metrics::count(
+                "badns_js_errors_total",
+                &[
+                    ("function", "badns_getResponse"),
+                    ("reason", "invalid_response"),
+                ],
+            );
+            response.failed = true;
+            return response;
+        }
+
+        let records = match json["records"].as_array() {
+            Some(e) => e,
+            None => {
+                error!(target: "bridge", "Received value is not an array!");
                 return JSResponse::default();
             }
-            _ => Value::Null,
         };
+        for resp in records {
+            let result = if resp["special"].as_bool() == Some(true) {
+                // This is a special marker for the rust code.
+                JSBridge::response_handle_special(bridge, resp, context, &mut response).await
+            } else {
+                if resp["authoritative"].as_bool() == Some(true) {
+                    response.authoritative = true;
+                }
+                parse_record(resp, message).map(|record| response.records.push(record))
+            };
 
-        if !json.is_array() {
-            println!("[JS->RS]: Received value is not an array!");
-            return JSResponse::default();
+            if let Err(error) = result {
+                error!(
+                    target: "bridge",
+                    handler = %handler,
+                    name = %message.name,
+                    "Handler returned an invalid response ({})", error
+                );
+                metrics::count(
+                    "badns_js_errors_total",
+                    &[
+                        ("function", "badns_getResponse"),
+                        ("reason", "invalid_response"),
+                    ],
+                );
+                response.records.clear();
+                response.failed = true;
+                return response;
+            }
         }
 
-        let mut response = JSResponse::default();
+        response
+    }
 
-        for resp in json.as_array().unwrap() {
-            if resp["special"].as_bool() == Some(true) {
-                // This is a special marker for the rust code.
-                self.response_handle_special(resp, &mut response).await;
-                continue;
+    // Runs the onUpstreamResponse hooks over the records received from upstream.
+    // Records that can't be represented in JS are passed through untouched.
+    pub async fn filter_upstream_response(
+        bridge: &Rc<AsyncMutex<JSBridge>>,
+        question: &Question,
+        context: &QueryContext,
+        records: Vec<Record>,
+    ) -> Result<Vec<Record>, ConversionError> {
+        let mut passthrough = Vec::new();
+        let mut serialized = Vec::new();
+        for record in &records {
+            match record_to_json(record) {
+                Some(e) => serialized.push(e),
+                None => passthrough.push(record.clone()),
             }
-            let name = match &resp["name"] {
-                Value::String(e) => e,
-                _ => &message.name,
-            }
-            .clone();
-            let ttl = Duration::from_secs(resp["ttl"].as_u64().unwrap());
-            let class = message.class;
-
-            let type_name = resp["type"].as_str().unwrap();
-            let resource: Option<Resource> = match type_name {
-                "A" => Some(Resource::A(resp["ip"].as_str().unwrap().parse().unwrap())),
-                "AAAA" => Some(Resource::AAAA(
-                    resp["ip"].as_str().unwrap().parse().unwrap(),
-                )),
-                "CNAME" => Some(Resource::CNAME(
-                    resp["target"].as_str().unwrap().to_string(),
-                )),
-                _ => {
-                    println!("[JS->RS]: Unrecognized type: {}", type_name);
-                    None
+        }
+
+        let args: Vec<JsValue> = vec![
+            JsValue::String(js_name(question)),
+            JsValue::Int(question.r#type as i32),
+            JsValue::Int(question.class as i32),
+            JsValue::String(context.peer.to_string()),
+            JsValue::String(context.local.to_string()),
+            JsValue::String(context.to_json().to_string()),
+            JsValue::String(Value::Array(serialized).to_string()),
+        ];
+        let json: Value =
+            match JSBridge::call_with_deadline(bridge, "badns_onUpstreamResponse", args, question)
+                .await
+            {
+                Ok(JsValue::String(str)) => match serde_json::from_str(&str) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        error!(
+                            target: "bridge",
+                            "Cannot deserialize data received from onUpstreamResponse ({})", e
+                        );
+                        return Ok(records);
+                    }
+                },
+                Err(HandlerError::Timeout(e)) => {
+                    error!(target: "js", name = %question.name, "Failed to run function! ({})", e);
+                    return Err(ConversionError {
+                        field: "onUpstreamResponse".to_string(),
+                        reason: e,
+                    });
                 }
+                Err(e) => {
+                    error!(target: "js", name = %question.name, "Failed to run function! ({})", e);
+                    return Ok(records);
+                }
+                _ => return Ok(records),
             };
-            if resp["authoritative"].as_bool() == Some(true) {
-                response.authoritative = true;
-            }
 
-            if let Some(resource) = resource {
-                response.records.push(Record {
-                    name,
-                    class,
-                    ttl,
-                    resource,
-                });
+        let rewritten = match json.as_array() {
+            Some(e) => e,
+            None => {
+                error!(target: "bridge", "Received value is not an array!");
+                return Ok(records);
+            }
+        };
+        for resp in rewritten {
+            match parse_record(resp, question) {
+                Ok(record) => passthrough.push(record),
+                Err(error) => {
+                    error!(
+                        target: "bridge",
+                        name = %question.name,
+                        "Upstream hook returned an invalid record ({})", error
+                    );
+                    return Err(error);
+                }
             }
         }
+        Ok(passthrough)
+    }
+}
 
-        response
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calls::run_calls;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use rustdns::Resource;
+    use std::convert::Infallible;
+    use std::net::Ipv4Addr;
+    use tokio::task::{spawn_local, LocalSet};
+
+    fn query_context() -> QueryContext {
+        QueryContext {
+            peer: "127.0.0.1:5353".parse().unwrap(),
+            local: "127.0.0.1:53".parse().unwrap(),
+            listener: "127.0.0.1:53".to_string(),
+            transport: "udp",
+            id: 1,
+            recursion_desired: true,
+            checking_disabled: false,
+            dnssec_ok: false,
+            client_subnet: None,
+        }
+    }
+
+    fn question(name: &str) -> Question {
+        Question {
+            name: name.to_string(),
+            r#type: Type::A,
+            class: Class::Internet,
+        }
+    }
+
+    // Serves `body` on a local port, taking `delay` to answer.
+    fn http_server(body: &'static str, delay: Duration) -> u16 {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_: Request<Body>| async move {
+                tokio::time::sleep(delay).await;
+                Ok::<_, Infallible>(Response::new(Body::from(body)))
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let port = server.local_addr().port();
+        tokio::spawn(server);
+        port
+    }
+
+    // Shares the bridge the way the servers do.
+    fn shared(bridge: JSBridge) -> Rc<AsyncMutex<JSBridge>> {
+        Rc::new(AsyncMutex::new(bridge))
+    }
+
+    // Runs the bridge's fetch() and resolve() calls, as the servers do.
+    fn run_bridge(mut bridge: JSBridge) -> Rc<AsyncMutex<JSBridge>> {
+        let requests = bridge.call_requests.take().unwrap();
+        let completions = bridge.completions.clone();
+        let generation = bridge.generation;
+        let bridge = shared(bridge);
+        spawn_local(run_calls(bridge.clone(), requests, completions, generation));
+        bridge
+    }
+
+    async fn ask(bridge: &Rc<AsyncMutex<JSBridge>>, name: &str) -> JSResponse {
+        JSBridge::get_response(bridge, &question(name), &query_context(), None).await
+    }
+
+    fn fetching_binding(name: &str, port: u16) -> String {
+        format!(
+            "addABinding('{}', async () => ({{ type: 'A', ttl: 60, ip: (await fetch('http://127.0.0.1:{}/')).text() }}))",
+            name, port
+        )
+    }
+
+    #[tokio::test]
+    async fn async_handler_awaits_fetch() {
+        // The server shares the test's only thread, so it can only answer if fetch() doesn't
+        // block it
+        let port = http_server("10.0.0.7", Duration::from_millis(50));
+        let mut bridge = JSBridge::new(Limits {
+            handler_timeout: Some(Duration::from_secs(5)),
+            memory: None,
+        })
+        .unwrap();
+        bridge.eval(&fetching_binding("svc.lab", port));
+        LocalSet::new()
+            .run_until(async {
+                let bridge = run_bridge(bridge);
+                let response = ask(&bridge, "svc.lab.").await;
+                assert!(!response.failed);
+                assert_eq!(response.records.len(), 1);
+                assert!(matches!(
+                    response.records[0].resource,
+                    Resource::A(ip) if ip == Ipv4Addr::new(10, 0, 0, 7)
+                ));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn queries_are_answered_while_a_handler_awaits_fetch() {
+        let port = http_server("10.0.0.7", Duration::from_millis(300));
+        let mut bridge = JSBridge::new(Limits {
+            handler_timeout: Some(Duration::from_secs(5)),
+            memory: None,
+        })
+        .unwrap();
+        bridge.eval(&fetching_binding("slow.lab", port));
+        bridge.eval("addABinding('good.lab', () => ({ type: 'A', ttl: 60, ip: '10.0.0.1' }))");
+        LocalSet::new()
+            .run_until(async {
+                let bridge = run_bridge(bridge);
+                let slow = spawn_local({
+                    let bridge = bridge.clone();
+                    async move { ask(&bridge, "slow.lab.").await }
+                });
+                // Lets the slow handler start its fetch()
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let started = Instant::now();
+                let response = ask(&bridge, "good.lab.").await;
+                assert_eq!(response.records.len(), 1);
+                assert!(started.elapsed() < Duration::from_millis(200));
+                let response = slow.await.unwrap();
+                assert!(!response.failed);
+                assert_eq!(response.records.len(), 1);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn fetch_outside_handlers_completes() {
+        let port = http_server("done", Duration::from_millis(10));
+        let mut bridge = JSBridge::new(Limits::default()).unwrap();
+        bridge.eval(&format!(
+            "var fetched = null; fetch('http://127.0.0.1:{}/').then(e => {{ fetched = e.text(); }});",
+            port
+        ));
+        LocalSet::new()
+            .run_until(async {
+                let bridge = run_bridge(bridge);
+                for _ in 0..100 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    if bridge.lock().await.eval("fetched") == JsValue::String("done".to_string()) {
+                        return;
+                    }
+                }
+                panic!("fetch() never completed");
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn async_handler_past_the_deadline_fails() {
+        let port = http_server("10.0.0.7", Duration::from_millis(500));
+        let mut bridge = JSBridge::new(Limits {
+            handler_timeout: Some(Duration::from_millis(50)),
+            memory: None,
+        })
+        .unwrap();
+        bridge.eval(&fetching_binding("slow.lab", port));
+        LocalSet::new()
+            .run_until(async {
+                let bridge = run_bridge(bridge);
+                let started = Instant::now();
+                let response = ask(&bridge, "slow.lab.").await;
+                assert!(response.failed);
+                assert!(started.elapsed() < Duration::from_millis(400));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn invalid_handler_output_fails_the_query() {
+        let mut bridge = JSBridge::new(Limits::default()).unwrap();
+        bridge.eval("addABinding('bad.lab', function broken() { return { type: 'A', ttl: 'soon', ip: '10.0.0.1' }; })");
+        let bridge = shared(bridge);
+        let response = ask(&bridge, "bad.lab.").await;
+        assert!(response.failed);
+        assert!(response.records.is_empty());
+        assert_eq!(response.handler.as_deref(), Some("broken"));
+    }
+
+    #[tokio::test]
+    async fn valid_handler_output_is_answered() {
+        let mut bridge = JSBridge::new(Limits::default()).unwrap();
+        bridge.eval("addABinding('good.lab', () => ({ type: 'A', ttl: 60, ip: '10.0.0.1' }))");
+        let bridge = shared(bridge);
+        let response = ask(&bridge, "good.lab.").await;
+        assert!(!response.failed);
+        assert_eq!(response.records.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn runaway_handler_is_interrupted() {
+        let mut bridge = JSBridge::new(Limits {
+            handler_timeout: Some(Duration::from_millis(50)),
+            memory: None,
+        })
+        .unwrap();
+        bridge.eval("addABinding('spin.lab', () => { while (true) {} })");
+        bridge.eval("addABinding('good.lab', () => ({ type: 'A', ttl: 60, ip: '10.0.0.1' }))");
+        let bridge = shared(bridge);
+        let response = ask(&bridge, "spin.lab.").await;
+        assert!(response.failed);
+        // The context keeps answering afterwards
+        let response = ask(&bridge, "good.lab.").await;
+        assert_eq!(response.records.len(), 1);
+    }
+
+    #[test]
+    fn memory_limit_too_small_fails_to_load() {
+        let limits = Limits {
+            handler_timeout: None,
+            memory: Some(0),
+        };
+        assert!(JSBridge::new(limits).is_err());
+    }
+
+    #[tokio::test]
+    async fn runaway_timer_is_interrupted() {
+        let mut bridge = JSBridge::new(Limits {
+            handler_timeout: Some(Duration::from_millis(50)),
+            memory: None,
+        })
+        .unwrap();
+        bridge.eval("addABinding('good.lab', () => ({ type: 'A', ttl: 60, ip: '10.0.0.1' }))");
+        let id = match bridge.eval("setTimeout(() => { while (true) {} })") {
+            JsValue::Int(e) => e,
+            e => panic!("setTimeout() returned {:?}", e),
+        };
+        let started = Instant::now();
+        bridge.fire_timer(id);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let bridge = shared(bridge);
+        let response = ask(&bridge, "good.lab.").await;
+        assert_eq!(response.records.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn config_modules_import_each_other_and_the_api() {
+        let directory = std::env::temp_dir().join(format!("badns-modules-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("lib")).unwrap();
+        std::fs::write(
+            directory.join("config.js"),
+            "import { addABinding } from 'badns';\n\
+             import { hosts, loaded, load } from './lib/hosts.js';\n\
+             load();\n\
+             for (let [name, ip] of hosts) addABinding(name, () => ({ type: 'A', ttl: 60, ip }));\n\
+             globalThis.loadedSeen = loaded;\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("lib/hosts.js"),
+            "export const hosts = [['module.lab', '10.0.0.9']];\n\
+             export let loaded = false;\n\
+             export function load() { loaded = true; }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("broken.js"),
+            "import { nothing } from 'elsewhere';\n",
+        )
+        .unwrap();
+
+        let mut bridge = JSBridge::new(Limits::default()).unwrap();
+        let result = bridge.evaulate_file(&directory.join("config.js").to_string_lossy());
+        let broken = JSBridge::new(Limits::default())
+            .unwrap()
+            .evaulate_file(&directory.join("broken.js").to_string_lossy());
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(result, Ok(()));
+        // Imports are live bindings
+        assert_eq!(bridge.eval("loadedSeen"), JsValue::Bool(true));
+        let bridge = shared(bridge);
+        let response = ask(&bridge, "module.lab.").await;
+        assert_eq!(response.records.len(), 1);
+        assert!(broken
+            .unwrap_err()
+            .contains("only relative paths and 'badns'"));
     }
 }
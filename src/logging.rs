@@ -0,0 +1,24 @@
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_FILTER: &str = "info";
+
+// Sets up the global logger. The filter uses the usual `level,target=level` syntax, where the
+// targets are baDNS' subsystems: dns, udp, cache, upstream, js, bridge, http, reload, store,
+// querylog.
+// It's taken from `filter`, falling back to the BADNS_LOG environment variable and then to "info".
+pub fn init_logging(filter: Option<&str>, json: bool) {
+    let filter = match filter {
+        Some(e) => EnvFilter::new(e),
+        None => {
+            EnvFilter::try_from_env("BADNS_LOG").unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER))
+        }
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(true);
+    if json {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
@@ -1,30 +1,57 @@
+mod admin;
+mod calls;
+mod convert;
+mod crypto;
+mod dnssec;
+mod dnstap;
+mod fetch;
 mod http;
 mod jsbridge;
+mod logging;
 mod messages;
+mod metrics;
+mod querylog;
+mod quickjs;
+mod reload;
+mod secrets;
 mod server;
+mod signer;
+mod stats;
+mod store;
+mod timers;
+mod transfer;
+mod tsig;
 mod ttldict;
+mod update;
+mod validator;
+mod wire;
+mod zone;
 
-use std::{collections::HashMap, env, fs::File, io::Read, path::Path, rc::Rc, thread};
+use std::{env, fs::File, io::Read, net::SocketAddr, path::Path, rc::Rc, thread, time::Duration};
 
+use admin::run_admin_server;
 use http::run_http_server;
-use jsbridge::{Address, JSBridge};
-use quick_js::JsValue;
-use server::run_server;
+use jsbridge::{JSBridge, Limits};
+use logging::init_logging;
+use metrics::run_metrics_server;
+use reload::{watch_for_reloads, ServerSet};
 use sha256::digest;
+use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::Mutex;
+use tracing::{error, info, warn};
 
 fn read_file(file_name: String) -> String {
     let mut str = String::new();
     let mut file = match File::open(Path::new(&file_name)) {
         Ok(file) => file,
         Err(error) => {
-            println!("Error while opening file {}: {}", file_name, error);
+            warn!(target: "js", "Error while opening file {}: {}", file_name, error);
             return "".to_string();
         }
     };
     match file.read_to_string(&mut str) {
         Err(error) => {
-            println!("Error while reading file {}: {}", file_name, error);
+            warn!(target: "js", "Error while reading file {}: {}", file_name, error);
             return "".to_string();
         }
         Ok(_) => 0,
@@ -33,63 +60,126 @@ fn read_file(file_name: String) -> String {
     str
 }
 
+fn load_config(config_file: &str, limits: Limits) -> Result<JSBridge, String> {
+    let mut bridge = JSBridge::new(limits)?;
+    bridge.add_extension("readFile", read_file);
+    bridge.add_extension("sha256", |x: String| digest(x));
+    bridge.evaulate_file(config_file)?;
+    bridge.mark_http_as_frozen();
+    bridge.apply_outputs();
+    Ok(bridge)
+}
+
+struct Options {
+    config_file: String,
+    watch_file: bool,
+    limits: Limits,
+    log_filter: Option<String>,
+    log_json: bool,
+    metrics_address: Option<SocketAddr>,
+    admin_address: Option<SocketAddr>,
+}
+
+fn parse_arguments(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        config_file: args.get(1)?.clone(),
+        watch_file: false,
+        limits: Limits::default(),
+        log_filter: None,
+        log_json: false,
+        metrics_address: None,
+        admin_address: None,
+    };
+    for argument in &args[2..] {
+        if argument == "--watch" {
+            options.watch_file = true;
+        } else if let Some(value) = argument.strip_prefix("--handler-timeout=") {
+            options.limits.handler_timeout = Some(Duration::from_millis(value.parse().ok()?));
+        } else if let Some(value) = argument.strip_prefix("--memory-limit=") {
+            options.limits.memory = Some(value.parse::<usize>().ok()? * 1024 * 1024);
+        } else if let Some(value) = argument.strip_prefix("--log=") {
+            options.log_filter = Some(value.to_string());
+        } else if argument == "--log-format=json" {
+            options.log_json = true;
+        } else if let Some(value) = argument.strip_prefix("--metrics=") {
+            options.metrics_address = Some(value.parse().ok()?);
+        } else if let Some(value) = argument.strip_prefix("--admin=") {
+            options.admin_address = Some(value.parse().ok()?);
+        } else {
+            return None;
+        }
+    }
+    Some(options)
+}
+
 #[tokio::main]
 async fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <config.js file location>", args[0]);
-        return;
-    }
-    let config_file = &args[1];
-
-    let bridge = Rc::new(Mutex::new(JSBridge::new()));
-    let mut addresses = Vec::new();
+    let options = match parse_arguments(&args) {
+        Some(e) => e,
+        None => {
+            println!(
+                "Usage: {} <config.js file location> [--watch] [--handler-timeout=<ms>] [--memory-limit=<MiB>] [--log=<filter>] [--log-format=json] [--metrics=<ip:port>] [--admin=<ip:port>]",
+                args[0]
+            );
+            return;
+        }
+    };
+    init_logging(options.log_filter.as_deref(), options.log_json);
+    let limits = options.limits;
+    let loader = move |file_name: &str| load_config(file_name, limits);
 
-    let http_host: String;
-    let http_port: u16;
-    let http_bindings: HashMap<String, String>;
+    let mut initial_bridge = match loader(&options.config_file) {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Failed to load the configuration: {}", e);
+            return;
+        }
+    };
+    let addresses = initial_bridge.bound_addresses.lock().unwrap().clone();
+    let (http_address, http_bindings) = initial_bridge.http_settings();
+    let timer_requests = initial_bridge.timer_requests.take().unwrap();
+    let call_requests = initial_bridge.call_requests.take().unwrap();
+    let completions = initial_bridge.completions.clone();
+    let generation = initial_bridge.generation;
+    let bridge = Rc::new(Mutex::new(initial_bridge));
 
-    {
-        let mut initial_reference = bridge.lock().await;
-        initial_reference.add_extension("readFile", read_file);
-        initial_reference.add_extension("sha256", |x: String| digest(x));
-        initial_reference.evaulate_file(config_file);
-        addresses.clone_from(&initial_reference.bound_addresses.lock().unwrap());
-        http_host = initial_reference
-            .eval("badns_httpRedirectHost")
-            .as_str()
-            .unwrap()
-            .to_string();
-        http_port = match initial_reference.eval("badns_httpRedirectPort") {
-            JsValue::Int(e) => e as u16,
-            _ => 0,
-        };
-        http_bindings = initial_reference.http_redirects.lock().unwrap().clone();
-        initial_reference.mark_http_as_frozen();
+    if http_address.port != 0 {
+        info!(target: "http", "Spawning HTTP Redirection Proxy");
+        thread::spawn(move || run_http_server(&http_address, http_bindings));
     }
-
-    if http_port != 0 {
-        println!("[HTTP]: Spawning HTTP Redirection Proxy");
-        thread::spawn(move || {
-            run_http_server(
-                &Address {
-                    address: http_host,
-                    port: http_port,
-                },
-                http_bindings,
-            )
-        });
+    if let Some(metrics_address) = options.metrics_address {
+        thread::spawn(move || run_metrics_server(metrics_address));
+    }
+    let (admin_sender, admin_requests) = unbounded_channel();
+    if let Some(admin_address) = options.admin_address {
+        match env::var("BADNS_ADMIN_TOKEN") {
+            Ok(token) if !token.is_empty() => {
+                thread::spawn(move || run_admin_server(admin_address, token, admin_sender));
+            }
+            _ => {
+                error!(target: "admin", "Not starting the admin API, BADNS_ADMIN_TOKEN isn't set!")
+            }
+        }
     }
 
     // Start all servers
     let local = tokio::task::LocalSet::new();
-
-    for address in &addresses {
-        let bridge_reference = bridge.clone();
-        let cloned_address = address.clone();
-        local.spawn_local(async move {
-            run_server(cloned_address, bridge_reference).await;
-        });
-    }
-    local.await;
+    local
+        .run_until(async move {
+            let mut servers = ServerSet::new(bridge.clone());
+            servers.start_timers(timer_requests, generation);
+            servers.start_calls(call_requests, completions, generation);
+            servers.apply(&addresses);
+            watch_for_reloads(
+                options.config_file,
+                bridge,
+                servers,
+                loader,
+                options.watch_file,
+                admin_requests,
+            )
+            .await;
+        })
+        .await;
 }
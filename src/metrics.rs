@@ -0,0 +1,201 @@
+// Prometheus metrics, rendered in the text exposition format on /metrics.
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use tracing::{error, info};
+
+// Upper bounds of the histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+// (name, type, help) of every exported metric
+const DESCRIPTIONS: [(&str, &str, &str); 10] = [
+    (
+        "badns_queries_total",
+        "counter",
+        "Answered queries by type and RCODE",
+    ),
+    (
+        "badns_cache_hits_total",
+        "counter",
+        "Questions answered from the cache",
+    ),
+    (
+        "badns_cache_misses_total",
+        "counter",
+        "Questions not found in the cache",
+    ),
+    (
+        "badns_upstream_duration_seconds",
+        "histogram",
+        "Time until an upstream answered",
+    ),
+    (
+        "badns_upstream_failures_total",
+        "counter",
+        "Failed upstream queries by reason",
+    ),
+    (
+        "badns_js_duration_seconds",
+        "histogram",
+        "Time spent running JS functions",
+    ),
+    (
+        "badns_js_errors_total",
+        "counter",
+        "JS functions that threw, timed out or returned invalid data",
+    ),
+    (
+        "badns_http_proxy_requests_total",
+        "counter",
+        "Requests to the HTTP redirection proxy by outcome",
+    ),
+    ("badns_updates_total", "counter", "Dynamic updates by RCODE"),
+    (
+        "badns_dnssec_validations_total",
+        "counter",
+        "Validated upstream answers by result",
+    ),
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    // Keyed by (name, rendered labels)
+    counters: BTreeMap<(&'static str, String), u64>,
+    histograms: BTreeMap<(&'static str, String), Histogram>,
+}
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(name, value)| {
+            let escaped = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, escaped)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn count(name: &'static str, labels: &[(&str, &str)]) {
+    let mut metrics = METRICS.lock().unwrap();
+    *metrics
+        .counters
+        .entry((name, render_labels(labels)))
+        .or_default() += 1;
+}
+
+pub fn observe(name: &'static str, labels: &[(&str, &str)], duration: Duration) {
+    let seconds = duration.as_secs_f64();
+    let mut metrics = METRICS.lock().unwrap();
+    let histogram = metrics
+        .histograms
+        .entry((name, render_labels(labels)))
+        .or_default();
+    for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKETS) {
+        if seconds <= bound {
+            *bucket += 1;
+        }
+    }
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+fn with_label(labels: &str, extra: &str) -> String {
+    if labels.is_empty() {
+        format!("{{{}}}", extra)
+    } else {
+        format!("{{{},{}}}", labels, extra)
+    }
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+pub fn render() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut output = String::new();
+    for (name, kind, help) in DESCRIPTIONS {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} {}", name, kind);
+        for ((_, labels), value) in metrics.counters.iter().filter(|e| e.0 .0 == name) {
+            let _ = writeln!(output, "{}{} {}", name, braced(labels), value);
+        }
+        for ((_, labels), histogram) in metrics.histograms.iter().filter(|e| e.0 .0 == name) {
+            for (bucket, bound) in histogram.buckets.iter().zip(BUCKETS) {
+                let bucket_labels = with_label(labels, &format!("le=\"{}\"", bound));
+                let _ = writeln!(output, "{}_bucket{} {}", name, bucket_labels, bucket);
+            }
+            let infinity = with_label(labels, "le=\"+Inf\"");
+            let _ = writeln!(output, "{}_bucket{} {}", name, infinity, histogram.count);
+            let _ = writeln!(output, "{}_sum{} {}", name, braced(labels), histogram.sum);
+            let _ = writeln!(
+                output,
+                "{}_count{} {}",
+                name,
+                braced(labels),
+                histogram.count
+            );
+        }
+    }
+    output
+}
+
+async fn metrics_service(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = if request.uri().path() == "/metrics" {
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(render()))
+    } else {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not found"))
+    };
+    Ok(response.unwrap())
+}
+
+#[tokio::main]
+pub async fn run_metrics_server(address: SocketAddr) {
+    let make_service =
+        make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(metrics_service)) });
+    let server = match Server::try_bind(&address) {
+        Ok(e) => e.serve(make_service),
+        Err(e) => {
+            error!(target: "metrics", "Cannot bind {} ({})", address, e);
+            return;
+        }
+    };
+
+    info!(target: "metrics", "Serving metrics on http://{}/metrics", address);
+
+    if let Err(e) = server.await {
+        error!(target: "metrics", "Server error: {}", e);
+    }
+}
@@ -0,0 +1,176 @@
+use lazy_static::lazy_static;
+use rustdns::{Rcode, Record, Resource};
+use serde_json::{json, Value};
+use std::ffi::OsString;
+use std::fs::{rename, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: u64 = 5;
+const SYSLOG_SOCKET: &str = "/dev/log";
+// Facility daemon (3), severity informational (6)
+const SYSLOG_PRIORITY: u8 = 3 * 8 + 6;
+
+pub struct QueryLogEntry<'a> {
+    pub client: String,
+    pub name: &'a str,
+    pub rrtype: u16,
+    pub answers: &'a [Record],
+    pub rcode: Rcode,
+    // Where the answer came from: `cache`, `js:<handler>`, `upstream:<address>` or `none`.
+    pub source: &'a str,
+    pub latency: Duration,
+}
+
+pub fn describe_record(record: &Record) -> String {
+    match &record.resource {
+        Resource::A(ip) => format!("A {}", ip),
+        Resource::AAAA(ip) => format!("AAAA {}", ip),
+        Resource::CNAME(target) => format!("CNAME {}", target),
+        other => format!("{:?}", other),
+    }
+}
+
+impl QueryLogEntry<'_> {
+    pub fn to_json(&self) -> Value {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        json!({
+            "timestamp": timestamp.as_millis() as u64,
+            "client": self.client,
+            "name": self.name,
+            "type": self.rrtype,
+            "answers": self.answers.iter().map(describe_record).collect::<Vec<_>>(),
+            "rcode": format!("{:?}", self.rcode),
+            "source": self.source,
+            "latency_us": self.latency.as_micros() as u64,
+        })
+    }
+}
+
+pub trait QueryLogSink: Send {
+    fn write(&mut self, line: &str);
+}
+
+// Appends JSON lines to a file, rotating it to `file.1`, `file.2`, ... once it grows over `max_size`.
+struct FileSink {
+    path: PathBuf,
+    max_size: u64,
+    keep: u64,
+    file: File,
+    size: u64,
+}
+
+impl FileSink {
+    fn open(path: PathBuf, max_size: u64, keep: u64) -> std::io::Result<FileSink> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(FileSink {
+            path,
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn numbered(&self, index: u64) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for index in (1..self.keep).rev() {
+            let source = self.numbered(index);
+            if source.exists() {
+                rename(&source, self.numbered(index + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            rename(&self.path, self.numbered(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl QueryLogSink for FileSink {
+    fn write(&mut self, line: &str) {
+        if self.size + line.len() as u64 + 1 > self.max_size {
+            if let Err(e) = self.rotate() {
+                error!(target: "querylog", "Cannot rotate {} ({})", self.path.display(), e);
+            }
+        }
+        match writeln!(self.file, "{}", line) {
+            Ok(_) => self.size += line.len() as u64 + 1,
+            Err(e) => error!(target: "querylog", "Cannot write to {} ({})", self.path.display(), e),
+        }
+    }
+}
+
+struct SyslogSink {
+    socket: UnixDatagram,
+}
+
+impl QueryLogSink for SyslogSink {
+    fn write(&mut self, line: &str) {
+        let message = format!("<{}>badns: {}", SYSLOG_PRIORITY, line);
+        if let Err(e) = self.socket.send(message.as_bytes()) {
+            error!(target: "querylog", "Cannot write to syslog ({})", e);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct QueryLog {
+    sinks: Vec<Box<dyn QueryLogSink>>,
+}
+
+lazy_static! {
+    pub static ref QUERY_LOG: Mutex<QueryLog> = Mutex::new(QueryLog::default());
+}
+
+impl QueryLog {
+    // Opens the sinks according to queryLog()'s options object.
+    pub fn new(options: &Value) -> Result<QueryLog, String> {
+        let mut sinks: Vec<Box<dyn QueryLogSink>> = Vec::new();
+        if let Some(file_name) = options["file"].as_str() {
+            let max_size = options["maxSize"].as_u64().unwrap_or(DEFAULT_MAX_SIZE);
+            let keep = options["keep"].as_u64().unwrap_or(DEFAULT_KEEP);
+            let sink = FileSink::open(PathBuf::from(file_name), max_size, keep)
+                .map_err(|e| format!("Cannot open query log {} ({})", file_name, e))?;
+            sinks.push(Box::new(sink));
+        }
+        if options["syslog"].as_bool() == Some(true) {
+            let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+            socket
+                .connect(SYSLOG_SOCKET)
+                .map_err(|e| format!("Cannot connect to {} ({})", SYSLOG_SOCKET, e))?;
+            sinks.push(Box::new(SyslogSink { socket }));
+        }
+        Ok(QueryLog { sinks })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.sinks.is_empty()
+    }
+
+    pub fn write(&mut self, entry: &QueryLogEntry) {
+        let line = entry.to_json().to_string();
+        for sink in &mut self.sinks {
+            sink.write(&line);
+        }
+    }
+}
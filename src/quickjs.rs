@@ -0,0 +1,581 @@
+// A QuickJS context driven through libquickjs-sys directly, for what quick-js' Context keeps
+// out of reach: interrupting JS that runs past a deadline, and loading ES modules. Values and
+// callbacks are quick-js'.
+use libquickjs_sys as q;
+use quick_js::{Callback, ExecutionError, JsValue, ValueError};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
+
+// JS_TAG_* from quickjs.h, which bindgen doesn't pick up
+const TAG_STRING: i64 = -7;
+const TAG_OBJECT: i64 = -1;
+const TAG_INT: i64 = 0;
+const TAG_BOOL: i64 = 1;
+const TAG_NULL: i64 = 2;
+const TAG_UNDEFINED: i64 = 3;
+const TAG_EXCEPTION: i64 = 6;
+const TAG_FLOAT64: i64 = 7;
+
+type WrappedCallback = dyn Fn(c_int, *mut q::JSValue) -> q::JSValue;
+type Normalizer = dyn Fn(&str, &str) -> Result<String, String>;
+type Loader = dyn Fn(&str) -> Result<String, String>;
+
+// Resolves an import against the name of the importing module, and reads a module's source.
+struct Modules {
+    normalize: Box<Normalizer>,
+    load: Box<Loader>,
+}
+
+fn raw_value(tag: i64, int32: i32) -> q::JSValue {
+    q::JSValue {
+        u: q::JSValueUnion { int32 },
+        tag,
+    }
+}
+
+fn cstring(value: &str) -> Result<CString, ValueError> {
+    CString::new(value).map_err(ValueError::StringWithZeroBytes)
+}
+
+// Called by QuickJS every so often while JS runs. A non-zero result throws an uncatchable
+// "interrupted" error.
+unsafe extern "C" fn interrupt(_runtime: *mut q::JSRuntime, opaque: *mut c_void) -> c_int {
+    let deadline = &*(opaque as *const Cell<Option<Instant>>);
+    matches!(deadline.get(), Some(e) if Instant::now() > e) as c_int
+}
+
+unsafe extern "C" fn trampoline(
+    _context: *mut q::JSContext,
+    _this: q::JSValue,
+    argc: c_int,
+    argv: *mut q::JSValue,
+    _magic: c_int,
+    data: *mut q::JSValue,
+) -> q::JSValue {
+    let closure = &*((*data).u.ptr as *const Box<WrappedCallback>);
+    closure(argc, argv)
+}
+
+unsafe fn throw(context: *mut q::JSContext, message: String) -> q::JSValue {
+    match serialize(context, JsValue::String(message)) {
+        Ok(e) => q::JS_Throw(context, e),
+        Err(_) => q::JS_ThrowOutOfMemory(context),
+    }
+}
+
+// The module name returned here is what `load_module` gets, and what the module is cached by.
+unsafe extern "C" fn normalize_module(
+    context: *mut q::JSContext,
+    base: *const c_char,
+    name: *const c_char,
+    opaque: *mut c_void,
+) -> *mut c_char {
+    let modules = &*(opaque as *const Modules);
+    let base = CStr::from_ptr(base).to_string_lossy();
+    let name = CStr::from_ptr(name).to_string_lossy();
+    let normalized =
+        std::panic::catch_unwind(AssertUnwindSafe(|| (modules.normalize)(&base, &name)))
+            .unwrap_or_else(|_| Err("module loader panicked".to_string()))
+            .and_then(|e| cstring(&e).map_err(|e| e.to_string()));
+    match normalized {
+        Ok(e) => q::js_strdup(context, e.as_ptr()),
+        Err(e) => {
+            throw(context, e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+unsafe extern "C" fn load_module(
+    context: *mut q::JSContext,
+    name: *const c_char,
+    opaque: *mut c_void,
+) -> *mut q::JSModuleDef {
+    let modules = &*(opaque as *const Modules);
+    let name = CStr::from_ptr(name);
+    let source =
+        std::panic::catch_unwind(AssertUnwindSafe(|| (modules.load)(&name.to_string_lossy())))
+            .unwrap_or_else(|_| Err("module loader panicked".to_string()))
+            .and_then(|e| cstring(&e).map_err(|e| e.to_string()));
+    let source = match source {
+        Ok(e) => e,
+        Err(e) => {
+            throw(context, e);
+            return std::ptr::null_mut();
+        }
+    };
+    let module = q::JS_Eval(
+        context,
+        source.as_ptr(),
+        source.as_bytes().len() as _,
+        name.as_ptr(),
+        (q::JS_EVAL_TYPE_MODULE | q::JS_EVAL_FLAG_COMPILE_ONLY) as i32,
+    );
+    if module.tag == TAG_EXCEPTION {
+        return std::ptr::null_mut();
+    }
+    // The context keeps the compiled module, the value only points to it
+    let definition = module.u.ptr as *mut q::JSModuleDef;
+    q::JS_FreeValue(context, module);
+    definition
+}
+
+// Whether `code` uses import or export statements, going by QuickJS' own parser.
+pub fn is_module(code: &str) -> bool {
+    match CString::new(code) {
+        Ok(e) => unsafe { q::JS_DetectModule(e.as_ptr(), e.as_bytes().len() as _) != 0 },
+        Err(_) => false,
+    }
+}
+
+unsafe fn serialize(context: *mut q::JSContext, value: JsValue) -> Result<q::JSValue, ValueError> {
+    let serialized = match value {
+        JsValue::Undefined => raw_value(TAG_UNDEFINED, 0),
+        JsValue::Null => raw_value(TAG_NULL, 0),
+        JsValue::Bool(e) => raw_value(TAG_BOOL, e as i32),
+        JsValue::Int(e) => raw_value(TAG_INT, e),
+        JsValue::Float(e) => q::JSValue {
+            u: q::JSValueUnion { float64: e },
+            tag: TAG_FLOAT64,
+        },
+        JsValue::String(e) => {
+            q::JS_NewStringLen(context, e.as_ptr() as *const c_char, e.len() as _)
+        }
+        JsValue::Array(values) => {
+            let array = q::JS_NewArray(context);
+            for (index, value) in values.into_iter().enumerate() {
+                let element = match serialize(context, value) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        q::JS_FreeValue(context, array);
+                        return Err(e);
+                    }
+                };
+                let flags = q::JS_PROP_C_W_E as i32;
+                if q::JS_DefinePropertyValueUint32(context, array, index as u32, element, flags) < 0
+                {
+                    q::JS_FreeValue(context, array);
+                    return Err(ValueError::Internal("cannot fill an array".to_string()));
+                }
+            }
+            array
+        }
+        JsValue::Object(properties) => {
+            let object = q::JS_NewObject(context);
+            for (key, value) in properties {
+                let defined = cstring(&key).and_then(|key| {
+                    let value = serialize(context, value)?;
+                    let flags = q::JS_PROP_C_W_E as i32;
+                    Ok(
+                        q::JS_DefinePropertyValueStr(context, object, key.as_ptr(), value, flags)
+                            >= 0,
+                    )
+                });
+                match defined {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        q::JS_FreeValue(context, object);
+                        return Err(ValueError::Internal("cannot fill an object".to_string()));
+                    }
+                    Err(e) => {
+                        q::JS_FreeValue(context, object);
+                        return Err(e);
+                    }
+                }
+            }
+            object
+        }
+        _ => return Err(ValueError::UnexpectedType),
+    };
+    if serialized.tag == TAG_EXCEPTION {
+        return Err(ValueError::Internal("out of memory".to_string()));
+    }
+    Ok(serialized)
+}
+
+unsafe fn deserialize(
+    context: *mut q::JSContext,
+    value: q::JSValue,
+) -> Result<JsValue, ValueError> {
+    match value.tag {
+        TAG_INT => Ok(JsValue::Int(value.u.int32)),
+        TAG_BOOL => Ok(JsValue::Bool(value.u.int32 != 0)),
+        TAG_NULL => Ok(JsValue::Null),
+        TAG_UNDEFINED => Ok(JsValue::Undefined),
+        TAG_FLOAT64 => Ok(JsValue::Float(value.u.float64)),
+        TAG_STRING => {
+            let pointer = q::JS_ToCStringLen2(context, std::ptr::null_mut(), value, 0);
+            if pointer.is_null() {
+                return Err(ValueError::Internal("cannot read a string".to_string()));
+            }
+            let string = CStr::from_ptr(pointer).to_str().map(str::to_string);
+            q::JS_FreeCString(context, pointer);
+            string
+                .map(JsValue::String)
+                .map_err(ValueError::InvalidString)
+        }
+        TAG_OBJECT if q::JS_IsArray(context, value) > 0 => {
+            let length = cstring("length")?;
+            let raw_length = q::JS_GetPropertyStr(context, value, length.as_ptr());
+            let length = deserialize(context, raw_length);
+            q::JS_FreeValue(context, raw_length);
+            let length = match length {
+                Ok(JsValue::Int(e)) => e,
+                _ => {
+                    return Err(ValueError::Internal(
+                        "cannot read an array's length".to_string(),
+                    ))
+                }
+            };
+            let mut values = Vec::new();
+            for index in 0..length.max(0) as u32 {
+                let element = q::JS_GetPropertyUint32(context, value, index);
+                let result = deserialize(context, element);
+                q::JS_FreeValue(context, element);
+                values.push(result?);
+            }
+            Ok(JsValue::Array(values))
+        }
+        TAG_OBJECT => {
+            let mut properties: *mut q::JSPropertyEnum = std::ptr::null_mut();
+            let mut count: u32 = 0;
+            let flags = (q::JS_GPN_STRING_MASK | q::JS_GPN_ENUM_ONLY) as i32;
+            if q::JS_GetOwnPropertyNames(context, &mut properties, &mut count, value, flags) != 0 {
+                return Err(ValueError::Internal(
+                    "cannot list an object's properties".to_string(),
+                ));
+            }
+            let mut map = HashMap::new();
+            let mut result = Ok(());
+            for index in 0..count as usize {
+                let atom = (*properties.add(index)).atom;
+                if result.is_ok() {
+                    let raw_key = q::JS_AtomToString(context, atom);
+                    let key = deserialize(context, raw_key);
+                    q::JS_FreeValue(context, raw_key);
+                    let property = q::JS_GetPropertyInternal(context, value, atom, value, 0);
+                    let property_value = deserialize(context, property);
+                    q::JS_FreeValue(context, property);
+                    result = match (key, property_value) {
+                        (Ok(JsValue::String(key)), Ok(property_value)) => {
+                            map.insert(key, property_value);
+                            Ok(())
+                        }
+                        (Err(e), _) | (_, Err(e)) => Err(e),
+                        _ => Err(ValueError::UnexpectedType),
+                    };
+                }
+                q::JS_FreeAtom(context, atom);
+            }
+            q::js_free(context, properties as *mut c_void);
+            result.map(|_| JsValue::Object(map))
+        }
+        TAG_EXCEPTION => Err(ValueError::Internal("exception".to_string())),
+        _ => Err(ValueError::UnexpectedType),
+    }
+}
+
+pub struct Context {
+    runtime: *mut q::JSRuntime,
+    context: *mut q::JSContext,
+    // QuickJS only holds pointers to the callbacks' closures. The outer box keeps each one at a
+    // fixed, thin address.
+    #[allow(clippy::vec_box)]
+    callbacks: Vec<Box<Box<WrappedCallback>>>,
+    // Running JS is interrupted once this instant has passed
+    deadline: Box<Cell<Option<Instant>>>,
+    // Calls made while no deadline is set get one this far away
+    timeout: Option<Duration>,
+    // QuickJS only holds a pointer to the module loader as well
+    modules: Option<Box<Modules>>,
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe {
+            q::JS_FreeContext(self.context);
+            q::JS_FreeRuntime(self.runtime);
+        }
+    }
+}
+
+impl Context {
+    pub fn new(memory_limit: Option<usize>) -> Result<Context, String> {
+        let runtime = unsafe { q::JS_NewRuntime() };
+        if runtime.is_null() {
+            return Err("cannot create a QuickJS runtime".to_string());
+        }
+        if let Some(limit) = memory_limit {
+            unsafe { q::JS_SetMemoryLimit(runtime, limit as _) };
+        }
+        let context = unsafe { q::JS_NewContext(runtime) };
+        if context.is_null() {
+            unsafe { q::JS_FreeRuntime(runtime) };
+            return Err("cannot create a QuickJS context".to_string());
+        }
+        let deadline = Box::new(Cell::new(None));
+        unsafe {
+            q::JS_SetInterruptHandler(
+                runtime,
+                Some(interrupt),
+                &*deadline as *const Cell<Option<Instant>> as *mut c_void,
+            );
+        }
+        Ok(Context {
+            runtime,
+            context,
+            callbacks: Vec::new(),
+            deadline,
+            timeout: None,
+            modules: None,
+        })
+    }
+
+    // JS running past `deadline` throws, until the deadline is cleared with None.
+    pub fn set_deadline(&self, deadline: Option<Instant>) {
+        self.deadline.set(deadline);
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    // Takes the pending exception, after a call returned TAG_EXCEPTION.
+    fn exception(&self) -> ExecutionError {
+        let raw = unsafe { q::JS_GetException(self.context) };
+        let message = unsafe {
+            let string = q::JS_ToString(self.context, raw);
+            let message = deserialize(self.context, string);
+            q::JS_FreeValue(self.context, string);
+            message
+        };
+        unsafe { q::JS_FreeValue(self.context, raw) };
+        match message {
+            Ok(JsValue::String(e)) if e.contains("out of memory") => ExecutionError::OutOfMemory,
+            Ok(e) => ExecutionError::Exception(e),
+            Err(_) => ExecutionError::Internal("unknown exception".to_string()),
+        }
+    }
+
+    // Converts a value returned by QuickJS and frees it, then runs the promise jobs the JS queued.
+    fn take(&self, value: q::JSValue) -> Result<JsValue, ExecutionError> {
+        if value.tag == TAG_EXCEPTION {
+            return Err(self.exception());
+        }
+        let result = unsafe { deserialize(self.context, value) };
+        unsafe { q::JS_FreeValue(self.context, value) };
+        self.run_jobs()?;
+        Ok(result?)
+    }
+
+    // Runs promise reactions and async function continuations until none are left.
+    fn run_jobs(&self) -> Result<(), ExecutionError> {
+        let mut context = std::ptr::null_mut();
+        loop {
+            match unsafe { q::JS_ExecutePendingJob(self.runtime, &mut context) } {
+                0 => return Ok(()),
+                e if e < 0 => return Err(self.exception()),
+                _ => {}
+            }
+        }
+    }
+
+    fn global_property(&self, name: &str) -> Result<q::JSValue, ExecutionError> {
+        let name = cstring(name)?;
+        unsafe {
+            let global = q::JS_GetGlobalObject(self.context);
+            let property = q::JS_GetPropertyStr(self.context, global, name.as_ptr());
+            q::JS_FreeValue(self.context, global);
+            Ok(property)
+        }
+    }
+
+    // Takes ownership of `value`.
+    fn set_global_raw(&self, name: &str, value: q::JSValue) -> Result<(), ExecutionError> {
+        let name = match cstring(name) {
+            Ok(e) => e,
+            Err(e) => {
+                unsafe { q::JS_FreeValue(self.context, value) };
+                return Err(e.into());
+            }
+        };
+        let result = unsafe {
+            let global = q::JS_GetGlobalObject(self.context);
+            let result = q::JS_SetPropertyStr(self.context, global, name.as_ptr(), value);
+            q::JS_FreeValue(self.context, global);
+            result
+        };
+        if result < 0 {
+            return Err(self.exception());
+        }
+        Ok(())
+    }
+
+    pub fn set_global<V: Into<JsValue>>(&self, name: &str, value: V) -> Result<(), ExecutionError> {
+        let value = unsafe { serialize(self.context, value.into())? };
+        self.set_global_raw(name, value)
+    }
+
+    pub fn eval(&self, code: &str) -> Result<JsValue, ExecutionError> {
+        let code = CString::new(code).map_err(|_| ExecutionError::InputWithZeroBytes)?;
+        let file_name = cstring("script.js")?;
+        let value = unsafe {
+            q::JS_Eval(
+                self.context,
+                code.as_ptr(),
+                code.as_bytes().len() as _,
+                file_name.as_ptr(),
+                q::JS_EVAL_TYPE_GLOBAL as i32,
+            )
+        };
+        self.take(value)
+    }
+
+    // Evaluates `code` as the ES module `name`, loading the modules it imports through the module
+    // loader.
+    pub fn eval_module(&self, name: &str, code: &str) -> Result<JsValue, ExecutionError> {
+        let code = CString::new(code).map_err(|_| ExecutionError::InputWithZeroBytes)?;
+        let name = cstring(name)?;
+        let value = unsafe {
+            q::JS_Eval(
+                self.context,
+                code.as_ptr(),
+                code.as_bytes().len() as _,
+                name.as_ptr(),
+                q::JS_EVAL_TYPE_MODULE as i32,
+            )
+        };
+        self.take(value)
+    }
+
+    // Lets modules import others: `normalize` resolves an import against the importing module's
+    // name, and `load` returns the source of the module it resolved to. Each module is loaded once.
+    pub fn set_module_loader(
+        &mut self,
+        normalize: impl Fn(&str, &str) -> Result<String, String> + 'static,
+        load: impl Fn(&str) -> Result<String, String> + 'static,
+    ) {
+        let modules = Box::new(Modules {
+            normalize: Box::new(normalize),
+            load: Box::new(load),
+        });
+        unsafe {
+            q::JS_SetModuleLoaderFunc(
+                self.runtime,
+                Some(normalize_module),
+                Some(load_module),
+                &*modules as *const Modules as *mut c_void,
+            );
+        }
+        self.modules = Some(modules);
+    }
+
+    // Calls a global function, within the deadline set, or the timeout if there's none.
+    pub fn call_function(&self, name: &str, args: Vec<JsValue>) -> Result<JsValue, ExecutionError> {
+        let own_deadline = self.deadline.get().is_none() && self.timeout.is_some();
+        if own_deadline {
+            self.set_deadline(self.timeout.map(|e| Instant::now() + e));
+        }
+        let result = self.call_global(name, args);
+        if own_deadline {
+            self.set_deadline(None);
+        }
+        result
+    }
+
+    fn call_global(&self, name: &str, args: Vec<JsValue>) -> Result<JsValue, ExecutionError> {
+        let function = self.global_property(name)?;
+        if function.tag != TAG_OBJECT {
+            unsafe { q::JS_FreeValue(self.context, function) };
+            return Err(ExecutionError::Internal(format!(
+                "{} isn't a global function",
+                name
+            )));
+        }
+        let mut raw_args = Vec::new();
+        for arg in args {
+            match unsafe { serialize(self.context, arg) } {
+                Ok(e) => raw_args.push(e),
+                Err(e) => {
+                    for raw in raw_args.into_iter().chain([function]) {
+                        unsafe { q::JS_FreeValue(self.context, raw) };
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        let value = unsafe {
+            q::JS_Call(
+                self.context,
+                function,
+                raw_value(TAG_UNDEFINED, 0),
+                raw_args.len() as c_int,
+                raw_args.as_mut_ptr(),
+            )
+        };
+        for raw in raw_args.into_iter().chain([function]) {
+            unsafe { q::JS_FreeValue(self.context, raw) };
+        }
+        self.take(value)
+    }
+
+    // Adds a global function backed by a Rust closure. Errors it returns are thrown in JS.
+    pub fn add_callback<F>(
+        &mut self,
+        name: &str,
+        callback: impl Callback<F> + 'static,
+    ) -> Result<(), ExecutionError> {
+        let context = self.context;
+        let argument_count = callback.argument_count() as c_int;
+        let closure: Box<WrappedCallback> = Box::new(move |argc, argv| unsafe {
+            let result = std::panic::catch_unwind(|| {
+                let raw_args = std::slice::from_raw_parts(argv, argc as usize);
+                let args = raw_args
+                    .iter()
+                    .map(|e| deserialize(context, *e))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| e.to_string())?;
+                match callback.call(args) {
+                    Ok(Ok(e)) => serialize(context, e).map_err(|e| e.to_string()),
+                    Ok(Err(e)) => Err(e),
+                    Err(e) => Err(e.to_string()),
+                }
+            });
+            let error = match result {
+                Ok(Ok(e)) => return e,
+                Ok(Err(e)) => e,
+                Err(_) => "callback panicked".to_string(),
+            };
+            throw(context, error)
+        });
+        let closure = Box::new(closure);
+        let mut data = q::JSValue {
+            u: q::JSValueUnion {
+                ptr: &*closure as *const Box<WrappedCallback> as *mut c_void,
+            },
+            tag: TAG_NULL,
+        };
+        self.callbacks.push(closure);
+        let function = unsafe {
+            q::JS_NewCFunctionData(
+                self.context,
+                Some(trampoline),
+                argument_count,
+                0,
+                1,
+                &mut data,
+            )
+        };
+        if function.tag != TAG_OBJECT {
+            return Err(ExecutionError::Internal(format!(
+                "cannot create the {} callback",
+                name
+            )));
+        }
+        self.set_global_raw(name, function)
+    }
+}
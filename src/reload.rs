@@ -0,0 +1,194 @@
+use serde_json::json;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::Mutex;
+use tokio::task::{spawn_local, JoinHandle};
+use tokio::time::{interval, Duration};
+use tracing::{error, info, warn};
+
+use crate::admin::{execute, AdminCommand, AdminRequest};
+use crate::calls::{run_calls, CallRequest, Completions};
+use crate::jsbridge::{Address, JSBridge};
+use crate::server::{run_server, run_tcp_server};
+use crate::timers::{run_timers, TimerRequest};
+
+// Keeps track of the DNS servers (UDP and TCP) started for the bound addresses, so that a reload
+// only touches the ones that actually changed.
+pub struct ServerSet {
+    bridge: Rc<Mutex<JSBridge>>,
+    running: HashMap<String, JoinHandle<()>>,
+    // The timer loop of the current configuration
+    timers: Option<JoinHandle<()>>,
+    // Runs its fetch() and resolve() calls
+    calls: Option<JoinHandle<()>>,
+}
+
+impl ServerSet {
+    pub fn new(bridge: Rc<Mutex<JSBridge>>) -> ServerSet {
+        ServerSet {
+            bridge,
+            running: HashMap::new(),
+            timers: None,
+            calls: None,
+        }
+    }
+
+    // Runs the timers of a newly loaded configuration, stopping the previous one's.
+    pub fn start_timers(&mut self, requests: UnboundedReceiver<TimerRequest>, generation: u64) {
+        if let Some(handle) = self.timers.take() {
+            handle.abort();
+        }
+        let handle = spawn_local(run_timers(self.bridge.clone(), requests, generation));
+        self.timers = Some(handle);
+    }
+
+    // Runs the fetch() and resolve() calls of a newly loaded configuration, stopping the previous
+    // one's.
+    pub fn start_calls(
+        &mut self,
+        requests: UnboundedReceiver<CallRequest>,
+        completions: Arc<Completions>,
+        generation: u64,
+    ) {
+        if let Some(handle) = self.calls.take() {
+            handle.abort();
+        }
+        let handle = spawn_local(run_calls(
+            self.bridge.clone(),
+            requests,
+            completions,
+            generation,
+        ));
+        self.calls = Some(handle);
+    }
+
+    pub fn apply(&mut self, addresses: &[Address]) {
+        let wanted: HashMap<String, &Address> = addresses
+            .iter()
+            .map(|address| (address.to_canonical(), address))
+            .collect();
+
+        self.running.retain(|canonical, handle| {
+            if wanted.contains_key(canonical) {
+                return true;
+            }
+            info!(target: "reload", address = %canonical, "Unbinding");
+            handle.abort();
+            false
+        });
+
+        for (canonical, address) in wanted {
+            if self.running.contains_key(&canonical) {
+                continue;
+            }
+            let bridge_reference = self.bridge.clone();
+            let cloned_address = address.clone();
+            let handle = spawn_local(async move {
+                tokio::join!(
+                    run_server(cloned_address.clone(), bridge_reference.clone()),
+                    run_tcp_server(cloned_address, bridge_reference)
+                );
+            });
+            self.running.insert(canonical, handle);
+        }
+    }
+}
+
+fn http_fingerprint(
+    settings: &(Address, HashMap<String, String>),
+) -> (String, Vec<(String, String)>) {
+    let mut bindings: Vec<_> = settings.1.clone().into_iter().collect();
+    bindings.sort();
+    (settings.0.to_canonical(), bindings)
+}
+
+async fn reload<L: Fn(&str) -> Result<JSBridge, String>>(
+    config_file: &str,
+    bridge: &Rc<Mutex<JSBridge>>,
+    servers: &mut ServerSet,
+    loader: &L,
+) -> Result<(), String> {
+    info!(target: "reload", "Reloading configuration from {}", config_file);
+    let mut fresh = match loader(config_file) {
+        Ok(e) => e,
+        Err(e) => {
+            error!(
+                target: "reload",
+                "New configuration failed to load, keeping the old one! ({})", e
+            );
+            return Err(e);
+        }
+    };
+    let addresses = fresh.bound_addresses.lock().unwrap().clone();
+    let timer_requests = fresh.timer_requests.take().unwrap();
+    let call_requests = fresh.call_requests.take().unwrap();
+    let completions = fresh.completions.clone();
+    let generation = fresh.generation;
+
+    {
+        let mut current = bridge.lock().await;
+        if http_fingerprint(&current.http_settings()) != http_fingerprint(&fresh.http_settings()) {
+            warn!(
+                target: "reload",
+                "HTTP redirection settings changed - restart baDNS to apply them!"
+            );
+        }
+        *current = fresh;
+    }
+
+    servers.start_timers(timer_requests, generation);
+    servers.start_calls(call_requests, completions, generation);
+    servers.apply(&addresses);
+    info!(target: "reload", "Configuration reloaded");
+    Ok(())
+}
+
+fn modification_time(file_name: &str) -> Option<SystemTime> {
+    std::fs::metadata(file_name)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// Reloads the configuration on every SIGHUP, and - if `watch_file` is set - whenever
+// the config file's modification time changes. Also runs the admin API's commands.
+pub async fn watch_for_reloads<L: Fn(&str) -> Result<JSBridge, String>>(
+    config_file: String,
+    bridge: Rc<Mutex<JSBridge>>,
+    mut servers: ServerSet,
+    loader: L,
+    watch_file: bool,
+    mut admin_requests: UnboundedReceiver<AdminRequest>,
+) {
+    let mut hangup = signal(SignalKind::hangup()).expect("Cannot listen for SIGHUP");
+    let mut poll = interval(Duration::from_secs(2));
+    let mut last_modified = modification_time(&config_file);
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => info!(target: "reload", "Received SIGHUP"),
+            _ = poll.tick(), if watch_file => {
+                let modified = modification_time(&config_file);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                info!(target: "reload", "{} changed", config_file);
+            }
+            Some(request) = admin_requests.recv() => {
+                let result = match request.command {
+                    AdminCommand::Reload => reload(&config_file, &bridge, &mut servers, &loader)
+                        .await
+                        .map(|_| json!({ "reloaded": true })),
+                    command => execute(command, &bridge).await,
+                };
+                let _ = request.reply.send(result);
+                continue;
+            }
+        }
+        let _ = reload(&config_file, &bridge, &mut servers, &loader).await;
+    }
+}
@@ -0,0 +1,55 @@
+use serde_json::{Map, Value};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+pub fn env(name: String) -> String {
+    Value::from(std::env::var(name).ok()).to_string()
+}
+
+// Parses `KEY=VALUE` lines, skipping empty ones and `#` comments.
+fn parse_lines(contents: &str) -> Result<Map<String, Value>, String> {
+    let mut secrets = Map::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some(e) => e,
+            None => return Err(format!("line {} is not in the KEY=VALUE format", index + 1)),
+        };
+        let value = value.trim();
+        let unquoted = value
+            .strip_prefix('"')
+            .and_then(|e| e.strip_suffix('"'))
+            .unwrap_or(value);
+        secrets.insert(key.trim().to_string(), Value::from(unquoted));
+    }
+    Ok(secrets)
+}
+
+// Reads a secrets file (a JSON object or KEY=VALUE lines) and returns it as a JSON object.
+// Files anyone on the system can read are refused.
+pub fn read_secrets(file_name: String) -> Result<String, String> {
+    let metadata = fs::metadata(&file_name)
+        .map_err(|e| format!("Cannot access secrets file {} ({})", file_name, e))?;
+    let mode = metadata.permissions().mode();
+    if mode & 0o004 != 0 {
+        return Err(format!(
+            "Secrets file {} is world-readable (mode {:o}) - run `chmod 600 {}` first!",
+            file_name,
+            mode & 0o777,
+            file_name
+        ));
+    }
+    let contents = fs::read_to_string(&file_name)
+        .map_err(|e| format!("Cannot read secrets file {} ({})", file_name, e))?;
+
+    let secrets = match serde_json::from_str(&contents) {
+        Ok(Value::Object(e)) => e,
+        Ok(_) => return Err(format!("Secrets file {} is not a JSON object!", file_name)),
+        Err(_) => parse_lines(&contents)
+            .map_err(|e| format!("Malformed secrets file {} ({})", file_name, e))?,
+    };
+    Ok(Value::Object(secrets).to_string())
+}
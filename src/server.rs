@@ -1,106 +1,387 @@
+use lazy_static::lazy_static;
 use rustdns::Opcode;
 use rustdns::Question;
+use rustdns::Rcode;
 use rustdns::Record;
 use rustdns::QR;
+use serde_json::{json, Value};
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
 use std::rc::Rc;
-use std::time::SystemTime;
-use tokio::net::UdpSocket;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::sync::Mutex;
 use tokio::sync::OnceCell;
+use tokio::task::spawn_local;
 use tokio::time::{timeout, Duration};
+use tracing::{debug, error, warn};
 
+use crate::dnssec;
+use crate::dnstap::{self, DnstapMessage, MessageType, Transport};
 use crate::jsbridge::Address;
 use crate::jsbridge::JSBridge;
+use crate::jsbridge::QueryContext;
+use crate::metrics;
+use crate::querylog::{QueryLogEntry, QUERY_LOG};
+use crate::stats::STATS;
+use crate::transfer::{answer_transfer, is_transfer};
+use crate::tsig::{
+    reject, sign, sign_request, verify, verify_response, SignedRequest, Verification,
+};
 use crate::ttldict::TTLDict;
+use crate::update::{handle_update, OPCODE_UPDATE};
+use crate::validator::{Security, Validator};
+use crate::wire::{
+    build_message, client_subnet, decode_record, parse_records, read_question, read_u16,
+    response_flags, tcp_length, truncate, udp_payload_size, Section, FLAG_TC, OPCODE_QUERY,
+    OPCODE_SHIFT,
+};
+use crate::zone::record_key;
 
 use rustdns::Message;
 
-static OUTBOUND: OnceCell<UdpSocket> = OnceCell::const_new();
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_NOTAUTH: u16 = 9;
 
 struct CacheEntry {
     entry: Vec<Record>,
     authoritative: bool,
+    // Validated with DNSSEC
+    secure: bool,
     init_time: SystemTime,
 }
 
 static CACHE: OnceCell<Mutex<TTLDict<u64, CacheEntry>>> = OnceCell::const_new();
 
-pub async fn query_upstream(question: &Question, bridge: &JSBridge) -> Vec<Record> {
-    let outbound = OUTBOUND
-        .get_or_init(|| async { UdpSocket::bind("0.0.0.0:0").await.unwrap() })
-        .await;
-    let mut message = Message::default();
-    message.questions.push(question.clone());
-    let serialized = match message.to_vec() {
+pub struct UpstreamAnswer {
+    pub records: Vec<Record>,
+    // The upstream that answered, as `address:port`.
+    pub upstream: Option<String>,
+    // Insecure unless the answer was validated. Bogus when the upstreams only had answers that
+    // failed validation, which are then the records.
+    pub security: Security,
+}
+
+impl UpstreamAnswer {
+    fn empty() -> UpstreamAnswer {
+        UpstreamAnswer {
+            records: Vec::default(),
+            upstream: None,
+            security: Security::Insecure,
+        }
+    }
+}
+
+#[derive(Default)]
+struct UpstreamHealth {
+    successes: u64,
+    failures: u64,
+    consecutive_failures: u64,
+    last_success: Option<SystemTime>,
+    last_failure: Option<(SystemTime, &'static str)>,
+}
+
+lazy_static! {
+    static ref UPSTREAM_HEALTH: std::sync::Mutex<HashMap<String, UpstreamHealth>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+fn upstream_failed(upstream: &str, reason: &'static str) {
+    metrics::count(
+        "badns_upstream_failures_total",
+        &[("upstream", upstream), ("reason", reason)],
+    );
+    let mut health = UPSTREAM_HEALTH.lock().unwrap();
+    let entry = health.entry(upstream.to_string()).or_default();
+    entry.failures += 1;
+    entry.consecutive_failures += 1;
+    entry.last_failure = Some((SystemTime::now(), reason));
+}
+
+fn upstream_succeeded(upstream: &str) {
+    let mut health = UPSTREAM_HEALTH.lock().unwrap();
+    let entry = health.entry(upstream.to_string()).or_default();
+    entry.successes += 1;
+    entry.consecutive_failures = 0;
+    entry.last_success = Some(SystemTime::now());
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Describes how the given upstreams fared so far. An upstream is healthy until it fails
+// three times in a row.
+pub fn upstream_health(upstreams: &[Address]) -> Value {
+    let health = UPSTREAM_HEALTH.lock().unwrap();
+    let described: Vec<Value> = upstreams
+        .iter()
+        .map(|upstream| {
+            let canonical = upstream.to_canonical();
+            let default = UpstreamHealth::default();
+            let entry = health.get(&canonical).unwrap_or(&default);
+            json!({
+                "address": canonical,
+                "healthy": entry.consecutive_failures < 3,
+                "successes": entry.successes,
+                "failures": entry.failures,
+                "consecutiveFailures": entry.consecutive_failures,
+                "lastSuccess": entry.last_success.map(unix_millis),
+                "lastFailure": entry.last_failure.map(|(time, _)| unix_millis(time)),
+                "lastFailureReason": entry.last_failure.map(|(_, reason)| reason),
+            })
+        })
+        .collect();
+    Value::Array(described)
+}
+
+// Sends a query to a single upstream and returns its response, with the TSIG checked and
+// removed. Truncated UDP responses are retried over TCP. Failures count against the upstream's
+// health.
+async fn exchange(upstream: &Address, query: &[u8]) -> Option<Vec<u8>> {
+    let canonical = upstream.to_canonical();
+    // Queries are answered concurrently, so each exchange gets a socket of its own
+    let outbound = match UdpSocket::bind("0.0.0.0:0").await {
         Ok(e) => e,
-        Err(_) => {
-            println!("Failed to serialize message, this should never happen!");
-            return Vec::default();
+        Err(err) => {
+            warn!(target: "upstream", upstream = %canonical, "Failed to open a socket ({})", err);
+            upstream_failed(&canonical, "send");
+            return None;
         }
     };
-
-    let timeout_duration = Duration::from_secs(5);
-    let upstreams_clone = bridge.upstreams.lock().unwrap().clone();
-    for upstream in upstreams_clone {
-        let canonical = upstream.to_canonical();
-        println!(
-            "[Upstream] Querying upstream {} for domain {}",
-            canonical, question.name
-        );
-        outbound.connect(upstream.to_canonical()).await.unwrap();
-        match outbound.send(&serialized).await {
-            Ok(_) => 0,
-            Err(err) => {
-                println!(
-                    "[Upstream]: Failed to send data to upstream {} ({})",
-                    canonical, err
-                );
-                continue;
+    let (query, mut request) = match &upstream.tsig_key {
+        Some(key) => {
+            let (signed, request) = sign_request(query.to_vec(), key);
+            (signed, Some(request))
+        }
+        None => (query.to_vec(), None),
+    };
+    outbound.connect(upstream.to_canonical()).await.unwrap();
+    let sent = SystemTime::now();
+    let upstream_address = outbound.peer_addr().ok();
+    let local_address = outbound.local_addr().ok();
+    dnstap::emit(&DnstapMessage {
+        kind: MessageType::ForwarderQuery,
+        transport: Transport::Udp,
+        query_address: local_address,
+        response_address: upstream_address,
+        query_time: Some(sent),
+        response_time: None,
+        query_message: Some(&query),
+        response_message: None,
+    });
+    if let Err(err) = outbound.send(&query).await {
+        warn!(target: "upstream", upstream = %canonical, "Failed to send data to upstream ({})", err);
+        upstream_failed(&canonical, "send");
+        return None;
+    }
+    let mut buffer = vec![0; 4096];
+    let len = loop {
+        let outbound_result = match timeout(UPSTREAM_TIMEOUT, outbound.recv(&mut buffer)).await {
+            Ok(res) => res,
+            Err(_) => {
+                warn!(target: "upstream", upstream = %canonical, "Timed out while waiting for upstream's response!");
+                upstream_failed(&canonical, "timeout");
+                return None;
             }
         };
-        let mut buffer = vec![0; 4096];
-        let outbound_future = outbound.recv(&mut buffer);
-        let outbound_result = match timeout(timeout_duration, outbound_future).await {
-            Ok(res) => res,
+        match outbound_result {
+            // Datagrams that don't answer this query are ignored
+            Ok(len) if buffer[..len].get(..2) != query.get(..2) => continue,
+            Ok(len) => break len,
+            Err(err) => {
+                warn!(target: "upstream", upstream = %canonical, "Failed to receive data from upstream ({})", err);
+                upstream_failed(&canonical, "receive");
+                return None;
+            }
+        }
+    };
+    buffer.truncate(len);
+    let mut transport = Transport::Udp;
+    if read_u16(&buffer, 2).unwrap_or(0) & FLAG_TC != 0 {
+        debug!(target: "upstream", upstream = %canonical, "Response was truncated, retrying over TCP");
+        transport = Transport::Tcp;
+        buffer = match timeout(UPSTREAM_TIMEOUT, exchange_tcp(&canonical, &query)).await {
+            Ok(Ok(e)) => e,
+            Ok(Err(err)) => {
+                warn!(target: "upstream", upstream = %canonical, "TCP exchange with upstream failed ({})", err);
+                upstream_failed(&canonical, "receive");
+                return None;
+            }
             Err(_) => {
-                println!(
-                    "[Upstream]: Timed out while waiting for upstream {}'s response!",
-                    canonical
-                );
-                continue;
+                warn!(target: "upstream", upstream = %canonical, "Timed out while waiting for upstream's response!");
+                upstream_failed(&canonical, "timeout");
+                return None;
             }
         };
-        let len = match outbound_result {
-            Ok(e) => e,
+    }
+    metrics::observe(
+        "badns_upstream_duration_seconds",
+        &[("upstream", &canonical)],
+        sent.elapsed().unwrap_or_default(),
+    );
+    dnstap::emit(&DnstapMessage {
+        kind: MessageType::ForwarderResponse,
+        transport,
+        query_address: local_address,
+        response_address: upstream_address,
+        query_time: Some(sent),
+        response_time: Some(SystemTime::now()),
+        query_message: Some(&query),
+        response_message: Some(&buffer),
+    });
+    match request.as_mut() {
+        Some(request) => match verify_response(&buffer, request) {
+            Ok(e) => Some(e),
             Err(err) => {
-                println!(
-                    "[Upstream]: Failed to receive data from upstream {} ({})",
-                    canonical, err
-                );
-                continue;
+                warn!(target: "upstream", upstream = %canonical, "Rejected upstream's response ({})", err);
+                upstream_failed(&canonical, "tsig");
+                None
+            }
+        },
+        None => Some(buffer),
+    }
+}
+
+async fn exchange_tcp(upstream: &str, query: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(upstream).await?;
+    write_tcp_message(&mut stream, query).await?;
+    let length = stream.read_u16().await?;
+    let mut buffer = vec![0; length as usize];
+    stream.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+// Sends a ready-made query to the upstreams in turn, and returns the first response.
+pub async fn fetch_raw(query: &[u8], upstreams: &[Address]) -> Option<Vec<u8>> {
+    for upstream in upstreams {
+        if let Some(response) = exchange(upstream, query).await {
+            upstream_succeeded(&upstream.to_canonical());
+            return Some(response);
+        }
+    }
+    None
+}
+
+// The answer records of a response, with the ones rustdns can't represent (RRSIGs, ...)
+// left out.
+fn decode_answers(response: &[u8]) -> Option<Vec<Record>> {
+    let records = parse_records(response)?;
+    Some(
+        records
+            .iter()
+            .filter(|e| e.section == Section::Answer)
+            .filter_map(|e| decode_record(response, e))
+            .collect(),
+    )
+}
+
+// Asks the upstreams in turn until one has answers. With a validator, answers are checked with
+// DNSSEC first, and bogus ones make the next upstream get asked.
+pub async fn query_upstream(
+    question: &Question,
+    upstreams: &[Address],
+    validator: Option<&Validator>,
+) -> UpstreamAnswer {
+    let mut message = Message::default();
+    message.questions.push(question.clone());
+    let serialized = match validator {
+        Some(_) => dnssec::query(&question.name, question.r#type as u16),
+        None => match message.to_vec() {
+            Ok(e) => e,
+            Err(_) => {
+                error!(target: "upstream", "Failed to serialize message, this should never happen!");
+                return UpstreamAnswer::empty();
             }
+        },
+    };
+
+    let mut bogus = None;
+    // Whether an upstream gave a negative answer that isn't bogus
+    let mut denied = false;
+    for upstream in upstreams {
+        let canonical = upstream.to_canonical();
+        debug!(target: "upstream", upstream = %canonical, name = %question.name, "Querying upstream");
+        let response = match exchange(upstream, &serialized).await {
+            Some(e) => e,
+            None => continue,
         };
-        let answer = match Message::from_slice(&buffer[0..len]) {
+        // rustdns can't read the DNSSEC records that come with signed answers
+        let records = match validator {
+            Some(_) => decode_answers(&response).ok_or_else(|| "unreadable records".to_string()),
+            None => Message::from_slice(&response)
+                .map(|answer| answer.answers)
+                .map_err(|err| err.to_string()),
+        };
+        let records = match records {
             Ok(e) => e,
             Err(err) => {
-                println!(
-                    "[Upstream]: Received malformed data from upstream {} ({})",
-                    canonical, err
-                );
+                warn!(target: "upstream", upstream = %canonical, "Received malformed data from upstream ({})", err);
+                upstream_failed(&canonical, "malformed");
                 continue;
             }
         };
-        if !answer.answers.is_empty() {
-            return answer.answers.clone();
+        upstream_succeeded(&canonical);
+        // Negative answers are validated too, as a forged denial is as bad as a forged answer
+        if records.is_empty() && validator.is_none() {
+            continue;
+        }
+        let security = match validator {
+            Some(validator) => validator.validate(&response, upstreams).await,
+            None => Security::Insecure,
+        };
+        let answer = UpstreamAnswer {
+            records,
+            upstream: Some(canonical.clone()),
+            security,
+        };
+        if validator.is_some() {
+            metrics::count(
+                "badns_dnssec_validations_total",
+                &[("result", security.name())],
+            );
+        }
+        if security == Security::Bogus {
+            warn!(target: "upstream", upstream = %canonical, name = %question.name, "Upstream's answer failed DNSSEC validation");
+            upstream_failed(&canonical, "bogus");
+            bogus = Some(answer);
+            continue;
+        }
+        if answer.records.is_empty() {
+            denied = true;
+            continue;
         }
+        return answer;
     }
-    if message.answers.is_empty() {
-        println!("[Upstream]: Upstream had no results for {}", question.name);
+    if let Some(answer) = bogus.filter(|_| !denied) {
+        return answer;
     }
-    Vec::default()
+    debug!(target: "upstream", name = %question.name, "Upstream had no results");
+    UpstreamAnswer::empty()
+}
+
+fn js_source(handler: &Option<String>) -> String {
+    match handler {
+        Some(handler) => format!("js:{}", handler),
+        None => "none".to_string(),
+    }
+}
+
+// Whether both hold the same records, in whatever order.
+fn same_records(a: &[Record], b: &[Record]) -> bool {
+    let sorted = |records: &[Record]| {
+        let mut sorted = records.to_vec();
+        sorted.sort_by_key(record_key);
+        sorted
+    };
+    sorted(a) == sorted(b)
 }
 
 fn hash_question(question: &Question) -> u64 {
@@ -111,109 +392,499 @@ fn hash_question(question: &Question) -> u64 {
     hasher.finish()
 }
 
-async fn handle_packet(
-    buffer: &[u8],
-    peer: &SocketAddr,
-    bridge: &Rc<Mutex<JSBridge>>,
-    socket: &UdpSocket,
-) {
+// Returns the cached answers, and whether they're authoritative and secure.
+async fn cache_lookup(question: &Question) -> Option<(Vec<Record>, bool, bool)> {
+    let mut cache = CACHE
+        .get_or_init(|| async { Mutex::new(TTLDict::new()) })
+        .await
+        .lock()
+        .await;
+    let hashed_question = hash_question(question);
+    let cached_entry = match cache.get(&hashed_question) {
+        Some(e) => e,
+        None => {
+            metrics::count("badns_cache_misses_total", &[]);
+            return None;
+        }
+    };
+    metrics::count("badns_cache_hits_total", &[]);
+    debug!(target: "cache", name = %question.name, hash = hashed_question, "Reading response from cache");
+    let mut answers = cached_entry.entry.clone();
+    let ttl_offset = SystemTime::now()
+        .duration_since(cached_entry.init_time)
+        .unwrap();
+    for ans in &mut answers {
+        ans.ttl -= ttl_offset;
+    }
+    Some((answers, cached_entry.authoritative, cached_entry.secure))
+}
+
+pub async fn cache_size() -> usize {
+    CACHE
+        .get_or_init(|| async { Mutex::new(TTLDict::new()) })
+        .await
+        .lock()
+        .await
+        .len()
+}
+
+// Drops every cached answer, returning how many there were.
+pub async fn flush_cache() -> usize {
+    CACHE
+        .get_or_init(|| async { Mutex::new(TTLDict::new()) })
+        .await
+        .lock()
+        .await
+        .clear()
+}
+
+async fn cache_store(question: &Question, answers: &[Record], authoritative: bool, secure: bool) {
+    if answers.is_empty() {
+        return;
+    }
     let mut cache = CACHE
         .get_or_init(|| async { Mutex::new(TTLDict::new()) })
         .await
         .lock()
         .await;
+    let hashed_question = hash_question(question);
+    let min_ttl: Duration = answers
+        .iter()
+        .map(|x| x.ttl)
+        .min()
+        .unwrap_or(Duration::from_secs(0));
+    debug!(
+        target: "cache",
+        name = %question.name,
+        hash = hashed_question,
+        min_ttl = min_ttl.as_secs(),
+        "Writing answer to cache"
+    );
+    cache.set(
+        hashed_question,
+        CacheEntry {
+            entry: answers.to_vec(),
+            authoritative,
+            secure,
+            init_time: SystemTime::now(),
+        },
+        min_ttl,
+    );
+}
+
+// Resolves a question through the cache and the upstream servers, bypassing the JS bindings.
+// Answers that fail DNSSEC validation are dropped. Upstream answers aren't cached, as the
+// onUpstreamResponse hooks can't run while JS is already running.
+pub async fn resolve(
+    question: &Question,
+    upstreams: &[Address],
+    validator: Option<&Validator>,
+) -> Vec<Record> {
+    if let Some((answers, _, _)) = cache_lookup(question).await {
+        return answers;
+    }
+    let upstream_answer = query_upstream(question, upstreams, validator).await;
+    if upstream_answer.security == Security::Bogus {
+        return Vec::new();
+    }
+    upstream_answer.records
+}
+
+pub struct Answer {
+    pub records: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+    pub authoritative: bool,
+    // Validated with DNSSEC, which earns the response the AD bit
+    pub secure: bool,
+    // Where the answer came from: `cache`, `js:<handler>`, `zone:<origin>`, `upstream:<address>`
+    // or `none`.
+    pub source: String,
+    // ServFail when the question couldn't be answered.
+    pub rcode: Rcode,
+}
+
+impl Answer {
+    fn new(records: Vec<Record>, authoritative: bool, source: String) -> Answer {
+        Answer {
+            records,
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            authoritative,
+            secure: false,
+            source,
+            rcode: Rcode::NoError,
+        }
+    }
+}
+
+// Answers a single question through the cache, the named JS bindings, the loaded zones, the
+// universal JS bindings and then the upstreams.
+pub async fn answer_question(
+    question: &Question,
+    bridge: &Rc<Mutex<JSBridge>>,
+    context: &QueryContext,
+) -> Answer {
+    if let Some((records, authoritative, secure)) = cache_lookup(question).await {
+        let mut answer = Answer::new(records, authoritative, "cache".to_string());
+        answer.secure = secure;
+        return answer;
+    }
+    let zones = bridge.lock().await.zones.clone();
+    let zone_kind = zones
+        .lock()
+        .unwrap()
+        .find(&question.name)
+        .map(|zone| zone.kind);
+    let js_answer = JSBridge::get_response(bridge, question, context, zone_kind).await;
+    let mut answer = Answer::new(
+        js_answer.records,
+        js_answer.authoritative,
+        js_source(&js_answer.handler),
+    );
+    if js_answer.failed {
+        answer.rcode = Rcode::ServFail;
+        return answer;
+    }
+
+    if zone_kind.is_some() {
+        let zones = zones.lock().unwrap();
+        if let Some(zone) = zones.find(&question.name) {
+            if !answer.records.is_empty() {
+                // Bindings inside a zone answer for it
+                answer.authoritative = true;
+                answer.authorities = zone.apex_ns();
+            } else {
+                // Zone answers aren't cached, so that they're always current. Names inside
+                // zones never reach the upstreams.
+                let zone_answer = zone.answer(question, js_answer.name_exists);
+                return Answer {
+                    records: zone_answer.records,
+                    authorities: zone_answer.authorities,
+                    additionals: zone_answer.additionals,
+                    authoritative: zone_answer.authoritative,
+                    secure: false,
+                    source: format!("zone:{}", zone.origin),
+                    rcode: zone_answer.rcode,
+                };
+            }
+        }
+    }
+
+    if answer.records.is_empty() {
+        let (upstreams, validator) = {
+            let instance = bridge.lock().await;
+            let upstreams = instance.upstreams.lock().unwrap().clone();
+            let validator = instance.validator.lock().unwrap().clone();
+            (upstreams, validator)
+        };
+        let upstream_answer = query_upstream(question, &upstreams, validator.as_deref()).await;
+        answer.source = match upstream_answer.upstream {
+            Some(upstream) => format!("upstream:{}", upstream),
+            None => "none".to_string(),
+        };
+        // Bogus answers aren't cached, and only reach clients that disabled checking
+        if upstream_answer.security == Security::Bogus {
+            if context.checking_disabled {
+                answer.records = upstream_answer.records;
+            } else {
+                answer.rcode = Rcode::ServFail;
+            }
+            return answer;
+        }
+        let validated = upstream_answer.records.clone();
+        match JSBridge::filter_upstream_response(bridge, question, context, upstream_answer.records)
+            .await
+        {
+            Ok(e) => answer.records = e,
+            Err(_) => {
+                answer.rcode = Rcode::ServFail;
+                return answer;
+            }
+        };
+        // Answers changed by onUpstreamResponse() aren't the ones that were validated
+        answer.secure = upstream_answer.security == Security::Secure
+            && same_records(&answer.records, &validated);
+    }
+    cache_store(
+        question,
+        &answer.records,
+        answer.authoritative,
+        answer.secure,
+    )
+    .await;
+    answer
+}
+
+// Answers a standard query.
+async fn answer_query(
+    buffer: &[u8],
+    peer: &SocketAddr,
+    own_address: SocketAddr,
+    listener: &str,
+    transport: Transport,
+    bridge: &Rc<Mutex<JSBridge>>,
+) -> Option<Vec<u8>> {
+    let received = Instant::now();
     let peer_address = peer.to_string();
-    let own_address = &socket.local_addr().unwrap().to_string();
     let message = match Message::from_slice(buffer) {
         Ok(e) => e,
         Err(err) => {
-            println!(
-                "[DNS]: Malformed incoming message from {} ({})",
-                peer_address, err
-            );
-            return;
+            warn!(target: "dns", peer = %peer_address, "Malformed incoming message ({})", err);
+            return None;
         }
     };
-    let mut instance = bridge.lock().await;
-
+    let context = QueryContext {
+        peer: *peer,
+        local: own_address,
+        listener: listener.to_string(),
+        transport: match transport {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        },
+        id: message.id,
+        recursion_desired: message.rd,
+        checking_disabled: message.cd,
+        dnssec_ok: message
+            .extension
+            .as_ref()
+            .map(|extension| extension.dnssec_ok)
+            .unwrap_or(false),
+        client_subnet: client_subnet(buffer),
+    };
     let mut outbound_response = message.clone();
     outbound_response.qr = QR::Response;
     outbound_response.opcode = Opcode::Query;
     outbound_response.answers = Vec::new();
+    outbound_response.authoritys = Vec::new();
+    outbound_response.additionals = Vec::new();
+    // (question, answers, source) for the query log
+    let mut logged_answers = Vec::new();
+    // The AD bit is only set for clients that showed they understand it (RFC 6840 section 5.8)
+    let mut authenticated = message.ad || context.dnssec_ok;
     for question in &message.questions {
-        let hashed_question = hash_question(question);
-        println!(
-            "[DNS]: Incoming query for {} from {}",
-            question.name, peer_address
-        );
-        if let Some(cached_entry) = cache.get(&hashed_question) {
-            println!(
-                "[Cache]: Reading response from cache: {} (hash={})",
-                question.name, hashed_question
-            );
-            let mut answers = cached_entry.entry.clone();
-            let ttl_offset = SystemTime::now()
-                .duration_since(cached_entry.init_time)
-                .unwrap();
-            for ans in &mut answers {
-                ans.ttl -= ttl_offset;
-            }
-            outbound_response.aa = cached_entry.authoritative;
-            outbound_response.answers.extend(answers);
-        } else {
-            let js_answer = instance
-                .get_response(question, &peer_address, own_address)
-                .await;
-            let mut answers = js_answer.records;
-
-            outbound_response.aa = js_answer.authoritative;
-
-            if answers.is_empty() {
-                answers = query_upstream(question, &instance).await;
-            }
-            if !answers.is_empty() {
-                let min_ttl: Duration = answers
-                    .iter()
-                    .map(|x| x.ttl)
-                    .min()
-                    .unwrap_or(Duration::from_secs(0));
-                println!(
-                    "[Cache]: Writing answer to cache: {} (hash={} min_ttl={}s)",
-                    question.name,
-                    hashed_question,
-                    min_ttl.as_secs()
-                );
-                cache.set(
-                    hashed_question,
-                    CacheEntry {
-                        entry: answers.clone(),
-                        authoritative: js_answer.authoritative,
-                        init_time: SystemTime::now(),
-                    },
-                    min_ttl,
-                );
-            }
-
-            outbound_response.answers.extend(answers);
+        debug!(target: "dns", name = %question.name, peer = %peer_address, "Incoming query");
+        let answer = answer_question(question, bridge, &context).await;
+        if answer.rcode != Rcode::NoError {
+            outbound_response.rcode = answer.rcode;
         }
+        authenticated &= answer.secure;
+        if answer.rcode != Rcode::ServFail {
+            outbound_response.aa = answer.authoritative;
+            outbound_response.answers.extend(answer.records.clone());
+            outbound_response.authoritys.extend(answer.authorities);
+            outbound_response.additionals.extend(answer.additionals);
+        }
+        logged_answers.push((question, answer.records, answer.source));
     }
 
-    let as_bytes = match outbound_response.to_vec() {
+    outbound_response.ad =
+        authenticated && outbound_response.rcode == Rcode::NoError && !message.questions.is_empty();
+
+    let mut as_bytes = match outbound_response.to_vec() {
         Ok(e) => e,
         Err(err) => {
-            println!("[DNS]: Malformed internal data ({})", err);
-            return;
+            error!(target: "dns", "Malformed internal data ({})", err);
+            return None;
         }
     };
-    if socket.send_to(&as_bytes, peer).await.is_err() {
-        println!("[DNS]: Failed sending response to {}", peer_address);
+    // Answers from signed zones get their DNSSEC records if the client asked for them
+    let signable = matches!(outbound_response.rcode, Rcode::NoError | Rcode::NXDomain);
+    if let [question] = &message.questions[..] {
+        if context.dnssec_ok && signable {
+            let zones = bridge.lock().await.zones.clone();
+            let zones = zones.lock().unwrap();
+            let zone = zones.find(&question.name);
+            if let Some((zone, signer)) = zone.and_then(|e| Some((e, zones.signer(&e.origin)?))) {
+                match signer.sign_response(&as_bytes, zone) {
+                    Some(e) => as_bytes = e,
+                    None => {
+                        warn!(target: "dnssec", zone = %zone.origin, name = %question.name, "Failed signing the answer")
+                    }
+                }
+            }
+        }
+    }
+    let rcode = format!("{:?}", outbound_response.rcode);
+    for question in &message.questions {
+        let rrtype = format!("{:?}", question.r#type);
+        metrics::count(
+            "badns_queries_total",
+            &[("rrtype", &rrtype), ("rcode", &rcode)],
+        );
+    }
+
+    let latency = received.elapsed();
+    let client_ip = peer.ip().to_string();
+    let mut query_log = QUERY_LOG.lock().unwrap();
+    let mut stats = STATS.lock().unwrap();
+    for (question, answers, source) in &logged_answers {
+        let entry = QueryLogEntry {
+            client: peer_address.clone(),
+            name: &question.name,
+            rrtype: question.r#type as u16,
+            answers,
+            rcode: outbound_response.rcode,
+            source,
+            latency,
+        };
+        stats.record(&entry, &client_ip);
+        if query_log.is_enabled() {
+            query_log.write(&entry);
+        }
+    }
+    Some(as_bytes)
+}
+
+// Checks the TSIG of a query or transfer request against the keys registered with tsigKey().
+// Requests with a bad signature get the error response to send back instead.
+async fn authenticate(
+    buffer: &[u8],
+    peer: &SocketAddr,
+    bridge: &Rc<Mutex<JSBridge>>,
+) -> Result<Option<SignedRequest>, Vec<u8>> {
+    let keys = bridge.lock().await.tsig_keys.clone();
+    let verification = verify(buffer, &keys.lock().unwrap());
+    let error_response = || {
+        let id = read_u16(buffer, 0).unwrap_or(0);
+        let flags = response_flags(buffer) | RCODE_NOTAUTH;
+        let question = read_question(buffer);
+        build_message(id, flags, question.as_ref(), [(&[], 0), (&[], 0), (&[], 0)])
+    };
+    match verification {
+        Verification::Unsigned => Ok(None),
+        Verification::Rejected {
+            key_name,
+            algorithm,
+            error,
+        } => {
+            warn!(target: "dns", peer = %peer, key = %key_name, "Bad TSIG (error {})", error);
+            Err(reject(error_response(), &key_name, &algorithm, error))
+        }
+        Verification::Signed(mut request) if request.error != 0 => {
+            warn!(target: "dns", peer = %peer, key = %request.key.name, "TSIG time is off by more than the allowed fudge");
+            Err(sign(error_response(), &mut request))
+        }
+        Verification::Signed(request) => Ok(Some(request)),
+    }
+}
+
+// Signs the answer to a signed query, and truncates UDP answers that don't fit the client's
+// payload size. Truncated answers keep their TSIG (RFC 8945 section 5.3).
+fn fit(
+    query: &[u8],
+    response: Vec<u8>,
+    transport: Transport,
+    request: Option<SignedRequest>,
+) -> Vec<u8> {
+    let signed = |message: Vec<u8>| match &request {
+        Some(request) => sign(message, &mut request.clone()),
+        None => message,
+    };
+    let limit = match transport {
+        Transport::Udp => udp_payload_size(query),
+        Transport::Tcp => usize::MAX,
+    };
+    let whole = signed(response.clone());
+    if whole.len() <= limit {
+        return whole;
+    }
+    debug!(target: "dns", size = whole.len(), limit, "Truncating the answer");
+    match truncate(&response) {
+        Some(e) => signed(e),
+        None => whole,
+    }
+}
+
+// Answers one incoming message, returning the response to send back (if any).
+async fn respond(
+    buffer: &[u8],
+    peer: &SocketAddr,
+    own_address: SocketAddr,
+    listener: &str,
+    transport: Transport,
+    bridge: &Rc<Mutex<JSBridge>>,
+) -> Option<Vec<u8>> {
+    let received_at = SystemTime::now();
+    dnstap::emit(&DnstapMessage {
+        kind: MessageType::ClientQuery,
+        transport,
+        query_address: Some(*peer),
+        response_address: Some(own_address),
+        query_time: Some(received_at),
+        response_time: None,
+        query_message: Some(buffer),
+        response_message: None,
+    });
+    let opcode = read_u16(buffer, 2).map(|flags| (flags >> OPCODE_SHIFT) & 0xF);
+    let response = match opcode {
+        Some(OPCODE_QUERY) => match authenticate(buffer, peer, bridge).await {
+            Err(response) => Some(response),
+            Ok(None) => answer_query(buffer, peer, own_address, listener, transport, bridge)
+                .await
+                .map(|response| fit(buffer, response, transport, None)),
+            // Signed queries are answered without their TSIG record, and the answer is signed
+            Ok(Some(request)) => answer_query(
+                &request.message,
+                peer,
+                own_address,
+                listener,
+                transport,
+                bridge,
+            )
+            .await
+            .map(|response| fit(buffer, response, transport, Some(request))),
+        },
+        // UPDATE messages don't parse as queries, so they're handled on the raw bytes
+        Some(OPCODE_UPDATE) => handle_update(buffer, peer, bridge).await,
+        _ => {
+            debug!(target: "dns", peer = %peer, "Unsupported opcode {:?}", opcode);
+            let id = read_u16(buffer, 0)?;
+            let question = read_question(buffer);
+            let flags = response_flags(buffer) | RCODE_NOTIMP;
+            Some(build_message(
+                id,
+                flags,
+                question.as_ref(),
+                [(&[], 0), (&[], 0), (&[], 0)],
+            ))
+        }
+    }?;
+    dnstap::emit(&DnstapMessage {
+        kind: MessageType::ClientResponse,
+        transport,
+        query_address: Some(*peer),
+        response_address: Some(own_address),
+        query_time: Some(received_at),
+        response_time: Some(SystemTime::now()),
+        query_message: Some(buffer),
+        response_message: Some(&response),
+    });
+    Some(response)
+}
+
+async fn handle_packet(
+    buffer: &[u8],
+    peer: &SocketAddr,
+    bridge: &Rc<Mutex<JSBridge>>,
+    socket: &UdpSocket,
+    listener: &str,
+) {
+    let own_address = socket.local_addr().unwrap();
+    let response = match respond(buffer, peer, own_address, listener, Transport::Udp, bridge).await
+    {
+        Some(e) => e,
+        None => return,
+    };
+    if socket.send_to(&response, peer).await.is_err() {
+        warn!(target: "dns", peer = %peer, "Failed sending response");
     }
 }
 
 pub async fn run_server(address: Address, bridge: Rc<Mutex<JSBridge>>) {
     let full_address = address.to_canonical();
     let socket = match UdpSocket::bind(&full_address).await {
-        Ok(socket) => socket,
+        Ok(socket) => Rc::new(socket),
         Err(error) => panic!("Couldn't bind server: {}", error),
     };
 
@@ -223,14 +894,113 @@ pub async fn run_server(address: Address, bridge: Rc<Mutex<JSBridge>>) {
         let (n, peer) = match socket.recv_from(&mut buf).await {
             Ok(e) => e,
             Err(x) => {
-                println!(
-                    "[UDP]: Error while receiving data on {}: {}",
-                    full_address, x
-                );
+                warn!(target: "udp", address = %full_address, "Error while receiving data: {}", x);
                 continue;
             }
         };
 
-        handle_packet(&buf[..n], &peer, &bridge, &socket).await;
+        // Each query is answered in a task of its own, so that handlers waiting for fetch() or
+        // resolve() don't hold up the others
+        let packet = buf[..n].to_vec();
+        let bridge = bridge.clone();
+        let socket = socket.clone();
+        let listener = full_address.clone();
+        spawn_local(async move {
+            handle_packet(&packet, &peer, &bridge, &socket, &listener).await;
+        });
+    }
+}
+
+async fn write_tcp_message(stream: &mut TcpStream, message: &[u8]) -> std::io::Result<()> {
+    stream.write_u16(tcp_length(message)?).await?;
+    stream.write_all(message).await
+}
+
+// Serves the length-prefixed messages of one TCP connection until the client goes quiet.
+async fn handle_connection(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    bridge: Rc<Mutex<JSBridge>>,
+    listener: String,
+) {
+    let own_address = match stream.local_addr() {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    loop {
+        let length = match timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(e)) => e as usize,
+            _ => return,
+        };
+        let mut buffer = vec![0; length];
+        match timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut buffer)).await {
+            Ok(Ok(_)) => {}
+            _ => return,
+        }
+
+        let responses = match read_question(&buffer).filter(is_transfer) {
+            Some(question) => match authenticate(&buffer, &peer, &bridge).await {
+                Err(response) => vec![response],
+                Ok(request) => {
+                    let zones = bridge.lock().await.zones.clone();
+                    let messages = answer_transfer(
+                        request.as_ref().map_or(&buffer, |e| &e.message),
+                        &question,
+                        peer.ip(),
+                        request.as_ref().map(|e| e.key.name.as_str()),
+                        &zones.lock().unwrap(),
+                    );
+                    // Every message of a signed transfer is signed
+                    match request {
+                        Some(mut request) => messages
+                            .into_iter()
+                            .map(|e| sign(e, &mut request))
+                            .collect(),
+                        None => messages,
+                    }
+                }
+            },
+            None => respond(
+                &buffer,
+                &peer,
+                own_address,
+                &listener,
+                Transport::Tcp,
+                &bridge,
+            )
+            .await
+            .into_iter()
+            .collect(),
+        };
+        for response in responses {
+            if write_tcp_message(&mut stream, &response).await.is_err() {
+                warn!(target: "tcp", peer = %peer, "Failed sending response");
+                return;
+            }
+        }
+    }
+}
+
+pub async fn run_tcp_server(address: Address, bridge: Rc<Mutex<JSBridge>>) {
+    let full_address = address.to_canonical();
+    let listener = match TcpListener::bind(&full_address).await {
+        Ok(listener) => listener,
+        Err(error) => panic!("Couldn't bind TCP server: {}", error),
+    };
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(e) => e,
+            Err(x) => {
+                warn!(target: "tcp", address = %full_address, "Error while accepting connection: {}", x);
+                continue;
+            }
+        };
+        spawn_local(handle_connection(
+            stream,
+            peer,
+            bridge.clone(),
+            full_address.clone(),
+        ));
     }
 }
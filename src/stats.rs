@@ -0,0 +1,70 @@
+// In-memory statistics shown on the dashboard.
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::querylog::QueryLogEntry;
+
+const RECENT_QUERIES: usize = 100;
+const TOP_ENTRIES: usize = 10;
+// Caps the memory used for counting, names seen after that aren't counted
+const MAX_TRACKED: usize = 10_000;
+// The source of answers made by ban()
+const BLOCKED_SOURCE: &str = "js:STUB";
+
+#[derive(Default)]
+pub struct Stats {
+    recent: VecDeque<Value>,
+    domains: HashMap<String, u64>,
+    clients: HashMap<String, u64>,
+    total: u64,
+    blocked: u64,
+}
+
+lazy_static! {
+    pub static ref STATS: Mutex<Stats> = Mutex::new(Stats::default());
+}
+
+fn increment(counts: &mut HashMap<String, u64>, key: &str) {
+    if let Some(count) = counts.get_mut(key) {
+        *count += 1;
+    } else if counts.len() < MAX_TRACKED {
+        counts.insert(key.to_string(), 1);
+    }
+}
+
+fn top(counts: &HashMap<String, u64>) -> Value {
+    let mut sorted: Vec<(&String, &u64)> = counts.iter().collect();
+    sorted.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    sorted
+        .into_iter()
+        .take(TOP_ENTRIES)
+        .map(|(name, count)| json!({ "name": name, "count": count }))
+        .collect()
+}
+
+impl Stats {
+    pub fn record(&mut self, entry: &QueryLogEntry, client_ip: &str) {
+        self.total += 1;
+        if entry.source == BLOCKED_SOURCE {
+            self.blocked += 1;
+        }
+        increment(&mut self.domains, entry.name);
+        increment(&mut self.clients, client_ip);
+        if self.recent.len() == RECENT_QUERIES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(entry.to_json());
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "total": self.total,
+            "blocked": self.blocked,
+            "recent": self.recent.iter().rev().collect::<Vec<_>>(),
+            "topDomains": top(&self.domains),
+            "topClients": top(&self.clients),
+        })
+    }
+}
@@ -0,0 +1,99 @@
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+use std::ffi::OsString;
+use std::fs::{rename, File};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::{error, info};
+
+// Process-wide key-value store. It's shared by every JS context, and (once a path is set)
+// mirrored to a JSON file on disk after every change.
+pub struct Store {
+    path: Option<PathBuf>,
+    data: Map<String, Value>,
+}
+
+lazy_static! {
+    pub static ref STORE: Mutex<Store> = Mutex::new(Store::new());
+}
+
+impl Store {
+    fn new() -> Store {
+        Store {
+            path: None,
+            data: Map::new(),
+        }
+    }
+
+    // Reads the store backed by the file.
+    pub fn open(file_name: &str) -> Result<Store, String> {
+        let path = PathBuf::from(file_name);
+        let data = match std::fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(Value::Object(e)) => e,
+                Ok(_) => return Err(format!("Store file {} is not a JSON object!", file_name)),
+                Err(e) => return Err(format!("Store file {} is malformed ({})", file_name, e)),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => Map::new(),
+            Err(e) => return Err(format!("Cannot read store file {} ({})", file_name, e)),
+        };
+        info!(target: "store", entries = data.len(), "Opened {}", file_name);
+        Ok(Store {
+            path: Some(path),
+            data,
+        })
+    }
+
+    // Whether the store is backed by the file - if so, its in-memory state is what's on disk.
+    pub fn is_backed_by(&self, file_name: &str) -> bool {
+        self.path.as_deref() == Some(std::path::Path::new(file_name))
+    }
+
+    // Stops mirroring the store to its file, keeping the data in memory.
+    pub fn detach(&mut self) {
+        self.path = None;
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.data.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: Value) {
+        self.data.insert(key, value);
+        self.flush();
+    }
+
+    pub fn delete(&mut self, key: &str) -> bool {
+        let existed = self.data.remove(key).is_some();
+        if existed {
+            self.flush();
+        }
+        existed
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.data.keys().cloned().collect()
+    }
+
+    fn write_file(&self, path: &PathBuf) -> std::io::Result<()> {
+        // Write to a sibling file first, then rename over the original so that a crash
+        // never leaves a half-written store behind.
+        let mut temporary_name = OsString::from(path.as_os_str());
+        temporary_name.push(".tmp");
+        let temporary_path = PathBuf::from(temporary_name);
+
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(Value::Object(self.data.clone()).to_string().as_bytes())?;
+        file.sync_all()?;
+        rename(&temporary_path, path)
+    }
+
+    fn flush(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = self.write_file(path) {
+                error!(target: "store", "Failed to write {} ({})", path.display(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use std::rc::Rc;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+use crate::jsbridge::JSBridge;
+
+pub struct TimerRequest {
+    pub id: i32,
+    pub delay: Duration,
+}
+
+// Waits for timers scheduled by setTimeout / setInterval and fires them on the shared context.
+// Cleared timers are still fired here - the JS side simply ignores ids it no longer knows.
+// `generation` is the bridge's that scheduled them: once a reload replaced it, its timers are
+// dropped instead of firing on the new context.
+pub async fn run_timers(
+    bridge: Rc<Mutex<JSBridge>>,
+    mut requests: UnboundedReceiver<TimerRequest>,
+    generation: u64,
+) {
+    while let Some(request) = requests.recv().await {
+        if bridge.lock().await.generation != generation {
+            break;
+        }
+        let bridge_reference = bridge.clone();
+        tokio::task::spawn_local(async move {
+            sleep(request.delay).await;
+            let mut instance = bridge_reference.lock().await;
+            if instance.generation == generation {
+                instance.fire_timer(request.id);
+            }
+        });
+    }
+}
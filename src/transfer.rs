@@ -0,0 +1,570 @@
+// Zone transfers over TCP: answering AXFR (RFC 5936) and IXFR (RFC 1995) for our zones, and
+// pulling secondary zones from a primary.
+use rustdns::{Record, Resource};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, Weak};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration};
+use tracing::{debug, info, warn};
+
+use crate::tsig::{sign_request, verify_response, SignedRequest, TsigKey};
+use crate::wire::{
+    build_message, decode_record, parse_records, read_u16, response_flags, tcp_length,
+    write_record, RawQuestion, Section, FLAG_AA, RR_SOA,
+};
+use crate::zone::{normalize, serial_newer, Zone, ZoneKind, ZoneSet};
+
+pub const RR_IXFR: u16 = 251;
+pub const RR_AXFR: u16 = 252;
+const RCODE_REFUSED: u16 = 5;
+const RCODE_NOTAUTH: u16 = 9;
+// Keeps each transfer message well under the 64 KiB TCP message limit, with room for a TSIG
+// record. Records larger than this get a message of their own.
+const MESSAGE_SIZE: usize = 16 * 1024;
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30);
+const MIN_REFRESH: Duration = Duration::from_secs(30);
+
+pub fn is_transfer(question: &RawQuestion) -> bool {
+    question.r#type == RR_AXFR || question.r#type == RR_IXFR
+}
+
+// The serial of the SOA a client sent in an IXFR request's authority section.
+fn ixfr_client_serial(buffer: &[u8]) -> Option<u32> {
+    let records = parse_records(buffer)?;
+    let soa = records
+        .iter()
+        .find(|e| e.section == Section::Authority && e.r#type == RR_SOA)?;
+    match decode_record(buffer, soa)?.resource {
+        Resource::SOA(soa) => Some(soa.serial),
+        _ => None,
+    }
+}
+
+fn error_message(buffer: &[u8], question: &RawQuestion, rcode: u16) -> Vec<Vec<u8>> {
+    let id = read_u16(buffer, 0).unwrap_or(0);
+    let flags = response_flags(buffer) | rcode;
+    vec![build_message(
+        id,
+        flags,
+        Some(question),
+        [(&[], 0), (&[], 0), (&[], 0)],
+    )]
+}
+
+// Splits the records over as many messages as needed. Only the first one repeats the question.
+fn package(buffer: &[u8], question: &RawQuestion, records: &[Record]) -> Vec<Vec<u8>> {
+    let id = read_u16(buffer, 0).unwrap_or(0);
+    let flags = response_flags(buffer) | FLAG_AA;
+    let mut messages = Vec::new();
+    let mut finish = |encoded: &[u8], count: u16| {
+        let question = if messages.is_empty() {
+            Some(question)
+        } else {
+            None
+        };
+        messages.push(build_message(
+            id,
+            flags,
+            question,
+            [(encoded, count), (&[], 0), (&[], 0)],
+        ));
+    };
+    let mut encoded = Vec::new();
+    let mut count = 0;
+    for record in records {
+        let mut next = Vec::new();
+        if write_record(&mut next, record).is_none() {
+            continue;
+        }
+        if count > 0 && encoded.len() + next.len() > MESSAGE_SIZE {
+            finish(&encoded, count);
+            encoded.clear();
+            count = 0;
+        }
+        encoded.extend(next);
+        count += 1;
+    }
+    if count > 0 {
+        finish(&encoded, count);
+    }
+    messages
+}
+
+fn full_transfer(zone: &Zone, soa: &Record) -> Vec<Record> {
+    let mut records = vec![soa.clone()];
+    records.extend(
+        zone.records()
+            .filter(|e| !matches!(e.resource, Resource::SOA(_)))
+            .cloned(),
+    );
+    records.push(soa.clone());
+    records
+}
+
+// The incremental transfer from `client_serial`, or None if the history doesn't reach back that far.
+fn incremental_transfer(zone: &Zone, soa: &Record, client_serial: u32) -> Option<Vec<Record>> {
+    if !serial_newer(zone.serial(), client_serial) {
+        return Some(vec![soa.clone()]);
+    }
+    let start = zone
+        .history
+        .iter()
+        .position(|diff| match &diff.from_soa.resource {
+            Resource::SOA(from) => from.serial == client_serial,
+            _ => false,
+        })?;
+    let mut records = vec![soa.clone()];
+    for diff in &zone.history[start..] {
+        records.push(diff.from_soa.clone());
+        records.extend(diff.removed.iter().cloned());
+        records.push(diff.to_soa.clone());
+        records.extend(diff.added.iter().cloned());
+    }
+    records.push(soa.clone());
+    Some(records)
+}
+
+// Answers an AXFR or IXFR request with the messages to send back. `key` names the TSIG key the
+// request was signed with.
+pub fn answer_transfer(
+    buffer: &[u8],
+    question: &RawQuestion,
+    client: IpAddr,
+    key: Option<&str>,
+    zones: &ZoneSet,
+) -> Vec<Vec<u8>> {
+    let kind = if question.r#type == RR_AXFR {
+        "AXFR"
+    } else {
+        "IXFR"
+    };
+    let zone = match zones.get(&question.name) {
+        Some(e) => e,
+        None => return error_message(buffer, question, RCODE_NOTAUTH),
+    };
+    if !zones.may_transfer(&zone.origin, client, key) {
+        warn!(target: "transfer", zone = %zone.origin, client = %client, "Refused {}", kind);
+        return error_message(buffer, question, RCODE_REFUSED);
+    }
+    let soa = match zone.soa() {
+        Some(e) => e,
+        None => return error_message(buffer, question, RCODE_NOTAUTH),
+    };
+    let records = if question.r#type == RR_IXFR {
+        // Without usable history, IXFR falls back to a full transfer
+        ixfr_client_serial(buffer)
+            .and_then(|serial| incremental_transfer(zone, &soa, serial))
+            .unwrap_or_else(|| full_transfer(zone, &soa))
+    } else {
+        full_transfer(zone, &soa)
+    };
+    info!(target: "transfer", zone = %zone.origin, client = %client, serial = zone.serial(), "Sending {} of {} records", kind, records.len());
+    package(buffer, question, &records)
+}
+
+// ======================================= Secondary zones =======================================
+
+async fn read_message(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let length = stream.read_u16().await? as usize;
+    let mut message = vec![0u8; length];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+async fn write_message(stream: &mut TcpStream, message: &[u8]) -> std::io::Result<()> {
+    stream.write_u16(tcp_length(message)?).await?;
+    stream.write_all(message).await
+}
+
+// Connects to the primary and sends it a query, signed if there's a key. The exchange checks
+// the signatures of the responses.
+async fn send_query(
+    origin: &str,
+    r#type: u16,
+    primary: SocketAddr,
+    key: Option<&TsigKey>,
+) -> Result<(TcpStream, Option<SignedRequest>), String> {
+    let question = RawQuestion {
+        name: origin.to_string(),
+        r#type,
+        class: 1,
+    };
+    let id = rand::random();
+    let message = build_message(id, 0, Some(&question), [(&[], 0), (&[], 0), (&[], 0)]);
+    let (message, request) = match key {
+        Some(key) => {
+            let (signed, request) = sign_request(message, key);
+            (signed, Some(request))
+        }
+        None => (message, None),
+    };
+    let mut stream = TcpStream::connect(primary)
+        .await
+        .map_err(|e| e.to_string())?;
+    write_message(&mut stream, &message)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok((stream, request))
+}
+
+async fn read_response(
+    stream: &mut TcpStream,
+    request: &mut Option<SignedRequest>,
+) -> Result<Vec<u8>, String> {
+    let message = read_message(stream).await.map_err(|e| e.to_string())?;
+    let message = match request {
+        Some(request) => verify_response(&message, request)?,
+        None => message,
+    };
+    match read_u16(&message, 2).map(|flags| flags & 0xF) {
+        Some(0) => Ok(message),
+        Some(rcode) => Err(format!("primary answered with RCODE {}", rcode)),
+        None => Err("primary sent a malformed message".to_string()),
+    }
+}
+
+// Asks the primary for the zone's current serial.
+async fn fetch_serial(
+    origin: &str,
+    primary: SocketAddr,
+    key: Option<&TsigKey>,
+) -> Result<u32, String> {
+    let (mut stream, mut request) = send_query(origin, RR_SOA, primary, key).await?;
+    let message = read_response(&mut stream, &mut request).await?;
+    let records = parse_records(&message).ok_or("malformed SOA response")?;
+    records
+        .iter()
+        .filter(|e| e.section == Section::Answer)
+        .filter_map(|e| decode_record(&message, e))
+        .find_map(|e| match e.resource {
+            Resource::SOA(soa) => Some(soa.serial),
+            _ => None,
+        })
+        .ok_or_else(|| "primary didn't return the SOA".to_string())
+}
+
+// Pulls the whole zone with AXFR.
+async fn fetch_zone(
+    origin: &str,
+    primary: SocketAddr,
+    key: Option<&TsigKey>,
+) -> Result<Zone, String> {
+    let (mut stream, mut request) = send_query(origin, RR_AXFR, primary, key).await?;
+
+    let mut records: Vec<Record> = Vec::new();
+    let mut skipped = 0;
+    loop {
+        let message = read_response(&mut stream, &mut request).await?;
+        let raw = parse_records(&message).ok_or("malformed AXFR message")?;
+        if raw.is_empty() {
+            return Err("primary ended the transfer early".to_string());
+        }
+        for record in raw.iter().filter(|e| e.section == Section::Answer) {
+            match decode_record(&message, record) {
+                Some(e) => records.push(e),
+                None => skipped += 1,
+            }
+        }
+        // The transfer ends with the SOA it started with
+        let soa_count = records
+            .iter()
+            .filter(|e| matches!(e.resource, Resource::SOA(_)))
+            .count();
+        if soa_count >= 2 {
+            break;
+        }
+    }
+    if matches!(&request, Some(e) if !e.complete()) {
+        return Err("the transfer didn't end with a signed message".to_string());
+    }
+    if skipped > 0 {
+        warn!(target: "transfer", zone = %origin, "Skipped {} records of unsupported types", skipped);
+    }
+    // Drop the closing SOA
+    records.pop();
+    Zone::new(origin, ZoneKind::Loaded, records)
+}
+
+// Keeps a secondary zone in sync with its primary until the zone set it feeds is dropped by a
+// reload. The SOA's refresh and retry intervals set the pace. With a key, all requests are signed
+// and unsigned responses are refused.
+pub async fn run_secondary(
+    origin: String,
+    primary: SocketAddr,
+    key: Option<TsigKey>,
+    zones: Weak<Mutex<ZoneSet>>,
+) {
+    let origin = normalize(&origin);
+    loop {
+        let current = match zones.upgrade() {
+            Some(zones) => zones.lock().unwrap().get(&origin).map(|e| e.serial()),
+            None => return,
+        };
+        let result = async {
+            let serial = timeout(TRANSFER_TIMEOUT, fetch_serial(&origin, primary, key.as_ref()))
+                .await
+                .map_err(|_| "timed out".to_string())??;
+            if let Some(current_serial) = current {
+                if !serial_newer(serial, current_serial) {
+                    debug!(target: "transfer", zone = %origin, serial, "Secondary zone is up to date");
+                    return Ok(());
+                }
+            }
+            let zone = timeout(TRANSFER_TIMEOUT, fetch_zone(&origin, primary, key.as_ref()))
+                .await
+                .map_err(|_| "timed out".to_string())??;
+            info!(target: "transfer", zone = %origin, serial = zone.serial(), "Transferred from {}", primary);
+            if let Some(zones) = zones.upgrade() {
+                zones.lock().unwrap().insert(zone);
+            }
+            Ok::<(), String>(())
+        }
+        .await;
+
+        let soa = match zones.upgrade() {
+            Some(zones) => zones.lock().unwrap().get(&origin).and_then(|e| e.soa()),
+            None => return,
+        };
+        let (refresh, retry) = match soa.map(|e| e.resource) {
+            Some(Resource::SOA(soa)) => (soa.refresh, soa.retry),
+            _ => (MIN_REFRESH, MIN_REFRESH),
+        };
+        let delay = match result {
+            Ok(_) => refresh,
+            Err(e) => {
+                warn!(target: "transfer", zone = %origin, "Transfer from {} failed ({})", primary, e);
+                retry
+            }
+        };
+        sleep(delay.max(MIN_REFRESH)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustdns::{Class, SOA, TXT};
+    use std::net::Ipv4Addr;
+
+    fn record(name: &str, resource: Resource) -> Record {
+        Record {
+            name: name.to_string(),
+            class: Class::Internet,
+            ttl: Duration::from_secs(300),
+            resource,
+        }
+    }
+
+    fn soa(serial: u32) -> Record {
+        record(
+            "lab.",
+            Resource::SOA(SOA {
+                mname: "ns.lab.".to_string(),
+                rname: "admin.lab.".to_string(),
+                serial,
+                refresh: Duration::from_secs(3600),
+                retry: Duration::from_secs(600),
+                expire: Duration::from_secs(86400),
+                minimum: Duration::from_secs(300),
+            }),
+        )
+    }
+
+    fn a(name: &str, ip: [u8; 4]) -> Record {
+        record(name, Resource::A(Ipv4Addr::from(ip)))
+    }
+
+    // Version `serial` of the `lab.` zone: `www.lab.` moves, and a host is added at each serial.
+    fn zone(serial: u32) -> Zone {
+        let mut records = vec![
+            soa(serial),
+            record("lab.", Resource::NS("ns.lab.".to_string())),
+            a("ns.lab.", [10, 0, 0, 53]),
+            a("www.lab.", [10, 0, 0, serial as u8]),
+        ];
+        for i in 1..=serial {
+            records.push(a(&format!("host{}.lab.", i), [10, 0, 1, i as u8]));
+        }
+        Zone::new("lab.", ZoneKind::Loaded, records).unwrap()
+    }
+
+    fn zones() -> ZoneSet {
+        let mut zones = ZoneSet::default();
+        zones.insert(zone(1));
+        zones
+            .allow_transfer("lab.", &["10.0.0.0/8".to_string(), "key:xfr".to_string()])
+            .unwrap();
+        zones
+    }
+
+    // A transfer request, with the client's SOA in the authority section for IXFR.
+    fn request(origin: &str, r#type: u16, client_serial: Option<u32>) -> (Vec<u8>, RawQuestion) {
+        let question = RawQuestion {
+            name: origin.to_string(),
+            r#type,
+            class: 1,
+        };
+        let mut authority = Vec::new();
+        if let Some(serial) = client_serial {
+            write_record(&mut authority, &soa(serial)).unwrap();
+        }
+        let count = client_serial.is_some() as u16;
+        let buffer = build_message(
+            7,
+            0,
+            Some(&question),
+            [(&[], 0), (&authority, count), (&[], 0)],
+        );
+        (buffer, question)
+    }
+
+    fn transfer(zones: &ZoneSet, r#type: u16, client: [u8; 4], key: Option<&str>) -> Vec<Vec<u8>> {
+        let (buffer, question) = request("lab.", r#type, None);
+        answer_transfer(&buffer, &question, IpAddr::from(client), key, zones)
+    }
+
+    fn ixfr(zones: &ZoneSet, client_serial: u32) -> Vec<Record> {
+        let (buffer, question) = request("lab.", RR_IXFR, Some(client_serial));
+        answers(&answer_transfer(
+            &buffer,
+            &question,
+            IpAddr::from([10, 0, 0, 1]),
+            None,
+            zones,
+        ))
+    }
+
+    fn rcode(messages: &[Vec<u8>]) -> u16 {
+        assert_eq!(messages.len(), 1);
+        read_u16(&messages[0], 2).unwrap() & 0xF
+    }
+
+    fn answers(messages: &[Vec<u8>]) -> Vec<Record> {
+        let mut records = Vec::new();
+        for message in messages {
+            assert_eq!(read_u16(message, 0), Some(7));
+            assert_eq!(read_u16(message, 2).unwrap() & 0xF, 0);
+            let raw = parse_records(message).unwrap();
+            records.extend(
+                raw.iter()
+                    .filter(|e| e.section == Section::Answer)
+                    .map(|e| decode_record(message, e).unwrap()),
+            );
+        }
+        records
+    }
+
+    fn serials(records: &[Record]) -> Vec<u32> {
+        records
+            .iter()
+            .filter_map(|e| match &e.resource {
+                Resource::SOA(soa) => Some(soa.serial),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn large_transfers_are_split_by_size() {
+        let (buffer, question) = request("lab.", RR_AXFR, None);
+        let text = |i: usize, strings: usize| {
+            let strings = (0..strings)
+                .map(|_| vec![b'a' + (i % 26) as u8; 250])
+                .collect();
+            record(&format!("txt{}.lab.", i), Resource::TXT(TXT(strings)))
+        };
+        let mut records: Vec<Record> = (0..500).map(|i| text(i, 1)).collect();
+        // Too large to share a message
+        records.insert(250, text(250, 100));
+
+        let messages = package(&buffer, &question, &records);
+        assert!(messages.len() > 10);
+        for (i, message) in messages.iter().enumerate() {
+            assert!(tcp_length(message).is_ok());
+            // Only the first message repeats the question
+            assert_eq!(read_u16(message, 4), Some((i == 0) as u16));
+            let count = read_u16(message, 6).unwrap();
+            assert!(count == 1 || message.len() <= MESSAGE_SIZE + 64);
+        }
+        let big = messages
+            .iter()
+            .find(|e| e.len() > MESSAGE_SIZE + 64)
+            .unwrap();
+        assert_eq!(read_u16(big, 6), Some(1));
+        assert_eq!(answers(&messages), records);
+    }
+
+    #[test]
+    fn axfr_sends_the_zone_between_soas() {
+        let zones = zones();
+        let records = answers(&transfer(&zones, RR_AXFR, [10, 1, 2, 3], None));
+        assert_eq!(records.len(), zone(1).records().count() + 1);
+        assert_eq!(records.first(), Some(&soa(1)));
+        assert_eq!(records.last(), Some(&soa(1)));
+        assert_eq!(serials(&records), vec![1, 1]);
+        assert!(records.contains(&record("lab.", Resource::NS("ns.lab.".to_string()))));
+
+        // By key from anywhere, by address otherwise
+        let signed = transfer(&zones, RR_AXFR, [192, 0, 2, 1], Some("xfr."));
+        assert_eq!(answers(&signed).len(), records.len());
+        assert_eq!(
+            rcode(&transfer(&zones, RR_AXFR, [192, 0, 2, 1], None)),
+            RCODE_REFUSED
+        );
+        assert_eq!(
+            rcode(&transfer(&zones, RR_AXFR, [192, 0, 2, 1], Some("other."))),
+            RCODE_REFUSED
+        );
+
+        let (buffer, question) = request("other.lab.", RR_AXFR, None);
+        let other = answer_transfer(
+            &buffer,
+            &question,
+            IpAddr::from([10, 0, 0, 1]),
+            None,
+            &zones,
+        );
+        assert_eq!(rcode(&other), RCODE_NOTAUTH);
+    }
+
+    #[test]
+    fn ixfr_sends_the_changes_since_the_client_serial() {
+        let mut zones = zones();
+        zones.insert(zone(2));
+        zones.insert(zone(3));
+
+        // Each change is the old SOA, the removed records, the new SOA and the added ones
+        let records = ixfr(&zones, 1);
+        assert_eq!(serials(&records), vec![3, 1, 2, 2, 3, 3]);
+        assert_eq!(
+            records[..4],
+            [soa(3), soa(1), a("www.lab.", [10, 0, 0, 1]), soa(2)]
+        );
+        assert_eq!(records.len(), 12);
+        assert!(records[4..6].contains(&a("host2.lab.", [10, 0, 1, 2])));
+        assert!(records[4..6].contains(&a("www.lab.", [10, 0, 0, 2])));
+        assert_eq!(serials(&ixfr(&zones, 2)), vec![3, 2, 3, 3]);
+
+        // Up to date
+        assert_eq!(ixfr(&zones, 3), vec![soa(3)]);
+        // Older than the history, so it falls back to a full transfer
+        let full = ixfr(&zones, 0);
+        assert_eq!(serials(&full), vec![3, 3]);
+        assert_eq!(full.len(), zone(3).records().count() + 1);
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut zones = zones();
+        for serial in 2..=40 {
+            zones.insert(zone(serial));
+        }
+        let history = &zones.get("lab.").unwrap().history;
+        assert_eq!(history.len(), 32);
+        assert_eq!(history[0].from_soa, soa(8));
+        assert_eq!(history[31].to_soa, soa(40));
+        assert_eq!(serials(&ixfr(&zones, 8))[..2], [40, 8]);
+        assert_eq!(serials(&ixfr(&zones, 7)), vec![40, 40]);
+    }
+}
@@ -0,0 +1,539 @@
+// Transaction signatures (RFC 8945), used to authenticate queries, zone transfers and dynamic
+// updates between servers sharing a key. Both sides are here: checking signed requests and
+// signing their responses, and signing our own requests and checking the responses.
+use data_encoding::BASE64;
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+
+use crate::wire::{parse_records, read_name, read_u16, write_name, write_raw_record, Section};
+use crate::zone::normalize;
+
+pub const RR_TSIG: u16 = 250;
+const CLASS_ANY: u16 = 255;
+const FUDGE: u16 = 300;
+
+pub const BADSIG: u16 = 16;
+pub const BADKEY: u16 = 17;
+pub const BADTIME: u16 = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    HmacSha1,
+    HmacSha256,
+    HmacSha512,
+}
+
+impl Algorithm {
+    // Accepts names with or without the trailing dot, e.g. `hmac-sha256`.
+    pub fn parse(name: &str) -> Option<Algorithm> {
+        match normalize(name).as_str() {
+            "hmac-sha1." => Some(Algorithm::HmacSha1),
+            "hmac-sha256." => Some(Algorithm::HmacSha256),
+            "hmac-sha512." => Some(Algorithm::HmacSha512),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Algorithm::HmacSha1 => "hmac-sha1.",
+            Algorithm::HmacSha256 => "hmac-sha256.",
+            Algorithm::HmacSha512 => "hmac-sha512.",
+        }
+    }
+
+    fn compute(&self, secret: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+        match self {
+            Algorithm::HmacSha1 => compute::<Hmac<Sha1>>(secret, parts),
+            Algorithm::HmacSha256 => compute::<Hmac<Sha256>>(secret, parts),
+            Algorithm::HmacSha512 => compute::<Hmac<Sha512>>(secret, parts),
+        }
+    }
+}
+
+fn compute<M: Mac + KeyInit>(secret: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[derive(Clone)]
+pub struct TsigKey {
+    // Normalized, e.g. `update-key.`
+    pub name: String,
+    pub algorithm: Algorithm,
+    pub secret: Vec<u8>,
+}
+
+impl TsigKey {
+    // Secrets are shared as base64, the way tsig-keygen and BIND configurations have them.
+    pub fn new(name: &str, algorithm: &str, secret: &str) -> Result<TsigKey, String> {
+        Ok(TsigKey {
+            name: normalize(name),
+            algorithm: Algorithm::parse(algorithm)
+                .ok_or_else(|| format!("Unsupported TSIG algorithm {}!", algorithm))?,
+            secret: BASE64
+                .decode(secret.as_bytes())
+                .map_err(|_| format!("The secret of key {} isn't base64", name))?,
+        })
+    }
+}
+
+// Keys end up in upstream and zone configurations, which get logged; the secret must not.
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TsigKey({} {})", self.name, self.algorithm.name())
+    }
+}
+
+// A signed exchange: a request whose signature checked out, or one we signed ourselves. Every
+// following message is signed or checked against the MAC of the one before it.
+#[derive(Clone)]
+pub struct SignedRequest {
+    pub key: TsigKey,
+    // The request without its TSIG record
+    pub message: Vec<u8>,
+    mac: Vec<u8>,
+    // BADTIME if the signature was valid but the clocks are too far apart
+    pub error: u16,
+    // Set once a response was signed or checked; later ones only cover the timers
+    continued: bool,
+    // Unsigned messages received since the last signed one, covered by the next MAC
+    unsigned: Vec<u8>,
+}
+
+impl SignedRequest {
+    // Whether the last response checked was signed. Transfers must end with a signed message.
+    pub fn complete(&self) -> bool {
+        self.unsigned.is_empty()
+    }
+}
+
+pub enum Verification {
+    Unsigned,
+    Signed(SignedRequest),
+    // BADKEY or BADSIG. The response must carry an unsigned TSIG with the error.
+    Rejected {
+        key_name: String,
+        algorithm: String,
+        error: u16,
+    },
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.as_secs())
+        .unwrap_or(0)
+}
+
+// The TSIG fields covered by the MAC, in the order RFC 8945 section 4.3.3 lists them.
+fn variables(key: &TsigKey, time_signed: u64, fudge: u16, error: u16, other: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    write_name(&mut output, &key.name);
+    output.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    output.extend_from_slice(&0u32.to_be_bytes());
+    write_name(&mut output, key.algorithm.name());
+    output.extend_from_slice(&timers(time_signed, fudge));
+    output.extend_from_slice(&error.to_be_bytes());
+    output.extend_from_slice(&(other.len() as u16).to_be_bytes());
+    output.extend_from_slice(other);
+    output
+}
+
+// All that's covered of the TSIG record in the second and later messages of an exchange.
+fn timers(time_signed: u64, fudge: u16) -> Vec<u8> {
+    let mut output = time_signed.to_be_bytes()[2..].to_vec();
+    output.extend_from_slice(&fudge.to_be_bytes());
+    output
+}
+
+// The message as it was before the TSIG record was added: without it, with one additional
+// record less, and with the original ID.
+fn strip(buffer: &[u8], tsig_start: usize, original_id: u16) -> Vec<u8> {
+    let mut message = buffer[..tsig_start].to_vec();
+    message[0..2].copy_from_slice(&original_id.to_be_bytes());
+    let additional = read_u16(buffer, 10).unwrap_or(1).saturating_sub(1);
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+    message
+}
+
+struct Fields<'a> {
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: &'a [u8],
+    original_id: u16,
+    error: u16,
+    other: &'a [u8],
+}
+
+fn parse_fields(buffer: &[u8], rdata_start: usize) -> Option<Fields<'_>> {
+    let (algorithm, position) = read_name(buffer, rdata_start)?;
+    let field = |offset: usize| read_u16(buffer, position + offset);
+    let time_signed = (field(0)? as u64) << 32 | (field(2)? as u64) << 16 | field(4)? as u64;
+    let mac_size = field(8)? as usize;
+    let mac = buffer.get(position + 10..position + 10 + mac_size)?;
+    let position = position + 10 + mac_size;
+    let other_size = read_u16(buffer, position + 4)? as usize;
+    Some(Fields {
+        algorithm,
+        time_signed,
+        fudge: field(6)?,
+        mac,
+        original_id: read_u16(buffer, position)?,
+        error: read_u16(buffer, position + 2)?,
+        other: buffer.get(position + 6..position + 6 + other_size)?,
+    })
+}
+
+// The TSIG record at the end of the message, if there is one: its offset, its key name, and its
+// fields if they parse.
+fn find_tsig(buffer: &[u8]) -> Option<(usize, String, Option<Fields<'_>>)> {
+    let records = parse_records(buffer)?;
+    let tsig = match records.last() {
+        Some(e) if e.r#type == RR_TSIG && e.section == Section::Additional => e,
+        _ => return None,
+    };
+    let key_name = normalize(&read_name(buffer, tsig.start)?.0);
+    Some((tsig.start, key_name, parse_fields(buffer, tsig.rdata_start)))
+}
+
+fn same_mac(expected: &[u8], received: &[u8]) -> bool {
+    expected.ct_eq(received).into()
+}
+
+fn outside_fudge(time_signed: u64, fudge: u16) -> bool {
+    now().abs_diff(time_signed) > fudge as u64
+}
+
+// Checks the TSIG record at the end of the message against the given keys.
+pub fn verify(buffer: &[u8], keys: &[TsigKey]) -> Verification {
+    let (start, key_name, fields) = match find_tsig(buffer) {
+        Some(e) => e,
+        None => return Verification::Unsigned,
+    };
+    let fields = match fields {
+        Some(e) => e,
+        None => {
+            return Verification::Rejected {
+                key_name,
+                algorithm: Algorithm::HmacSha256.name().to_string(),
+                error: BADSIG,
+            }
+        }
+    };
+    let rejected = |error| Verification::Rejected {
+        key_name: key_name.clone(),
+        algorithm: fields.algorithm.clone(),
+        error,
+    };
+
+    let key = match keys.iter().find(|key| key.name == key_name) {
+        Some(key) if Algorithm::parse(&fields.algorithm) == Some(key.algorithm) => key,
+        _ => return rejected(BADKEY),
+    };
+    let message = strip(buffer, start, fields.original_id);
+    let covered = variables(
+        key,
+        fields.time_signed,
+        fields.fudge,
+        fields.error,
+        fields.other,
+    );
+    let expected = key.algorithm.compute(&key.secret, &[&message, &covered]);
+    if !same_mac(&expected, fields.mac) {
+        return rejected(BADSIG);
+    }
+    Verification::Signed(SignedRequest {
+        key: key.clone(),
+        message,
+        mac: fields.mac.to_vec(),
+        error: if outside_fudge(fields.time_signed, fields.fudge) {
+            BADTIME
+        } else {
+            0
+        },
+        continued: false,
+        unsigned: Vec::new(),
+    })
+}
+
+fn append_tsig(
+    mut message: Vec<u8>,
+    key_name: &str,
+    algorithm: &str,
+    time_signed: u64,
+    mac: &[u8],
+    error: u16,
+    other: &[u8],
+) -> Vec<u8> {
+    let id = read_u16(&message, 0).unwrap_or(0);
+    let mut rdata = Vec::new();
+    write_name(&mut rdata, algorithm);
+    rdata.extend_from_slice(&timers(time_signed, FUDGE));
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(mac);
+    rdata.extend_from_slice(&id.to_be_bytes());
+    rdata.extend_from_slice(&error.to_be_bytes());
+    rdata.extend_from_slice(&(other.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(other);
+
+    let additional = read_u16(&message, 10).unwrap_or(0) + 1;
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+    write_raw_record(&mut message, key_name, RR_TSIG, CLASS_ANY, 0, &rdata);
+    message
+}
+
+// Signs a response to a signed request. Each message of a multi-message response (like a zone
+// transfer) is signed in turn.
+pub fn sign(message: Vec<u8>, request: &mut SignedRequest) -> Vec<u8> {
+    let time_signed = now();
+    // BADTIME responses tell the client our time
+    let other = if request.error == BADTIME {
+        time_signed.to_be_bytes()[2..].to_vec()
+    } else {
+        Vec::new()
+    };
+    let covered = if request.continued {
+        timers(time_signed, FUDGE)
+    } else {
+        variables(&request.key, time_signed, FUDGE, request.error, &other)
+    };
+    let mac = request.key.algorithm.compute(
+        &request.key.secret,
+        &[
+            &(request.mac.len() as u16).to_be_bytes(),
+            &request.mac,
+            &message,
+            &covered,
+        ],
+    );
+    let signed = append_tsig(
+        message,
+        &request.key.name,
+        request.key.algorithm.name(),
+        time_signed,
+        &mac,
+        request.error,
+        &other,
+    );
+    request.mac = mac;
+    request.continued = true;
+    signed
+}
+
+// Adds the unsigned TSIG record that reports a BADKEY or BADSIG error.
+pub fn reject(message: Vec<u8>, key_name: &str, algorithm: &str, error: u16) -> Vec<u8> {
+    append_tsig(message, key_name, algorithm, now(), &[], error, &[])
+}
+
+// Signs a request of our own. The returned exchange checks the responses.
+pub fn sign_request(message: Vec<u8>, key: &TsigKey) -> (Vec<u8>, SignedRequest) {
+    let time_signed = now();
+    let covered = variables(key, time_signed, FUDGE, 0, &[]);
+    let mac = key.algorithm.compute(&key.secret, &[&message, &covered]);
+    let signed = append_tsig(
+        message.clone(),
+        &key.name,
+        key.algorithm.name(),
+        time_signed,
+        &mac,
+        0,
+        &[],
+    );
+    let request = SignedRequest {
+        key: key.clone(),
+        message,
+        mac,
+        error: 0,
+        continued: false,
+        unsigned: Vec::new(),
+    };
+    (signed, request)
+}
+
+// Checks a response to a request we signed, returning it without its TSIG record. After the
+// first response, unsigned messages are allowed in between: the next signed one covers them.
+pub fn verify_response(buffer: &[u8], request: &mut SignedRequest) -> Result<Vec<u8>, String> {
+    let (start, key_name, fields) = match find_tsig(buffer) {
+        Some((start, key_name, Some(fields))) => (start, key_name, fields),
+        Some((_, _, None)) => return Err("malformed TSIG record".to_string()),
+        None if request.continued => {
+            request.unsigned.extend_from_slice(buffer);
+            return Ok(buffer.to_vec());
+        }
+        None => return Err("the response isn't signed".to_string()),
+    };
+    if key_name != request.key.name
+        || Algorithm::parse(&fields.algorithm) != Some(request.key.algorithm)
+    {
+        return Err(format!("the response is signed with key {}", key_name));
+    }
+    if fields.error != 0 {
+        return Err(format!(
+            "the key was rejected with TSIG error {}",
+            fields.error
+        ));
+    }
+    let message = strip(buffer, start, fields.original_id);
+    let covered = if request.continued {
+        timers(fields.time_signed, fields.fudge)
+    } else {
+        variables(
+            &request.key,
+            fields.time_signed,
+            fields.fudge,
+            fields.error,
+            fields.other,
+        )
+    };
+    let expected = request.key.algorithm.compute(
+        &request.key.secret,
+        &[
+            &(request.mac.len() as u16).to_be_bytes(),
+            &request.mac,
+            &request.unsigned,
+            &message,
+            &covered,
+        ],
+    );
+    if !same_mac(&expected, fields.mac) {
+        return Err("the response's TSIG signature is wrong".to_string());
+    }
+    if outside_fudge(fields.time_signed, fields.fudge) {
+        return Err("the response's TSIG time is off by more than the allowed fudge".to_string());
+    }
+    request.mac = fields.mac.to_vec();
+    request.continued = true;
+    request.unsigned.clear();
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{build_message, RawQuestion};
+
+    const RR_AXFR: u16 = 252;
+
+    fn tsig_key(secret: &str) -> TsigKey {
+        TsigKey::new("xfr", "hmac-sha256", secret).unwrap()
+    }
+
+    fn message(id: u16, flags: u16) -> Vec<u8> {
+        let question = RawQuestion {
+            name: "lab.".to_string(),
+            r#type: RR_AXFR,
+            class: 1,
+        };
+        build_message(id, flags, Some(&question), [(&[], 0), (&[], 0), (&[], 0)])
+    }
+
+    fn verified(buffer: &[u8], key: &TsigKey) -> Result<SignedRequest, u16> {
+        match verify(buffer, std::slice::from_ref(key)) {
+            Verification::Signed(e) => Ok(e),
+            Verification::Rejected { error, .. } => Err(error),
+            Verification::Unsigned => Err(0),
+        }
+    }
+
+    #[test]
+    fn signed_exchange_round_trip() {
+        let key = tsig_key("c2VjcmV0LWtleS1mb3ItdGVzdHM=");
+        let query = message(7, 0);
+        let (signed, mut client) = sign_request(query.clone(), &key);
+        assert_eq!(read_u16(&signed, 10), Some(1));
+
+        let mut server = verified(&signed, &key).unwrap();
+        assert_eq!(server.message, query);
+        assert_eq!(server.error, 0);
+
+        // Every message of a multi-message response is signed in turn
+        for i in 0..3 {
+            let response = message(7, 0x8000 | i);
+            let signed = sign(response.clone(), &mut server);
+            assert_eq!(verify_response(&signed, &mut client), Ok(response));
+            assert!(client.complete());
+        }
+        // Unsigned messages are only allowed after the first signed one, until the next
+        let unsigned = message(7, 0x8000);
+        assert_eq!(verify_response(&unsigned, &mut client), Ok(unsigned));
+        assert!(!client.complete());
+        let (_, mut fresh) = sign_request(query, &key);
+        assert!(verify_response(&message(7, 0x8000), &mut fresh).is_err());
+
+        assert!(matches!(
+            verify(&message(7, 0), &[key]),
+            Verification::Unsigned
+        ));
+    }
+
+    #[test]
+    fn wrong_signatures_are_rejected() {
+        let key = tsig_key("c2VjcmV0LWtleS1mb3ItdGVzdHM=");
+        let (signed, mut client) = sign_request(message(7, 0), &key);
+
+        let mut tampered = signed.clone();
+        // In the question name
+        tampered[13] ^= 1;
+        assert_eq!(verified(&tampered, &key).err(), Some(BADSIG));
+        let other_secret = tsig_key("b3RoZXItc2VjcmV0");
+        assert_eq!(verified(&signed, &other_secret).err(), Some(BADSIG));
+        let other_name = TsigKey {
+            name: "other.".to_string(),
+            ..key.clone()
+        };
+        assert_eq!(verified(&signed, &other_name).err(), Some(BADKEY));
+
+        // Responses signed with another secret, or changed on the way, are refused too
+        let (_, mut server) = sign_request(message(7, 0), &other_secret);
+        server.mac = client.mac.clone();
+        let forged = sign(message(7, 0x8000), &mut server);
+        assert!(verify_response(&forged, &mut client).is_err());
+        let mut server = verified(&signed, &key).unwrap();
+        let mut response = sign(message(7, 0x8000), &mut server);
+        response[3] ^= 1;
+        assert!(verify_response(&response, &mut client).is_err());
+    }
+
+    #[test]
+    fn clock_skew_is_badtime() {
+        let key = tsig_key("c2VjcmV0LWtleS1mb3ItdGVzdHM=");
+        let query = message(7, 0);
+        let time_signed = now() - FUDGE as u64 - 60;
+        let covered = variables(&key, time_signed, FUDGE, 0, &[]);
+        let mac = key.algorithm.compute(&key.secret, &[&query, &covered]);
+        let signed = append_tsig(
+            query.clone(),
+            &key.name,
+            key.algorithm.name(),
+            time_signed,
+            &mac,
+            0,
+            &[],
+        );
+
+        // The signature is valid, so the response is signed, with the error and our time
+        let mut server = verified(&signed, &key).unwrap();
+        assert_eq!(server.error, BADTIME);
+        let response = sign(message(7, 0x8000), &mut server);
+        let mut client = SignedRequest {
+            key,
+            message: query,
+            mac,
+            error: 0,
+            continued: false,
+            unsigned: Vec::new(),
+        };
+        let error = verify_response(&response, &mut client).unwrap_err();
+        assert!(error.contains(&format!("TSIG error {}", BADTIME)));
+    }
+}
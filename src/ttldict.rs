@@ -49,6 +49,18 @@ impl<K: Eq + std::hash::Hash + Clone, V> TTLDict<K, V> {
         self.backing.get(key).map(|expiring| &expiring.value)
     }
 
+    pub fn len(&mut self) -> usize {
+        self.tidy_up();
+        self.backing.len()
+    }
+
+    pub fn clear(&mut self) -> usize {
+        let count = self.backing.len();
+        self.backing.clear();
+        self.drop_queue.clear();
+        count
+    }
+
     pub fn set(&mut self, key: K, value: V, ttl: Duration) {
         let expiring = ExpiringValue::new(value, ttl);
         self.backing.insert(key.clone(), expiring);
@@ -0,0 +1,669 @@
+// Dynamic updates (RFC 2136) of declared zones, authenticated with TSIG.
+use rustdns::{Record, Resource, Type};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Mutex as StdMutex;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::jsbridge::JSBridge;
+use crate::metrics;
+use crate::tsig::{reject, sign, verify, TsigKey, Verification};
+use crate::wire::{
+    build_message, decode_record, encode_rdata, parse_records, read_name, read_question, read_u16,
+    response_flags, RawRecord, Section, RR_SOA,
+};
+use crate::zone::{
+    format_rdata, is_in_zone, normalize, record_type, save_zone, serial_newer, Zone, ZoneKind,
+    ZoneSet,
+};
+
+pub const OPCODE_UPDATE: u16 = 5;
+
+const RCODE_NOERROR: u16 = 0;
+const RCODE_FORMERR: u16 = 1;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+const RCODE_YXDOMAIN: u16 = 6;
+const RCODE_YXRRSET: u16 = 7;
+const RCODE_NXRRSET: u16 = 8;
+const RCODE_NOTAUTH: u16 = 9;
+const RCODE_NOTZONE: u16 = 10;
+
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const TYPE_ANY: u16 = 255;
+
+// Who may update a zone, and where its contents are saved.
+pub struct UpdatePolicy {
+    pub keys: Vec<TsigKey>,
+    pub file: Option<String>,
+}
+
+enum Operation {
+    Add(Record),
+    DeleteRRset(String, u16),
+    DeleteName(String),
+    DeleteRecord(Record),
+}
+
+fn type_code(record: &Record) -> Option<u16> {
+    record_type(&record.resource).map(|e| e as u16)
+}
+
+fn same_record(a: &Record, b: &Record) -> bool {
+    normalize(&a.name) == normalize(&b.name)
+        && encode_rdata(&a.resource) == encode_rdata(&b.resource)
+}
+
+fn owned_by<'a>(contents: &'a [Record], name: &'a str) -> impl Iterator<Item = &'a Record> {
+    contents.iter().filter(move |e| normalize(&e.name) == name)
+}
+
+// Decodes a record whose class is NONE or ANY as if it were IN, which rustdns can represent.
+fn decode_as_internet(buffer: &[u8], raw: &RawRecord) -> Option<Record> {
+    let raw = RawRecord {
+        section: raw.section,
+        start: raw.start,
+        r#type: raw.r#type,
+        class: CLASS_IN,
+        ttl: raw.ttl,
+        rdata_start: raw.rdata_start,
+        rdata: raw.rdata,
+    };
+    decode_record(buffer, &raw)
+}
+
+// RFC 2136 section 3.2
+fn check_prerequisites(
+    buffer: &[u8],
+    records: &[RawRecord],
+    contents: &[Record],
+    origin: &str,
+) -> Result<(), u16> {
+    // Value dependent prerequisites, which must match whole RRsets
+    let mut expected: Vec<Record> = Vec::new();
+    for raw in records.iter().filter(|e| e.section == Section::Answer) {
+        let name = normalize(&read_name(buffer, raw.start).ok_or(RCODE_FORMERR)?.0);
+        if raw.ttl != 0 {
+            return Err(RCODE_FORMERR);
+        }
+        if !is_in_zone(&name, origin) {
+            return Err(RCODE_NOTZONE);
+        }
+        let in_use = owned_by(contents, &name).next().is_some();
+        let rrset_exists = owned_by(contents, &name).any(|e| type_code(e) == Some(raw.r#type));
+        match (raw.class, raw.r#type) {
+            (CLASS_ANY, TYPE_ANY) if !in_use => return Err(RCODE_NXDOMAIN),
+            (CLASS_ANY, _) if raw.r#type != TYPE_ANY && !rrset_exists => return Err(RCODE_NXRRSET),
+            (CLASS_NONE, TYPE_ANY) if in_use => return Err(RCODE_YXDOMAIN),
+            (CLASS_NONE, _) if raw.r#type != TYPE_ANY && rrset_exists => return Err(RCODE_YXRRSET),
+            (CLASS_ANY | CLASS_NONE, _) => {
+                if !raw.rdata.is_empty() {
+                    return Err(RCODE_FORMERR);
+                }
+            }
+            (CLASS_IN, _) => expected.push(decode_record(buffer, raw).ok_or(RCODE_NOTIMP)?),
+            _ => return Err(RCODE_FORMERR),
+        }
+    }
+    for record in &expected {
+        let name = normalize(&record.name);
+        let rrset: Vec<&Record> = owned_by(contents, &name)
+            .filter(|e| type_code(e) == type_code(record))
+            .collect();
+        let wanted: Vec<&Record> = expected
+            .iter()
+            .filter(|e| normalize(&e.name) == name && type_code(e) == type_code(record))
+            .collect();
+        let matches = rrset.len() == wanted.len()
+            && rrset
+                .iter()
+                .all(|e| wanted.iter().any(|other| same_record(e, other)));
+        if !matches {
+            return Err(RCODE_NXRRSET);
+        }
+    }
+    Ok(())
+}
+
+// RFC 2136 section 3.4.1
+fn prescan(buffer: &[u8], records: &[RawRecord], origin: &str) -> Result<Vec<Operation>, u16> {
+    let mut operations = Vec::new();
+    for raw in records.iter().filter(|e| e.section == Section::Authority) {
+        let name = normalize(&read_name(buffer, raw.start).ok_or(RCODE_FORMERR)?.0);
+        if !is_in_zone(&name, origin) {
+            return Err(RCODE_NOTZONE);
+        }
+        // Meta types such as AXFR can't be added or deleted one by one
+        let meta = raw.r#type >= 128 && raw.r#type != TYPE_ANY;
+        let operation = match raw.class {
+            CLASS_IN if !meta && raw.r#type != TYPE_ANY => {
+                Operation::Add(decode_record(buffer, raw).ok_or(RCODE_NOTIMP)?)
+            }
+            CLASS_ANY if !meta && raw.ttl == 0 && raw.rdata.is_empty() => {
+                if raw.r#type == TYPE_ANY {
+                    Operation::DeleteName(name)
+                } else {
+                    Operation::DeleteRRset(name, raw.r#type)
+                }
+            }
+            CLASS_NONE if !meta && raw.ttl == 0 && raw.r#type != TYPE_ANY => {
+                Operation::DeleteRecord(decode_as_internet(buffer, raw).ok_or(RCODE_NOTIMP)?)
+            }
+            _ => return Err(RCODE_FORMERR),
+        };
+        operations.push(operation);
+    }
+    Ok(operations)
+}
+
+// RFC 2136 section 3.4.2. The apex SOA and last NS record can't be deleted, and CNAMEs can't
+// share their name with other records.
+fn apply(contents: &mut Vec<Record>, operation: Operation, origin: &str) {
+    let apex_protected =
+        |name: &str, r#type: Option<u16>| name == origin && matches!(r#type, Some(2 | 6));
+    match operation {
+        Operation::Add(record) => {
+            let name = normalize(&record.name);
+            let is_cname = type_code(&record) == Some(Type::CNAME as u16);
+            if owned_by(contents, &name)
+                .any(|e| (type_code(e) == Some(Type::CNAME as u16)) != is_cname)
+            {
+                return;
+            }
+            if let Resource::SOA(soa) = &record.resource {
+                let current = owned_by(contents, &name).find_map(|e| match &e.resource {
+                    Resource::SOA(current) => Some(current.serial),
+                    _ => None,
+                });
+                if name != origin
+                    || !current
+                        .map(|e| serial_newer(soa.serial, e))
+                        .unwrap_or(false)
+                {
+                    return;
+                }
+            }
+            contents.retain(|e| {
+                let replaced = normalize(&e.name) == name
+                    && (is_cname || type_code(e) == Some(RR_SOA))
+                    && type_code(e) == type_code(&record);
+                !replaced && !same_record(e, &record)
+            });
+            contents.push(record);
+        }
+        Operation::DeleteRRset(name, r#type) => {
+            if apex_protected(&name, Some(r#type)) {
+                return;
+            }
+            contents.retain(|e| normalize(&e.name) != name || type_code(e) != Some(r#type));
+        }
+        Operation::DeleteName(name) => {
+            contents.retain(|e| normalize(&e.name) != name || apex_protected(&name, type_code(e)))
+        }
+        Operation::DeleteRecord(record) => {
+            let name = normalize(&record.name);
+            let r#type = type_code(&record);
+            if r#type == Some(RR_SOA) {
+                return;
+            }
+            let rrset_size = owned_by(contents, &name)
+                .filter(|e| type_code(e) == r#type)
+                .count();
+            if apex_protected(&name, r#type) && rrset_size <= 1 {
+                return;
+            }
+            contents.retain(|e| !same_record(e, &record));
+        }
+    }
+}
+
+// Bumps the serial, unless the update already raised it.
+fn bump_serial(contents: &mut [Record], old_serial: u32) {
+    for record in contents.iter_mut() {
+        if let Resource::SOA(soa) = &mut record.resource {
+            if !serial_newer(soa.serial, old_serial) {
+                soa.serial = old_serial.wrapping_add(1);
+            }
+        }
+    }
+}
+
+fn record_to_json(record: &Record) -> Value {
+    json!({
+        "name": record.name,
+        "type": record_type(&record.resource).map(|e| format!("{:?}", e)),
+        "ttl": record.ttl.as_secs(),
+        "data": format_rdata(&record.resource),
+    })
+}
+
+// Checks and commits the update, returning the RCODE to answer with.
+fn process(
+    buffer: &[u8],
+    origin: &str,
+    key: &TsigKey,
+    peer: &SocketAddr,
+    zones: &StdMutex<ZoneSet>,
+    instance: &mut JSBridge,
+) -> u16 {
+    let records = match parse_records(buffer) {
+        Some(e) => e,
+        None => return RCODE_FORMERR,
+    };
+    let (updated, diff, file) = {
+        let zones = zones.lock().unwrap();
+        let zone = match zones.get(origin) {
+            Some(e) => e,
+            None => return RCODE_NOTAUTH,
+        };
+        let mut contents: Vec<Record> = zone.records().cloned().collect();
+        if let Err(rcode) = check_prerequisites(buffer, &records, &contents, &zone.origin) {
+            return rcode;
+        }
+        let operations = match prescan(buffer, &records, &zone.origin) {
+            Ok(e) => e,
+            Err(rcode) => return rcode,
+        };
+        let original = contents.clone();
+        for operation in operations {
+            apply(&mut contents, operation, &zone.origin);
+        }
+        let changed = contents.len() != original.len()
+            || contents.iter().any(|e| {
+                !original
+                    .iter()
+                    .any(|other| same_record(e, other) && e.ttl == other.ttl)
+            });
+        if !changed {
+            // Nothing to do, so the serial stays
+            return RCODE_NOERROR;
+        }
+        bump_serial(&mut contents, zone.serial());
+        let updated = match Zone::new(&zone.origin, ZoneKind::Declared, contents) {
+            Ok(e) => e,
+            Err(_) => return RCODE_SERVFAIL,
+        };
+        let diff = match zone.diff(&updated) {
+            Some(e) => e,
+            None => return RCODE_SERVFAIL,
+        };
+        let file = zones.update_policy(origin).and_then(|e| e.file.clone());
+        (updated, diff, file)
+    };
+
+    let update = json!({
+        "key": key.name.trim_end_matches('.'),
+        "client": peer.to_string(),
+        "added": diff.added.iter().map(record_to_json).collect::<Vec<_>>(),
+        "removed": diff.removed.iter().map(record_to_json).collect::<Vec<_>>(),
+    });
+    match instance.accept_update(origin, &update) {
+        Ok(true) => {}
+        Ok(false) => {
+            info!(target: "update", zone = %origin, key = %key.name, "Update refused by an onUpdate hook");
+            return RCODE_REFUSED;
+        }
+        Err(e) => {
+            error!(target: "js", zone = %origin, "onUpdate hook failed! ({})", e);
+            return RCODE_SERVFAIL;
+        }
+    }
+    if let Some(file) = file {
+        if let Err(e) = save_zone(&updated, &file) {
+            error!(target: "update", zone = %origin, "Not applying the update ({})", e);
+            return RCODE_SERVFAIL;
+        }
+    }
+    info!(
+        target: "update",
+        zone = %origin,
+        key = %key.name,
+        serial = updated.serial(),
+        "Applied update: {} added, {} removed", diff.added.len(), diff.removed.len()
+    );
+    zones.lock().unwrap().insert(updated);
+    RCODE_NOERROR
+}
+
+// Answers an UPDATE message. Only signed updates for zones that allowUpdate() was called for
+// are accepted.
+pub async fn handle_update(
+    buffer: &[u8],
+    peer: &SocketAddr,
+    bridge: &Rc<Mutex<JSBridge>>,
+) -> Option<Vec<u8>> {
+    let id = read_u16(buffer, 0)?;
+    let zone_section = read_question(buffer)?;
+    let reply = |rcode: u16| {
+        metrics::count("badns_updates_total", &[("rcode", &rcode.to_string())]);
+        build_message(
+            id,
+            response_flags(buffer) | rcode,
+            Some(&zone_section),
+            [(&[], 0), (&[], 0), (&[], 0)],
+        )
+    };
+    if read_u16(buffer, 4) != Some(1) || zone_section.r#type != RR_SOA {
+        return Some(reply(RCODE_FORMERR));
+    }
+    let origin = normalize(&zone_section.name);
+
+    let mut instance = bridge.lock().await;
+    let zones = instance.zones.clone();
+    let keys = {
+        let zones = zones.lock().unwrap();
+        match zones.update_policy(&origin) {
+            Some(policy) => policy.keys.clone(),
+            None => {
+                warn!(target: "update", zone = %origin, peer = %peer, "Update for a zone that doesn't accept updates");
+                return Some(reply(RCODE_NOTAUTH));
+            }
+        }
+    };
+    let mut request = match verify(buffer, &keys) {
+        Verification::Signed(e) => e,
+        Verification::Unsigned => {
+            warn!(target: "update", zone = %origin, peer = %peer, "Refused unsigned update");
+            return Some(reply(RCODE_REFUSED));
+        }
+        Verification::Rejected {
+            key_name,
+            algorithm,
+            error,
+        } => {
+            warn!(target: "update", zone = %origin, peer = %peer, key = %key_name, "Bad TSIG (error {})", error);
+            return Some(reject(reply(RCODE_NOTAUTH), &key_name, &algorithm, error));
+        }
+    };
+    if request.error != 0 {
+        warn!(target: "update", zone = %origin, peer = %peer, "TSIG time is off by more than the allowed fudge");
+        return Some(sign(reply(RCODE_NOTAUTH), &mut request));
+    }
+
+    let rcode = process(buffer, &origin, &request.key, peer, &zones, &mut instance);
+    Some(sign(reply(rcode), &mut request))
+}
+
+// Combines a zone's saved contents with its declaration: the records come from the file,
+// the SOA from the declaration, keeping the newer serial.
+pub fn restore(declared: &Zone, saved: Zone) -> Result<Zone, String> {
+    let mut soa = declared.soa().ok_or("declared zone has no SOA")?;
+    if let (Resource::SOA(declared_soa), Resource::SOA(saved_soa)) = (
+        &mut soa.resource,
+        saved
+            .soa()
+            .map(|e| e.resource)
+            .ok_or("saved zone has no SOA")?,
+    ) {
+        if serial_newer(saved_soa.serial, declared_soa.serial) {
+            declared_soa.serial = saved_soa.serial;
+        }
+    }
+    let mut records: Vec<Record> = saved
+        .records()
+        .filter(|e| record_type(&e.resource) != Some(Type::SOA))
+        .cloned()
+        .collect();
+    records.push(soa);
+    info!(target: "update", zone = %declared.origin, "Restored {} saved records", records.len() - 1);
+    Zone::new(&declared.origin, ZoneKind::Declared, records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{write_raw_record, RawQuestion, OPCODE_SHIFT};
+    use rustdns::{Class, SOA};
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    const RR_A: u16 = 1;
+    const RR_NS: u16 = 2;
+    const RR_CNAME: u16 = 5;
+
+    fn record(name: &str, resource: Resource) -> Record {
+        Record {
+            name: name.to_string(),
+            class: Class::Internet,
+            ttl: Duration::from_secs(300),
+            resource,
+        }
+    }
+
+    fn soa(serial: u32) -> Record {
+        record(
+            "lab.",
+            Resource::SOA(SOA {
+                mname: "ns.lab.".to_string(),
+                rname: "admin.lab.".to_string(),
+                serial,
+                refresh: Duration::from_secs(3600),
+                retry: Duration::from_secs(600),
+                expire: Duration::from_secs(86400),
+                minimum: Duration::from_secs(300),
+            }),
+        )
+    }
+
+    fn a(name: &str, ip: [u8; 4]) -> Record {
+        record(name, Resource::A(Ipv4Addr::from(ip)))
+    }
+
+    fn ns(target: &str) -> Record {
+        record("lab.", Resource::NS(target.to_string()))
+    }
+
+    fn cname(name: &str, target: &str) -> Record {
+        record(name, Resource::CNAME(target.to_string()))
+    }
+
+    // The `lab.` zone, with a single nameserver.
+    fn contents() -> Vec<Record> {
+        vec![
+            soa(1),
+            ns("ns.lab."),
+            a("lab.", [10, 0, 0, 9]),
+            a("www.lab.", [10, 0, 0, 1]),
+            cname("alias.lab.", "www.lab."),
+        ]
+    }
+
+    fn serial(contents: &[Record]) -> Option<u32> {
+        contents.iter().find_map(|e| match &e.resource {
+            Resource::SOA(soa) => Some(soa.serial),
+            _ => None,
+        })
+    }
+
+    fn has(contents: &[Record], record: &Record) -> bool {
+        contents.iter().any(|e| same_record(e, record))
+    }
+
+    // Checks prerequisites given as (name, class, type, ttl, rdata) against the zone.
+    fn prerequisites(records: &[(&str, u16, u16, u32, &[u8])]) -> Result<(), u16> {
+        let mut encoded = Vec::new();
+        for (name, class, r#type, ttl, rdata) in records {
+            write_raw_record(&mut encoded, name, *r#type, *class, *ttl, rdata);
+        }
+        let zone = RawQuestion {
+            name: "lab.".to_string(),
+            r#type: RR_SOA,
+            class: CLASS_IN,
+        };
+        let buffer = build_message(
+            1,
+            OPCODE_UPDATE << OPCODE_SHIFT,
+            Some(&zone),
+            [(&encoded, records.len() as u16), (&[], 0), (&[], 0)],
+        );
+        let parsed = parse_records(&buffer).unwrap();
+        check_prerequisites(&buffer, &parsed, &contents(), "lab.")
+    }
+
+    #[test]
+    fn apex_soa_and_last_nameserver_are_kept() {
+        let mut zone = contents();
+        apply(
+            &mut zone,
+            Operation::DeleteRRset("lab.".into(), RR_SOA),
+            "lab.",
+        );
+        apply(
+            &mut zone,
+            Operation::DeleteRRset("lab.".into(), RR_NS),
+            "lab.",
+        );
+        apply(&mut zone, Operation::DeleteRecord(soa(1)), "lab.");
+        apply(&mut zone, Operation::DeleteRecord(ns("ns.lab.")), "lab.");
+        assert!(has(&zone, &soa(1)));
+        assert!(has(&zone, &ns("ns.lab.")));
+
+        // Everything else at the apex goes with its name
+        apply(&mut zone, Operation::DeleteName("lab.".into()), "lab.");
+        assert!(has(&zone, &soa(1)));
+        assert!(has(&zone, &ns("ns.lab.")));
+        assert!(!has(&zone, &a("lab.", [10, 0, 0, 9])));
+
+        // A nameserver can go once another one replaces it
+        apply(&mut zone, Operation::Add(ns("ns2.lab.")), "lab.");
+        apply(&mut zone, Operation::DeleteRecord(ns("ns.lab.")), "lab.");
+        assert!(!has(&zone, &ns("ns.lab.")));
+        assert!(has(&zone, &ns("ns2.lab.")));
+    }
+
+    #[test]
+    fn cnames_keep_their_names_to_themselves() {
+        let mut zone = contents();
+        apply(
+            &mut zone,
+            Operation::Add(a("alias.lab.", [10, 0, 0, 2])),
+            "lab.",
+        );
+        apply(&mut zone, Operation::Add(cname("www.lab.", "lab.")), "lab.");
+        assert_eq!(zone.len(), contents().len());
+        assert!(!has(&zone, &a("alias.lab.", [10, 0, 0, 2])));
+        assert!(!has(&zone, &cname("www.lab.", "lab.")));
+
+        // A new CNAME replaces the old one
+        apply(
+            &mut zone,
+            Operation::Add(cname("alias.lab.", "lab.")),
+            "lab.",
+        );
+        assert!(has(&zone, &cname("alias.lab.", "lab.")));
+        assert!(!has(&zone, &cname("alias.lab.", "www.lab.")));
+    }
+
+    #[test]
+    fn soa_is_only_replaced_by_a_newer_serial() {
+        let mut zone = contents();
+        apply(&mut zone, Operation::Add(soa(1)), "lab.");
+        apply(&mut zone, Operation::Add(soa(0)), "lab.");
+        assert_eq!(serial(&zone), Some(1));
+        apply(&mut zone, Operation::Add(soa(5)), "lab.");
+        assert_eq!(serial(&zone), Some(5));
+        assert_eq!(
+            zone.iter().filter(|e| type_code(e) == Some(RR_SOA)).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn serial_is_bumped_unless_already_raised() {
+        let mut zone = contents();
+        bump_serial(&mut zone, 1);
+        assert_eq!(serial(&zone), Some(2));
+
+        let mut zone = contents();
+        apply(&mut zone, Operation::Add(soa(7)), "lab.");
+        bump_serial(&mut zone, 1);
+        assert_eq!(serial(&zone), Some(7));
+
+        let mut zone = vec![soa(u32::MAX)];
+        bump_serial(&mut zone, u32::MAX);
+        assert_eq!(serial(&zone), Some(0));
+    }
+
+    #[test]
+    fn rrset_prerequisites() {
+        assert_eq!(
+            prerequisites(&[("www.lab.", CLASS_ANY, RR_A, 0, &[])]),
+            Ok(())
+        );
+        assert_eq!(
+            prerequisites(&[("www.lab.", CLASS_ANY, RR_CNAME, 0, &[])]),
+            Err(RCODE_NXRRSET)
+        );
+        assert_eq!(
+            prerequisites(&[("www.lab.", CLASS_NONE, RR_CNAME, 0, &[])]),
+            Ok(())
+        );
+        assert_eq!(
+            prerequisites(&[("www.lab.", CLASS_NONE, RR_A, 0, &[])]),
+            Err(RCODE_YXRRSET)
+        );
+    }
+
+    #[test]
+    fn name_prerequisites() {
+        assert_eq!(
+            prerequisites(&[("www.lab.", CLASS_ANY, TYPE_ANY, 0, &[])]),
+            Ok(())
+        );
+        assert_eq!(
+            prerequisites(&[("nope.lab.", CLASS_ANY, TYPE_ANY, 0, &[])]),
+            Err(RCODE_NXDOMAIN)
+        );
+        assert_eq!(
+            prerequisites(&[("nope.lab.", CLASS_NONE, TYPE_ANY, 0, &[])]),
+            Ok(())
+        );
+        assert_eq!(
+            prerequisites(&[("www.lab.", CLASS_NONE, TYPE_ANY, 0, &[])]),
+            Err(RCODE_YXDOMAIN)
+        );
+    }
+
+    #[test]
+    fn value_prerequisites_match_whole_rrsets() {
+        let www = [10, 0, 0, 1];
+        let other = [10, 0, 0, 2];
+        assert_eq!(
+            prerequisites(&[("www.lab.", CLASS_IN, RR_A, 0, &www)]),
+            Ok(())
+        );
+        assert_eq!(
+            prerequisites(&[("www.lab.", CLASS_IN, RR_A, 0, &other)]),
+            Err(RCODE_NXRRSET)
+        );
+        assert_eq!(
+            prerequisites(&[
+                ("www.lab.", CLASS_IN, RR_A, 0, &www),
+                ("www.lab.", CLASS_IN, RR_A, 0, &other),
+            ]),
+            Err(RCODE_NXRRSET)
+        );
+    }
+
+    #[test]
+    fn malformed_prerequisites() {
+        assert_eq!(
+            prerequisites(&[("www.lab.", CLASS_ANY, RR_A, 300, &[])]),
+            Err(RCODE_FORMERR)
+        );
+        assert_eq!(
+            prerequisites(&[("www.lab.", CLASS_ANY, RR_A, 0, &[10, 0, 0, 1])]),
+            Err(RCODE_FORMERR)
+        );
+        assert_eq!(
+            prerequisites(&[("www.example.", CLASS_ANY, RR_A, 0, &[])]),
+            Err(RCODE_NOTZONE)
+        );
+    }
+}
@@ -0,0 +1,890 @@
+// DNSSEC validation of upstream answers (RFC 4035 section 5). Trust starts from the configured
+// anchors and is carried down one zone cut at a time, following DS and DNSKEY records fetched
+// from the upstreams, until it reaches the zone that signed the answer.
+use data_encoding::{BASE64, HEXUPPER_PERMISSIVE};
+use std::cmp::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::debug;
+
+use crate::dnssec::{
+    canonical_cmp, has_type, nsec3_hash, owner_hash, parse_nsec, parse_nsec3, query, rrsets,
+    supported_ds, verify_rrset, DnsKey, Nsec, Nsec3, RrSet, RR_DNSKEY, RR_DS, RR_NSEC, RR_NSEC3,
+};
+use crate::jsbridge::Address;
+use crate::server::fetch_raw;
+use crate::ttldict::TTLDict;
+use crate::wire::{read_question, read_u16, Section, RR_CNAME, RR_NS, RR_SOA};
+use crate::zone::{is_in_zone, normalize};
+
+// The root zone's KSKs, as published by IANA
+const ROOT_ANCHORS: [&str; 2] = [
+    ". IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+    ". IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+];
+// NSEC3 hashing is costly - zones that ask for more iterations are treated as unsigned
+// (RFC 9276 section 3.2)
+const MAX_NSEC3_ITERATIONS: u16 = 100;
+// How long a zone's validated keys (or its lack of them) are remembered at most
+const MAX_TRUST_TTL: Duration = Duration::from_secs(3600);
+const RCODE_MASK: u16 = 0x000F;
+const RCODE_NXDOMAIN: u16 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Security {
+    // Signed, with a chain of trust from an anchor
+    Secure,
+    // Provably unsigned, or not below any anchor
+    Insecure,
+    // Should have been signed, but the signatures are missing or don't check out
+    Bogus,
+}
+
+impl Security {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Security::Secure => "secure",
+            Security::Insecure => "insecure",
+            Security::Bogus => "bogus",
+        }
+    }
+}
+
+enum Anchor {
+    Ds(Vec<u8>),
+    DnsKey(Vec<u8>),
+}
+
+#[derive(Clone)]
+enum Trust {
+    // A zone cut, with the zone's validated keys
+    Keys(Vec<DnsKey>),
+    // Not a zone cut - the keys of the zone above apply
+    NoCut,
+    // An unsigned zone, which makes everything below it unsigned as well
+    Insecure,
+}
+
+// Where following the chain of trust down to a name ended.
+enum Chain {
+    Keys { zone: String, keys: Vec<DnsKey> },
+    Insecure,
+    Bogus,
+}
+
+pub struct Validator {
+    // (owner, anchor), owners normalized
+    anchors: Vec<(String, Anchor)>,
+    // By normalized name
+    trust: Mutex<TTLDict<String, Trust>>,
+}
+
+// Parses a trust anchor given as a DS or DNSKEY record in zone file format, e.g.
+// `example. IN DS 31589 8 2 <digest>`. The TTL and class are optional.
+fn parse_anchor(text: &str) -> Result<(String, Anchor), String> {
+    let mut fields = text.split_whitespace();
+    let owner = fields
+        .next()
+        .ok_or_else(|| "empty trust anchor".to_string())?;
+    let mut fields = fields
+        .skip_while(|e| e.parse::<u32>().is_ok() || e.eq_ignore_ascii_case("IN"))
+        .collect::<Vec<_>>()
+        .into_iter();
+    let r#type = fields.next().unwrap_or_default().to_uppercase();
+    if r#type != "DS" && r#type != "DNSKEY" {
+        return Err(format!("trust anchor {:?} is neither DS nor DNSKEY", text));
+    }
+    let numbers = fields
+        .by_ref()
+        .take(3)
+        .map(|e| e.parse::<u16>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid trust anchor {:?} ({})", text, e))?;
+    if numbers.len() != 3 {
+        return Err(format!("incomplete trust anchor {:?}", text));
+    }
+    let rest: String = fields.collect();
+    // DS: key tag, algorithm, digest type. DNSKEY: flags, protocol, algorithm.
+    let mut rdata = numbers[0].to_be_bytes().to_vec();
+    rdata.extend([numbers[1] as u8, numbers[2] as u8]);
+    let anchor = match r#type.as_str() {
+        "DS" => {
+            let digest = HEXUPPER_PERMISSIVE
+                .decode(rest.as_bytes())
+                .map_err(|e| format!("invalid digest in trust anchor {:?} ({})", text, e))?;
+            rdata.extend(digest);
+            Anchor::Ds(rdata)
+        }
+        _ => {
+            let key = BASE64
+                .decode(rest.as_bytes())
+                .map_err(|e| format!("invalid key in trust anchor {:?} ({})", text, e))?;
+            rdata.extend(key);
+            Anchor::DnsKey(rdata)
+        }
+    };
+    Ok((normalize(owner), anchor))
+}
+
+// The names from right below `zone` down to `name`, top to bottom.
+fn names_below(zone: &str, name: &str) -> Vec<String> {
+    let labels: Vec<&str> = name.split('.').filter(|e| !e.is_empty()).collect();
+    let zone_labels = zone.split('.').filter(|e| !e.is_empty()).count();
+    (zone_labels + 1..=labels.len())
+        .map(|count| format!("{}.", labels[labels.len() - count..].join(".")))
+        .collect()
+}
+
+// Whether `name` sorts between an NSEC's owner and next name. The last NSEC of a zone wraps
+// around to the apex.
+fn nsec_covers(owner: &str, next: &str, name: &str) -> bool {
+    let after_owner = canonical_cmp(owner, name) == Ordering::Less;
+    match canonical_cmp(owner, next) {
+        Ordering::Less => after_owner && canonical_cmp(name, next) == Ordering::Less,
+        _ => after_owner,
+    }
+}
+
+// The same for NSEC3 hashes, which sort like bytes.
+fn nsec3_covers(owner: &[u8], next: &[u8], hash: &[u8]) -> bool {
+    match owner.cmp(next) {
+        Ordering::Less => owner < hash && hash < next,
+        _ => owner < hash || hash < next,
+    }
+}
+
+fn cache_ttl(set: &RrSet) -> Duration {
+    Duration::from_secs(set.ttl.into()).min(MAX_TRUST_TTL)
+}
+
+// `name` and the names above it, bottom to top.
+fn ancestors(name: &str) -> Vec<String> {
+    let labels: Vec<&str> = name.split('.').filter(|e| !e.is_empty()).collect();
+    (0..=labels.len())
+        .map(|skip| format!("{}.", labels[skip..].join(".")))
+        .collect()
+}
+
+fn wildcard(encloser: &str) -> String {
+    match encloser {
+        "." => "*.".to_string(),
+        _ => format!("*.{}", encloser),
+    }
+}
+
+// The NSEC and NSEC3 records of an authority section that the zone's keys signed.
+struct Proofs<'a> {
+    nsecs: Vec<(&'a str, Nsec<'a>)>,
+    // With the hash from their owner name
+    nsec3s: Vec<(Vec<u8>, Nsec3<'a>)>,
+}
+
+impl<'a> Proofs<'a> {
+    fn new(authorities: &'a [RrSet], keys: &[DnsKey], zone: &str) -> Proofs<'a> {
+        let mut proofs = Proofs {
+            nsecs: Vec::new(),
+            nsec3s: Vec::new(),
+        };
+        let signed = authorities
+            .iter()
+            .filter(|set| is_in_zone(&set.name, zone))
+            .filter(|set| verify_rrset(set, keys, zone).is_some());
+        for set in signed {
+            for rdata in &set.rdatas {
+                match set.r#type {
+                    RR_NSEC => proofs
+                        .nsecs
+                        .extend(parse_nsec(rdata).map(|e| (set.name.as_str(), e))),
+                    RR_NSEC3 => {
+                        if let (Some(nsec3), Some(owner)) =
+                            (parse_nsec3(rdata), owner_hash(&set.name))
+                        {
+                            proofs.nsec3s.push((owner, nsec3));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        proofs
+    }
+
+    // The type bitmap of the NSEC at `name`, or of the NSEC3 matching its hash.
+    fn bitmap(&self, name: &str) -> Option<&'a [u8]> {
+        let nsec = self.nsecs.iter().find(|(owner, _)| *owner == name);
+        if let Some((_, nsec)) = nsec {
+            return Some(nsec.bitmap);
+        }
+        self.nsec3s
+            .iter()
+            .find(|(owner, e)| *owner == nsec3_hash(name, e.salt, e.iterations))
+            .map(|(_, e)| e.bitmap)
+    }
+
+    fn covering_nsec3(&self, name: &str) -> Option<&Nsec3<'a>> {
+        self.nsec3s
+            .iter()
+            .find(|(owner, e)| {
+                nsec3_covers(owner, e.next_hash, &nsec3_hash(name, e.salt, e.iterations))
+            })
+            .map(|(_, e)| e)
+    }
+
+    // Whether an NSEC or NSEC3 record proves that `name` doesn't exist.
+    fn covers(&self, name: &str) -> bool {
+        self.nsecs
+            .iter()
+            .any(|(owner, e)| nsec_covers(owner, &e.next, name))
+            || self.covering_nsec3(name).is_some()
+    }
+
+    // The closest encloser of a name that doesn't exist: the closest name above it that does
+    // (RFC 4035 section 5.4, RFC 5155 section 7.2.1). With NSEC3, also the record covering the
+    // next closer name.
+    fn closest_encloser(&self, name: &str) -> Option<(String, Option<&Nsec3<'a>>)> {
+        let names = ancestors(name);
+        let covering = self
+            .nsecs
+            .iter()
+            .find(|(owner, e)| nsec_covers(owner, &e.next, name));
+        if let Some((owner, nsec)) = covering {
+            let encloser = names
+                .into_iter()
+                .skip(1)
+                .find(|e| is_in_zone(owner, e) || is_in_zone(&nsec.next, e))?;
+            return Some((encloser, None));
+        }
+        for (i, encloser) in names.iter().enumerate().skip(1) {
+            if self.bitmap(encloser).is_some() {
+                let next_closer = self.covering_nsec3(&names[i - 1])?;
+                return Some((encloser.clone(), Some(next_closer)));
+            }
+        }
+        None
+    }
+
+    // NXDOMAIN: neither the name nor the wildcard that could have answered for it exist.
+    fn name_denied(&self, name: &str) -> Security {
+        match self.closest_encloser(name) {
+            Some((encloser, _)) if self.covers(&wildcard(&encloser)) => Security::Secure,
+            _ => Security::Bogus,
+        }
+    }
+
+    // NODATA: the name, or the wildcard that answers for it, exists without the type. An
+    // opt-out NSEC3 covering the name leaves a missing DS unsigned (RFC 5155 section 8.6).
+    fn type_denied(&self, name: &str, r#type: u16) -> Security {
+        let lacks_type = |bitmap: &[u8]| !has_type(bitmap, r#type) && !has_type(bitmap, RR_CNAME);
+        if let Some(bitmap) = self.bitmap(name) {
+            return if lacks_type(bitmap) {
+                Security::Secure
+            } else {
+                Security::Bogus
+            };
+        }
+        match self.closest_encloser(name) {
+            Some((_, Some(nsec3))) if nsec3.opt_out && r#type == RR_DS => Security::Insecure,
+            Some((encloser, _)) => match self.bitmap(&wildcard(&encloser)) {
+                Some(bitmap) if lacks_type(bitmap) => Security::Secure,
+                _ => Security::Bogus,
+            },
+            None => Security::Bogus,
+        }
+    }
+}
+
+impl Validator {
+    // Without anchors, the root zone's are used.
+    pub fn new(anchors: &[String]) -> Result<Validator, String> {
+        let texts: Vec<&str> = match anchors.is_empty() {
+            true => ROOT_ANCHORS.to_vec(),
+            false => anchors.iter().map(String::as_str).collect(),
+        };
+        let anchors = texts
+            .iter()
+            .map(|e| parse_anchor(e))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Validator {
+            anchors,
+            trust: Mutex::new(TTLDict::new()),
+        })
+    }
+
+    // Validates the answer section of a response. The answer is as secure as its least secure
+    // RRset. Negative answers are validated by their proofs instead.
+    pub async fn validate(&self, response: &[u8], upstreams: &[Address]) -> Security {
+        let answers = match rrsets(response, Section::Answer) {
+            Some(e) => e,
+            None => return Security::Bogus,
+        };
+        let authorities = rrsets(response, Section::Authority).unwrap_or_default();
+        if answers.is_empty() {
+            let result = self
+                .validate_denial(response, &authorities, upstreams)
+                .await;
+            debug!(target: "dnssec", result = result.name(), "Validated negative answer");
+            return result;
+        }
+        let mut security = Security::Secure;
+        for set in &answers {
+            let result = self.validate_rrset(set, &authorities, upstreams).await;
+            debug!(target: "dnssec", name = %set.name, rrtype = set.r#type, result = result.name(), "Validated RRset");
+            security = security.max(result);
+        }
+        security
+    }
+
+    async fn validate_rrset(
+        &self,
+        set: &RrSet,
+        authorities: &[RrSet],
+        upstreams: &[Address],
+    ) -> Security {
+        // The zone whose keys should have signed the RRset: the signer it names, which has to
+        // be above it, or for unsigned RRsets the zone it's in
+        let signer = set
+            .signatures
+            .iter()
+            .map(|e| e.signer.as_str())
+            .find(|signer| is_in_zone(&set.name, signer));
+        let (zone, keys) = match self.chain(signer.unwrap_or(&set.name), upstreams).await {
+            Chain::Keys { zone, keys } => (zone, keys),
+            Chain::Insecure => return Security::Insecure,
+            Chain::Bogus => return Security::Bogus,
+        };
+        let signature = match verify_rrset(set, &keys, &zone) {
+            Some(e) => e,
+            None => return Security::Bogus,
+        };
+        // Answers synthesized from a wildcard need proof that the name itself doesn't exist
+        // (RFC 4035 section 5.3.4)
+        let labels: Vec<&str> = set.name.split('.').filter(|e| !e.is_empty()).collect();
+        if (signature.labels as usize) < labels.len() {
+            let next_closer = labels[labels.len() - signature.labels as usize - 1..].join(".");
+            if !self.denied(
+                &set.name,
+                &format!("{}.", next_closer),
+                authorities,
+                &keys,
+                &zone,
+            ) {
+                return Security::Bogus;
+            }
+        }
+        Security::Secure
+    }
+
+    // Validates a negative answer: the zone's keys have to have signed its SOA, and its NSEC or
+    // NSEC3 records have to prove that the name (NXDOMAIN) or the type (NODATA) doesn't exist.
+    async fn validate_denial(
+        &self,
+        response: &[u8],
+        authorities: &[RrSet],
+        upstreams: &[Address],
+    ) -> Security {
+        let (question, flags) = match (read_question(response), read_u16(response, 2)) {
+            (Some(question), Some(flags)) => (question, flags),
+            _ => return Security::Bogus,
+        };
+        let name = normalize(&question.name);
+        // The zone that signed the proofs, or for unsigned ones the zone of the SOA
+        let signer = authorities
+            .iter()
+            .flat_map(|set| &set.signatures)
+            .map(|e| e.signer.as_str())
+            .find(|signer| is_in_zone(&name, signer));
+        let soa_owner = authorities
+            .iter()
+            .find(|set| set.r#type == RR_SOA)
+            .map(|set| set.name.as_str());
+        let (zone, keys) = match self
+            .chain(signer.or(soa_owner).unwrap_or(&name), upstreams)
+            .await
+        {
+            Chain::Keys { zone, keys } => (zone, keys),
+            Chain::Insecure => return Security::Insecure,
+            Chain::Bogus => return Security::Bogus,
+        };
+        let soa = authorities
+            .iter()
+            .find(|set| set.r#type == RR_SOA && set.name == zone);
+        if soa.is_none_or(|soa| verify_rrset(soa, &keys, &zone).is_none()) {
+            return Security::Bogus;
+        }
+        let proofs = Proofs::new(authorities, &keys, &zone);
+        if proofs
+            .nsec3s
+            .iter()
+            .any(|(_, e)| e.iterations > MAX_NSEC3_ITERATIONS)
+        {
+            return Security::Insecure;
+        }
+        match flags & RCODE_MASK {
+            RCODE_NXDOMAIN => proofs.name_denied(&name),
+            _ => proofs.type_denied(&name, question.r#type),
+        }
+    }
+
+    // Whether the authority section proves that `name` doesn't exist, with NSEC covering it or
+    // NSEC3 covering the next closer name.
+    fn denied(
+        &self,
+        name: &str,
+        next_closer: &str,
+        authorities: &[RrSet],
+        keys: &[DnsKey],
+        zone: &str,
+    ) -> bool {
+        let proofs = authorities
+            .iter()
+            .filter(|set| is_in_zone(&set.name, zone))
+            .filter(|set| verify_rrset(set, keys, zone).is_some());
+        for set in proofs {
+            for rdata in &set.rdatas {
+                match set.r#type {
+                    RR_NSEC => match parse_nsec(rdata) {
+                        Some(nsec) if nsec_covers(&set.name, &nsec.next, name) => return true,
+                        _ => {}
+                    },
+                    RR_NSEC3 => {
+                        let (nsec3, owner) = match (parse_nsec3(rdata), owner_hash(&set.name)) {
+                            (Some(nsec3), Some(owner)) => (nsec3, owner),
+                            _ => continue,
+                        };
+                        let hash = nsec3_hash(next_closer, nsec3.salt, nsec3.iterations);
+                        if nsec3_covers(&owner, nsec3.next_hash, &hash) {
+                            return true;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        false
+    }
+
+    fn cached(&self, name: &str) -> Option<Trust> {
+        self.trust.lock().unwrap().get(&name.to_string()).cloned()
+    }
+
+    // Follows the chain of trust from the closest anchor down to the zone `name` is in.
+    async fn chain(&self, name: &str, upstreams: &[Address]) -> Chain {
+        let name = normalize(name);
+        let closest = self
+            .anchors
+            .iter()
+            .map(|(owner, _)| owner)
+            .filter(|owner| is_in_zone(&name, owner))
+            .max_by_key(|owner| owner.len());
+        let mut zone = match closest {
+            Some(e) => e.clone(),
+            None => return Chain::Insecure,
+        };
+        let mut keys = match self.cached(&zone) {
+            Some(Trust::Keys(keys)) => keys,
+            _ => match self.anchor_keys(&zone, upstreams).await {
+                Some(e) => e,
+                None => return Chain::Bogus,
+            },
+        };
+        for child in names_below(&zone, &name) {
+            let (trust, ttl) = match self.cached(&child) {
+                Some(trust) => (trust, None),
+                None => match self.descend(&child, &zone, &keys, upstreams).await {
+                    Some((trust, ttl)) => (trust, Some(ttl)),
+                    None => return Chain::Bogus,
+                },
+            };
+            if let Some(ttl) = ttl {
+                self.trust
+                    .lock()
+                    .unwrap()
+                    .set(child.clone(), trust.clone(), ttl);
+            }
+            match trust {
+                Trust::Keys(child_keys) => {
+                    zone = child;
+                    keys = child_keys;
+                }
+                Trust::NoCut => {}
+                Trust::Insecure => return Chain::Insecure,
+            }
+        }
+        Chain::Keys { zone, keys }
+    }
+
+    // Validates the keys of an anchored zone.
+    async fn anchor_keys(&self, zone: &str, upstreams: &[Address]) -> Option<Vec<DnsKey>> {
+        let anchors: Vec<&Anchor> = self
+            .anchors
+            .iter()
+            .filter(|(owner, _)| owner == zone)
+            .map(|(_, anchor)| anchor)
+            .collect();
+        let trusted = |key: &DnsKey| {
+            anchors.iter().any(|anchor| match anchor {
+                Anchor::Ds(ds) => key.matches_ds(zone, ds),
+                Anchor::DnsKey(rdata) => key.same_as(rdata),
+            })
+        };
+        let (keys, ttl) = self.zone_keys(zone, trusted, upstreams).await?;
+        self.trust
+            .lock()
+            .unwrap()
+            .set(zone.to_string(), Trust::Keys(keys.clone()), ttl);
+        Some(keys)
+    }
+
+    // Fetches a zone's DNSKEY RRset, which one of the keys `trusted` accepts has to have
+    // signed.
+    async fn zone_keys(
+        &self,
+        zone: &str,
+        trusted: impl Fn(&DnsKey) -> bool,
+        upstreams: &[Address],
+    ) -> Option<(Vec<DnsKey>, Duration)> {
+        let response = fetch_raw(&query(zone, RR_DNSKEY), upstreams).await?;
+        let answers = rrsets(&response, Section::Answer)?;
+        let set = answers
+            .iter()
+            .find(|e| e.name == zone && e.r#type == RR_DNSKEY)?;
+        let keys: Vec<DnsKey> = set.rdatas.iter().filter_map(|e| DnsKey::parse(e)).collect();
+        let entry_points: Vec<DnsKey> = keys.iter().filter(|e| trusted(e)).cloned().collect();
+        verify_rrset(set, &entry_points, zone)?;
+        Some((keys, cache_ttl(set)))
+    }
+
+    // Finds out whether `child` is the apex of a zone below `zone`, and whether that zone is
+    // signed. None when the answers don't add up.
+    async fn descend(
+        &self,
+        child: &str,
+        zone: &str,
+        keys: &[DnsKey],
+        upstreams: &[Address],
+    ) -> Option<(Trust, Duration)> {
+        let response = fetch_raw(&query(child, RR_DS), upstreams).await?;
+        let answers = rrsets(&response, Section::Answer)?;
+        if let Some(ds) = answers
+            .iter()
+            .find(|e| e.name == child && e.r#type == RR_DS)
+        {
+            verify_rrset(ds, keys, zone)?;
+            // Zones signed with algorithms we don't know are as good as unsigned
+            let supported: Vec<&Vec<u8>> = ds.rdatas.iter().filter(|e| supported_ds(e)).collect();
+            if supported.is_empty() {
+                return Some((Trust::Insecure, cache_ttl(ds)));
+            }
+            let trusted = |key: &DnsKey| supported.iter().any(|ds| key.matches_ds(child, ds));
+            let (child_keys, ttl) = self.zone_keys(child, trusted, upstreams).await?;
+            return Some((Trust::Keys(child_keys), ttl.min(cache_ttl(ds))));
+        }
+        // A CNAME can't live at a zone cut
+        if let Some(cname) = answers
+            .iter()
+            .find(|e| e.name == child && e.r#type == RR_CNAME)
+        {
+            verify_rrset(cname, keys, zone)?;
+            return Some((Trust::NoCut, cache_ttl(cname)));
+        }
+        // Without a DS, the parent zone has to prove there isn't one (RFC 4035 section 5.2)
+        let authorities = rrsets(&response, Section::Authority)?;
+        let proofs = authorities
+            .iter()
+            .filter(|set| is_in_zone(&set.name, zone))
+            .filter(|set| verify_rrset(set, keys, zone).is_some());
+        for set in proofs {
+            let ttl = cache_ttl(set);
+            for rdata in &set.rdatas {
+                match set.r#type {
+                    RR_NSEC => {
+                        let nsec = match parse_nsec(rdata) {
+                            Some(e) => e,
+                            None => continue,
+                        };
+                        if set.name == child {
+                            return delegation(nsec.bitmap).map(|e| (e, ttl));
+                        }
+                        // The name doesn't exist, or only has names below it
+                        if nsec_covers(&set.name, &nsec.next, child) {
+                            return Some((Trust::NoCut, ttl));
+                        }
+                    }
+                    RR_NSEC3 => {
+                        let (nsec3, owner) = match (parse_nsec3(rdata), owner_hash(&set.name)) {
+                            (Some(nsec3), Some(owner)) => (nsec3, owner),
+                            _ => continue,
+                        };
+                        if nsec3.iterations > MAX_NSEC3_ITERATIONS {
+                            return Some((Trust::Insecure, ttl));
+                        }
+                        let hash = nsec3_hash(child, nsec3.salt, nsec3.iterations);
+                        if hash == owner {
+                            return delegation(nsec3.bitmap).map(|e| (e, ttl));
+                        }
+                        // Opt-out spans may hide unsigned delegations (RFC 5155 section 6)
+                        if nsec3_covers(&owner, nsec3.next_hash, &hash) {
+                            let trust = match nsec3.opt_out {
+                                true => Trust::Insecure,
+                                false => Trust::NoCut,
+                            };
+                            return Some((trust, ttl));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        None
+    }
+}
+
+// What the type bitmap of the NSEC/NSEC3 record at a name without DS says about it: with NS
+// but without SOA, it's an unsigned delegation.
+fn delegation(bitmap: &[u8]) -> Option<Trust> {
+    if has_type(bitmap, RR_DS) || has_type(bitmap, RR_SOA) {
+        return None;
+    }
+    match has_type(bitmap, RR_NS) {
+        true => Some(Trust::Insecure),
+        false => Some(Trust::NoCut),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dnssec::{
+        name_to_wire, nsec3_owner, type_bitmap, SigningKey, ALGORITHM_ECDSAP256SHA256, FLAGS_KSK,
+        RR_RRSIG,
+    };
+    use crate::wire::{build_message, RawQuestion, FLAG_QR};
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::net::UdpSocket;
+
+    const RR_A: u16 = 1;
+    const RR_AAAA: u16 = 28;
+    const RCODE_REFUSED: u16 = 5;
+
+    // An RRset with the data of its RRSIG, if it has one.
+    type Signed = (RrSet, Option<Vec<u8>>);
+
+    fn a(name: &str, ip: [u8; 4]) -> RrSet {
+        RrSet::new(name, RR_A, 300, vec![ip.to_vec()])
+    }
+
+    fn soa() -> RrSet {
+        let mut rdata = name_to_wire("ns.lab.");
+        rdata.extend(name_to_wire("admin.lab."));
+        for value in [1u32, 3600, 600, 86400, 300] {
+            rdata.extend(value.to_be_bytes());
+        }
+        RrSet::new("lab.", RR_SOA, 300, vec![rdata])
+    }
+
+    fn nsec(name: &str, next: &str, types: &[u16]) -> RrSet {
+        let mut rdata = name_to_wire(next);
+        rdata.extend(type_bitmap(types));
+        RrSet::new(name, RR_NSEC, 300, vec![rdata])
+    }
+
+    // An NSEC3 record matching only `name`, without salt or extra iterations.
+    fn nsec3(name: &str, types: &[u16]) -> RrSet {
+        let hash = nsec3_hash(name, &[], 0);
+        let mut next = hash.clone();
+        *next.last_mut().unwrap() += 1;
+        let mut rdata = vec![1, 0, 0, 0, 0, hash.len() as u8];
+        rdata.extend(next);
+        rdata.extend(type_bitmap(types));
+        RrSet::new(&nsec3_owner(&hash, "lab."), RR_NSEC3, 300, vec![rdata])
+    }
+
+    fn message(id: u16, name: &str, r#type: u16, rcode: u16, sections: [&[Signed]; 2]) -> Vec<u8> {
+        let mut encoded = [(Vec::new(), 0), (Vec::new(), 0)];
+        for ((output, count), sets) in encoded.iter_mut().zip(sections) {
+            for (set, signature) in sets {
+                *count += set.write(output);
+                if let Some(signature) = signature {
+                    set.write_signature(output, signature);
+                    *count += 1;
+                }
+            }
+        }
+        let question = RawQuestion {
+            name: name.to_string(),
+            r#type,
+            class: 1,
+        };
+        build_message(
+            id,
+            FLAG_QR | rcode,
+            Some(&question),
+            [
+                (&encoded[0].0, encoded[0].1),
+                (&encoded[1].0, encoded[1].1),
+                (&[], 0),
+            ],
+        )
+    }
+
+    // The signed `lab.` zone, with a wildcard at `*.wild.lab.` and an unsigned delegation to
+    // `unsigned.lab.`.
+    struct TestZone {
+        key: SigningKey,
+    }
+
+    impl TestZone {
+        fn new() -> TestZone {
+            let document = SigningKey::generate(ALGORITHM_ECDSAP256SHA256).unwrap();
+            TestZone {
+                key: SigningKey::from_pkcs8(&document, FLAGS_KSK).unwrap(),
+            }
+        }
+
+        fn signed(&self, set: RrSet) -> Signed {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32;
+            let signature = self.key.sign(&set, "lab.", now - 3600, now + 3600);
+            (set, signature)
+        }
+
+        // What the zone's servers answer while the validator follows the chain of trust.
+        fn answer(&self, id: u16, name: &str, r#type: u16) -> Vec<u8> {
+            match (normalize(name).as_str(), r#type) {
+                ("lab.", RR_DNSKEY) => {
+                    let keys = RrSet::new("lab.", RR_DNSKEY, 3600, vec![self.key.dnskey.clone()]);
+                    message(id, name, r#type, 0, [&[self.signed(keys)], &[]])
+                }
+                ("unsigned.lab.", RR_DS) => {
+                    let proof = nsec("unsigned.lab.", "wild.lab.", &[RR_NS, RR_RRSIG, RR_NSEC]);
+                    let authorities = [self.signed(soa()), self.signed(proof)];
+                    message(id, name, r#type, 0, [&[], &authorities])
+                }
+                _ => message(id, name, r#type, RCODE_REFUSED, [&[], &[]]),
+            }
+        }
+    }
+
+    // Validates a response against the zone, served by a local upstream and anchored with the
+    // zone's DS.
+    async fn validate(zone: TestZone, response: &[u8]) -> Security {
+        let anchor = zone.key.ds("lab.");
+        let zone = Arc::new(zone);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; 512];
+            while let Ok((length, peer)) = socket.recv_from(&mut buffer).await {
+                let query = &buffer[..length];
+                let (question, id) = match (read_question(query), read_u16(query, 0)) {
+                    (Some(question), Some(id)) => (question, id),
+                    _ => continue,
+                };
+                let response = zone.answer(id, &question.name, question.r#type);
+                let _ = socket.send_to(&response, peer).await;
+            }
+        });
+        let upstream = Address {
+            address: "127.0.0.1".to_string(),
+            port,
+            tsig_key: None,
+        };
+        let validator = Validator::new(&[anchor]).unwrap();
+        validator.validate(response, &[upstream]).await
+    }
+
+    #[tokio::test]
+    async fn signed_answer_is_secure() {
+        let zone = TestZone::new();
+        let answer = zone.signed(a("www.lab.", [10, 0, 0, 1]));
+        let response = message(1, "www.lab.", RR_A, 0, [&[answer], &[]]);
+        assert_eq!(validate(zone, &response).await, Security::Secure);
+    }
+
+    #[tokio::test]
+    async fn tampered_signature_is_bogus() {
+        let zone = TestZone::new();
+        let (set, signature) = zone.signed(a("www.lab.", [10, 0, 0, 1]));
+        let mut signature = signature.unwrap();
+        *signature.last_mut().unwrap() ^= 0xFF;
+        let response = message(1, "www.lab.", RR_A, 0, [&[(set, Some(signature))], &[]]);
+        assert_eq!(validate(zone, &response).await, Security::Bogus);
+    }
+
+    #[tokio::test]
+    async fn missing_signature_is_bogus() {
+        let zone = TestZone::new();
+        let response = message(
+            1,
+            "www.lab.",
+            RR_A,
+            0,
+            [&[(a("www.lab.", [6, 6, 6, 6]), None)], &[]],
+        );
+        assert_eq!(validate(zone, &response).await, Security::Bogus);
+    }
+
+    #[tokio::test]
+    async fn unsigned_delegation_is_insecure() {
+        let zone = TestZone::new();
+        let answer = (a("host.unsigned.lab.", [10, 0, 0, 3]), None);
+        let response = message(1, "host.unsigned.lab.", RR_A, 0, [&[answer], &[]]);
+        assert_eq!(validate(zone, &response).await, Security::Insecure);
+    }
+
+    #[tokio::test]
+    async fn wildcard_answer_needs_a_denial() {
+        // The answer is synthesized from *.wild.lab., whose signature carries one label less
+        let answer = |zone: &TestZone| {
+            let (_, signature) = zone.signed(a("*.wild.lab.", [10, 0, 0, 2]));
+            (a("foo.wild.lab.", [10, 0, 0, 2]), signature)
+        };
+        let zone = TestZone::new();
+        let proof = zone.signed(nsec("*.wild.lab.", "www.lab.", &[RR_A, RR_RRSIG, RR_NSEC]));
+        let response = message(1, "foo.wild.lab.", RR_A, 0, [&[answer(&zone)], &[proof]]);
+        assert_eq!(validate(zone, &response).await, Security::Secure);
+
+        let zone = TestZone::new();
+        let response = message(1, "foo.wild.lab.", RR_A, 0, [&[answer(&zone)], &[]]);
+        assert_eq!(validate(zone, &response).await, Security::Bogus);
+    }
+
+    #[tokio::test]
+    async fn nxdomain_needs_a_denial() {
+        let zone = TestZone::new();
+        let proof = zone.signed(nsec("lab.", "unsigned.lab.", &[RR_SOA, RR_RRSIG, RR_NSEC]));
+        let authorities = [zone.signed(soa()), proof];
+        let response = message(1, "nothere.lab.", RR_A, RCODE_NXDOMAIN, [&[], &authorities]);
+        assert_eq!(validate(zone, &response).await, Security::Secure);
+
+        let zone = TestZone::new();
+        let authorities = [zone.signed(soa())];
+        let response = message(1, "www.lab.", RR_A, RCODE_NXDOMAIN, [&[], &authorities]);
+        assert_eq!(validate(zone, &response).await, Security::Bogus);
+    }
+
+    #[tokio::test]
+    async fn nodata_needs_a_denial_of_the_type() {
+        let zone = TestZone::new();
+        let proof = zone.signed(nsec("www.lab.", "lab.", &[RR_A, RR_RRSIG, RR_NSEC]));
+        let authorities = [zone.signed(soa()), proof];
+        let response = message(1, "www.lab.", RR_AAAA, 0, [&[], &authorities]);
+        assert_eq!(validate(zone, &response).await, Security::Secure);
+
+        // The same proof can't deny the A record it lists
+        let zone = TestZone::new();
+        let proof = zone.signed(nsec("www.lab.", "lab.", &[RR_A, RR_RRSIG, RR_NSEC]));
+        let authorities = [zone.signed(soa()), proof];
+        let response = message(1, "www.lab.", RR_A, 0, [&[], &authorities]);
+        assert_eq!(validate(zone, &response).await, Security::Bogus);
+
+        let zone = TestZone::new();
+        let proof = zone.signed(nsec3("www.lab.", &[RR_A, RR_RRSIG]));
+        let authorities = [zone.signed(soa()), proof];
+        let response = message(1, "www.lab.", RR_AAAA, 0, [&[], &authorities]);
+        assert_eq!(validate(zone, &response).await, Security::Secure);
+    }
+}
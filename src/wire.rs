@@ -0,0 +1,442 @@
+// Minimal reader and writer for the parts of DNS wire messages rustdns doesn't handle (EDNS
+// options, zone transfers, ...). Names are written uncompressed.
+use rustdns::{Class, Record, Resource, MX, SOA, SRV, TXT};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+pub struct RawRecord<'a> {
+    pub section: Section,
+    // Offset of the record's first byte (its owner name) within the message.
+    pub start: usize,
+    pub r#type: u16,
+    pub class: u16,
+    pub ttl: u32,
+    // Offset of the record's data within the message, needed to decompress names in it.
+    pub rdata_start: usize,
+    pub rdata: &'a [u8],
+}
+
+pub struct RawQuestion {
+    pub name: String,
+    pub r#type: u16,
+    pub class: u16,
+}
+
+pub const HEADER_LENGTH: usize = 12;
+pub const RR_NS: u16 = 2;
+pub const RR_CNAME: u16 = 5;
+pub const RR_SOA: u16 = 6;
+pub const RR_OPT: u16 = 41;
+pub const EDNS_CLIENT_SUBNET: u16 = 8;
+
+pub fn read_u16(buffer: &[u8], position: usize) -> Option<u16> {
+    let bytes = buffer.get(position..position + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+pub fn read_u32(buffer: &[u8], position: usize) -> Option<u32> {
+    let bytes = buffer.get(position..position + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// Returns the offset right after the name starting at `position`.
+pub fn skip_name(buffer: &[u8], mut position: usize) -> Option<usize> {
+    loop {
+        let length = *buffer.get(position)?;
+        match length & 0xC0 {
+            0x00 if length == 0 => return Some(position + 1),
+            0x00 => position += 1 + length as usize,
+            // Compression pointer - the name ends here as far as this message is concerned.
+            0xC0 => return Some(position + 2),
+            _ => return None,
+        }
+    }
+}
+
+// Reads the name starting at `position`, following compression pointers. Returns the name with
+// a trailing dot, and the offset right after it.
+pub fn read_name(buffer: &[u8], position: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut current = position;
+    let mut end = None;
+    // Bounds the number of pointers followed, so that pointer loops can't hang
+    for _ in 0..128 {
+        let length = *buffer.get(current)? as usize;
+        match length & 0xC0 {
+            0x00 if length == 0 => {
+                if name.is_empty() {
+                    name.push('.');
+                }
+                return Some((name, end.unwrap_or(current + 1)));
+            }
+            0x00 => {
+                let label = buffer.get(current + 1..current + 1 + length)?;
+                name.push_str(&String::from_utf8_lossy(label));
+                name.push('.');
+                current += 1 + length;
+            }
+            0xC0 => {
+                let pointer = (read_u16(buffer, current)? & 0x3FFF) as usize;
+                end.get_or_insert(current + 2);
+                current = pointer;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+// Returns the message's first question.
+pub fn read_question(buffer: &[u8]) -> Option<RawQuestion> {
+    if read_u16(buffer, 4)? == 0 {
+        return None;
+    }
+    let (name, position) = read_name(buffer, HEADER_LENGTH)?;
+    Some(RawQuestion {
+        name,
+        r#type: read_u16(buffer, position)?,
+        class: read_u16(buffer, position + 2)?,
+    })
+}
+
+// Returns every resource record in the message, or None if the message is malformed.
+pub fn parse_records(buffer: &[u8]) -> Option<Vec<RawRecord<'_>>> {
+    let question_count = read_u16(buffer, 4)?;
+    let counts = [
+        (Section::Answer, read_u16(buffer, 6)?),
+        (Section::Authority, read_u16(buffer, 8)?),
+        (Section::Additional, read_u16(buffer, 10)?),
+    ];
+
+    let mut position = HEADER_LENGTH;
+    for _ in 0..question_count {
+        position = skip_name(buffer, position)? + 4;
+    }
+
+    let mut records = Vec::new();
+    for (section, count) in counts {
+        for _ in 0..count {
+            let start = position;
+            position = skip_name(buffer, position)?;
+            let r#type = read_u16(buffer, position)?;
+            let class = read_u16(buffer, position + 2)?;
+            let ttl = read_u32(buffer, position + 4)?;
+            let rdata_length = read_u16(buffer, position + 8)? as usize;
+            position += 10;
+            let rdata = buffer.get(position..position + rdata_length)?;
+            position += rdata_length;
+            records.push(RawRecord {
+                section,
+                start,
+                r#type,
+                class,
+                ttl,
+                rdata_start: position - rdata_length,
+                rdata,
+            });
+        }
+    }
+    Some(records)
+}
+
+// Returns the data of the first EDNS option with the given code.
+pub fn find_edns_option(buffer: &[u8], code: u16) -> Option<&[u8]> {
+    let records = parse_records(buffer)?;
+    let opt = records
+        .iter()
+        .find(|record| record.section == Section::Additional && record.r#type == RR_OPT)?;
+    let mut position = 0;
+    while position + 4 <= opt.rdata.len() {
+        let option_code = read_u16(opt.rdata, position)?;
+        let option_length = read_u16(opt.rdata, position + 2)? as usize;
+        let data = opt.rdata.get(position + 4..position + 4 + option_length)?;
+        if option_code == code {
+            return Some(data);
+        }
+        position += 4 + option_length;
+    }
+    None
+}
+
+// Formats the EDNS client subnet option (RFC 7871) as `address/prefix`.
+pub fn client_subnet(buffer: &[u8]) -> Option<String> {
+    let data = find_edns_option(buffer, EDNS_CLIENT_SUBNET)?;
+    let family = read_u16(data, 0)?;
+    let source_prefix = *data.get(2)?;
+    let address = data.get(4..)?;
+    let formatted = match family {
+        1 => {
+            let mut octets = [0u8; 4];
+            octets[..address.len().min(4)].copy_from_slice(&address[..address.len().min(4)]);
+            std::net::Ipv4Addr::from(octets).to_string()
+        }
+        2 => {
+            let mut octets = [0u8; 16];
+            octets[..address.len().min(16)].copy_from_slice(&address[..address.len().min(16)]);
+            std::net::Ipv6Addr::from(octets).to_string()
+        }
+        _ => return None,
+    };
+    Some(format!("{}/{}", formatted, source_prefix))
+}
+
+pub fn write_name(output: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|e| !e.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        output.push(label.len() as u8);
+        output.extend_from_slice(label);
+    }
+    output.push(0);
+}
+
+fn write_seconds(output: &mut Vec<u8>, duration: Duration) {
+    output.extend_from_slice(&(duration.as_secs() as u32).to_be_bytes());
+}
+
+// Returns the record's type and data, or None for types that can't be encoded.
+pub fn encode_rdata(resource: &Resource) -> Option<(u16, Vec<u8>)> {
+    let mut data = Vec::new();
+    let r#type = match resource {
+        Resource::A(ip) => {
+            data.extend_from_slice(&ip.octets());
+            1
+        }
+        Resource::NS(name) => {
+            write_name(&mut data, name);
+            2
+        }
+        Resource::CNAME(name) => {
+            write_name(&mut data, name);
+            5
+        }
+        Resource::SOA(soa) => {
+            write_name(&mut data, &soa.mname);
+            write_name(&mut data, &soa.rname);
+            data.extend_from_slice(&soa.serial.to_be_bytes());
+            write_seconds(&mut data, soa.refresh);
+            write_seconds(&mut data, soa.retry);
+            write_seconds(&mut data, soa.expire);
+            write_seconds(&mut data, soa.minimum);
+            RR_SOA
+        }
+        Resource::PTR(name) => {
+            write_name(&mut data, name);
+            12
+        }
+        Resource::MX(mx) => {
+            data.extend_from_slice(&mx.preference.to_be_bytes());
+            write_name(&mut data, &mx.exchange);
+            15
+        }
+        Resource::TXT(txt) => {
+            for string in &txt.0 {
+                for chunk in string.chunks(255) {
+                    data.push(chunk.len() as u8);
+                    data.extend_from_slice(chunk);
+                }
+            }
+            16
+        }
+        Resource::AAAA(ip) => {
+            data.extend_from_slice(&ip.octets());
+            28
+        }
+        Resource::SRV(srv) => {
+            data.extend_from_slice(&srv.priority.to_be_bytes());
+            data.extend_from_slice(&srv.weight.to_be_bytes());
+            data.extend_from_slice(&srv.port.to_be_bytes());
+            write_name(&mut data, &srv.name);
+            33
+        }
+        _ => return None,
+    };
+    Some((r#type, data))
+}
+
+pub fn write_raw_record(
+    output: &mut Vec<u8>,
+    name: &str,
+    r#type: u16,
+    class: u16,
+    ttl: u32,
+    rdata: &[u8],
+) {
+    write_name(output, name);
+    output.extend_from_slice(&r#type.to_be_bytes());
+    output.extend_from_slice(&class.to_be_bytes());
+    output.extend_from_slice(&ttl.to_be_bytes());
+    output.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    output.extend_from_slice(rdata);
+}
+
+pub fn write_record(output: &mut Vec<u8>, record: &Record) -> Option<()> {
+    let (r#type, rdata) = encode_rdata(&record.resource)?;
+    let ttl = record.ttl.as_secs() as u32;
+    write_raw_record(
+        output,
+        &record.name,
+        r#type,
+        record.class as u16,
+        ttl,
+        &rdata,
+    );
+    Some(())
+}
+
+// Decodes a record read by parse_records. None for types rustdns can't represent.
+pub fn decode_record(buffer: &[u8], raw: &RawRecord) -> Option<Record> {
+    let (name, _) = read_name(buffer, raw.start)?;
+    let data = raw.rdata;
+    let name_at = |offset: usize| read_name(buffer, raw.rdata_start + offset);
+    let seconds = |offset: usize| read_u32(data, offset).map(|e| Duration::from_secs(e.into()));
+    let resource = match raw.r#type {
+        1 => Resource::A(Ipv4Addr::from(<[u8; 4]>::try_from(data).ok()?)),
+        2 => Resource::NS(name_at(0)?.0),
+        5 => Resource::CNAME(name_at(0)?.0),
+        RR_SOA => {
+            let (mname, after_mname) = name_at(0)?;
+            let (rname, after_rname) = read_name(buffer, after_mname)?;
+            let offset = after_rname - raw.rdata_start;
+            Resource::SOA(SOA {
+                mname,
+                rname,
+                serial: read_u32(data, offset)?,
+                refresh: seconds(offset + 4)?,
+                retry: seconds(offset + 8)?,
+                expire: seconds(offset + 12)?,
+                minimum: seconds(offset + 16)?,
+            })
+        }
+        12 => Resource::PTR(name_at(0)?.0),
+        15 => Resource::MX(MX {
+            preference: read_u16(data, 0)?,
+            exchange: name_at(2)?.0,
+        }),
+        16 => {
+            let mut strings = Vec::new();
+            let mut position = 0;
+            while position < data.len() {
+                let length = data[position] as usize;
+                strings.push(data.get(position + 1..position + 1 + length)?.to_vec());
+                position += 1 + length;
+            }
+            Resource::TXT(TXT(strings))
+        }
+        28 => Resource::AAAA(Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?)),
+        33 => Resource::SRV(SRV {
+            priority: read_u16(data, 0)?,
+            weight: read_u16(data, 2)?,
+            port: read_u16(data, 4)?,
+            name: name_at(6)?.0,
+        }),
+        _ => return None,
+    };
+    Some(Record {
+        name,
+        class: if raw.class == 1 {
+            Class::Internet
+        } else {
+            return None;
+        },
+        ttl: Duration::from_secs(raw.ttl.into()),
+        resource,
+    })
+}
+
+pub const FLAG_QR: u16 = 0x8000;
+pub const FLAG_AA: u16 = 0x0400;
+pub const FLAG_TC: u16 = 0x0200;
+pub const FLAG_RD: u16 = 0x0100;
+pub const FLAG_CD: u16 = 0x0010;
+pub const OPCODE_SHIFT: u16 = 11;
+pub const OPCODE_QUERY: u16 = 0;
+
+// Header flags of a response to the given message: QR, plus its opcode and RD flag.
+pub fn response_flags(buffer: &[u8]) -> u16 {
+    let flags = read_u16(buffer, 2).unwrap_or(0);
+    FLAG_QR | (flags & (0xF << OPCODE_SHIFT)) | (flags & FLAG_RD)
+}
+
+// The largest UDP response the sender of a query accepts: the payload size of its OPT record, or
+// 512 bytes without EDNS (RFC 6891 section 6.2.5).
+pub fn udp_payload_size(query: &[u8]) -> usize {
+    let opt = parse_records(query).and_then(|records| {
+        records
+            .iter()
+            .find(|e| e.section == Section::Additional && e.r#type == RR_OPT)
+            .map(|e| e.class)
+    });
+    opt.map_or(512, |size| size.max(512) as usize)
+}
+
+// The length prefix of a message sent over TCP (RFC 1035 section 4.2.2). Longer messages than
+// it can describe are an error, rather than a stream the other side can't follow.
+pub fn tcp_length(message: &[u8]) -> std::io::Result<u16> {
+    u16::try_from(message.len()).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("a {} byte message doesn't fit TCP framing", message.len()),
+        )
+    })
+}
+
+// Cuts a response down to its header, question and OPT record, with TC set so the client
+// retries over TCP.
+pub fn truncate(response: &[u8]) -> Option<Vec<u8>> {
+    let id = read_u16(response, 0)?;
+    let flags = read_u16(response, 2)? | FLAG_TC;
+    let mut opt = Vec::new();
+    let mut opt_count = 0;
+    for record in parse_records(response)?
+        .iter()
+        .filter(|e| e.section == Section::Additional && e.r#type == RR_OPT)
+    {
+        write_raw_record(
+            &mut opt,
+            ".",
+            RR_OPT,
+            record.class,
+            record.ttl,
+            record.rdata,
+        );
+        opt_count += 1;
+    }
+    Some(build_message(
+        id,
+        flags,
+        read_question(response).as_ref(),
+        [(&[], 0), (&[], 0), (&opt, opt_count)],
+    ))
+}
+
+// Builds a message from raw parts. The records in each section must already be encoded.
+pub fn build_message(
+    id: u16,
+    flags: u16,
+    question: Option<&RawQuestion>,
+    sections: [(&[u8], u16); 3],
+) -> Vec<u8> {
+    let mut output = Vec::new();
+    output.extend_from_slice(&id.to_be_bytes());
+    output.extend_from_slice(&flags.to_be_bytes());
+    output.extend_from_slice(&(question.is_some() as u16).to_be_bytes());
+    for (_, count) in &sections {
+        output.extend_from_slice(&count.to_be_bytes());
+    }
+    if let Some(question) = question {
+        write_name(&mut output, &question.name);
+        output.extend_from_slice(&question.r#type.to_be_bytes());
+        output.extend_from_slice(&question.class.to_be_bytes());
+    }
+    for (records, _) in &sections {
+        output.extend_from_slice(records);
+    }
+    output
+}
@@ -0,0 +1,1250 @@
+// Authoritative zones, loaded from RFC 1035 master files.
+use rustdns::{Class, Question, Rcode, Record, Resource, Type, MX, SOA, SRV, TXT};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_to_string, rename, write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::convert::{field_str, field_u64};
+use crate::dnssec::RR_DS;
+use crate::signer::ZoneSigner;
+use crate::update::{restore, UpdatePolicy};
+use crate::wire::write_record;
+
+// How many CNAMEs are followed inside a zone before giving up
+pub const MAX_CNAME_CHAIN: usize = 8;
+
+// Lowercases a name and makes sure it ends with a dot.
+pub fn normalize(name: &str) -> String {
+    let mut name = name.to_lowercase();
+    if !name.ends_with('.') {
+        name.push('.');
+    }
+    name
+}
+
+// Whether `name` is `origin` or below it. Both must be normalized.
+pub fn is_in_zone(name: &str, origin: &str) -> bool {
+    origin == "." || name == origin || name.ends_with(&format!(".{}", origin))
+}
+
+// The name's parent, e.g. `example.lab.` for `www.example.lab.`.
+fn parent(name: &str) -> Option<&str> {
+    match name.find('.') {
+        Some(i) if i + 1 < name.len() => Some(&name[i + 1..]),
+        Some(_) if name != "." => Some("."),
+        _ => None,
+    }
+}
+
+pub fn record_type(resource: &Resource) -> Option<Type> {
+    Some(match resource {
+        Resource::A(_) => Type::A,
+        Resource::AAAA(_) => Type::AAAA,
+        Resource::CNAME(_) => Type::CNAME,
+        Resource::NS(_) => Type::NS,
+        Resource::PTR(_) => Type::PTR,
+        Resource::MX(_) => Type::MX,
+        Resource::TXT(_) => Type::TXT,
+        Resource::SRV(_) => Type::SRV,
+        Resource::SOA(_) => Type::SOA,
+        _ => return None,
+    })
+}
+
+pub struct ZoneAnswer {
+    pub records: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+    pub rcode: Rcode,
+    pub authoritative: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ZoneKind {
+    // Loaded from a master file. Its records answer before the universal bindings.
+    Loaded,
+    // Declared from JS. The bindings answer, the zone only adds its apex and negative answers.
+    Declared,
+}
+
+impl ZoneKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ZoneKind::Loaded => "loaded",
+            ZoneKind::Declared => "declared",
+        }
+    }
+}
+
+// How many serial changes are kept for IXFR
+const MAX_HISTORY: usize = 32;
+
+// What changed between two versions of a zone, for IXFR.
+#[derive(Clone)]
+pub struct ZoneDiff {
+    pub from_soa: Record,
+    pub to_soa: Record,
+    pub removed: Vec<Record>,
+    pub added: Vec<Record>,
+}
+
+// Whether serial `a` is newer than `b`, using RFC 1982 serial number arithmetic.
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+// Identifies a record by its wire form with a lowercased owner name.
+pub fn record_key(record: &Record) -> Option<Vec<u8>> {
+    let mut key = Vec::new();
+    let lowercased = Record {
+        name: normalize(&record.name),
+        ..record.clone()
+    };
+    write_record(&mut key, &lowercased)?;
+    Some(key)
+}
+
+pub struct Zone {
+    // Normalized, e.g. `example.lab.`
+    pub origin: String,
+    pub kind: ZoneKind,
+    // Oldest first
+    pub history: Vec<ZoneDiff>,
+    // Keyed by the normalized owner name
+    records: HashMap<String, Vec<Record>>,
+    // Every name that exists in the zone, including empty non-terminals
+    names: HashSet<String>,
+}
+
+impl Zone {
+    pub fn new(origin: &str, kind: ZoneKind, records: Vec<Record>) -> Result<Zone, String> {
+        let mut zone = Zone {
+            origin: normalize(origin),
+            kind,
+            history: Vec::new(),
+            records: HashMap::new(),
+            names: HashSet::new(),
+        };
+        for record in records {
+            let owner = normalize(&record.name);
+            if !is_in_zone(&owner, &zone.origin) {
+                warn!(target: "zone", zone = %zone.origin, "Ignoring out-of-zone record {}", record.name);
+                continue;
+            }
+            zone.records.entry(owner).or_default().push(record);
+        }
+        if zone.soa().is_none() {
+            return Err(format!("{} has no SOA record at its apex", zone.origin));
+        }
+        zone.index_names();
+        Ok(zone)
+    }
+
+    fn index_names(&mut self) {
+        self.names.clear();
+        for owner in self.records.keys() {
+            let mut name = Some(owner.as_str());
+            while let Some(current) = name {
+                if !self.names.insert(current.to_string()) || current == self.origin {
+                    break;
+                }
+                name = parent(current);
+            }
+        }
+    }
+
+    fn at(&self, name: &str, r#type: Type) -> Vec<Record> {
+        self.records
+            .get(name)
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|e| record_type(&e.resource) == Some(r#type))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn soa(&self) -> Option<Record> {
+        self.at(&self.origin, Type::SOA).into_iter().next()
+    }
+
+    pub fn serial(&self) -> u32 {
+        match self.soa().map(|e| e.resource) {
+            Some(Resource::SOA(soa)) => soa.serial,
+            _ => 0,
+        }
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.records.values().flatten()
+    }
+
+    // The records that differ between this zone and a newer version of it, SOA excluded.
+    pub fn diff(&self, newer: &Zone) -> Option<ZoneDiff> {
+        let keyed = |zone: &Zone| -> HashMap<Vec<u8>, Record> {
+            zone.records()
+                .filter(|e| record_type(&e.resource) != Some(Type::SOA))
+                .filter_map(|e| Some((record_key(e)?, e.clone())))
+                .collect()
+        };
+        let old = keyed(self);
+        let new = keyed(newer);
+        Some(ZoneDiff {
+            from_soa: self.soa()?,
+            to_soa: newer.soa()?,
+            removed: old
+                .iter()
+                .filter(|(key, _)| !new.contains_key(*key))
+                .map(|(_, record)| record.clone())
+                .collect(),
+            added: new
+                .iter()
+                .filter(|(key, _)| !old.contains_key(*key))
+                .map(|(_, record)| record.clone())
+                .collect(),
+        })
+    }
+
+    pub fn apex_ns(&self) -> Vec<Record> {
+        self.at(&self.origin, Type::NS)
+    }
+
+    // The SOA to put in the authority section of negative answers, with the TTL from RFC 2308.
+    fn negative_soa(&self) -> Vec<Record> {
+        let mut soa = match self.soa() {
+            Some(e) => e,
+            None => return Vec::new(),
+        };
+        if let Resource::SOA(data) = &soa.resource {
+            soa.ttl = soa.ttl.min(data.minimum);
+        }
+        vec![soa]
+    }
+
+    // The TTL of negative answers from the zone (RFC 2308).
+    pub fn negative_ttl(&self) -> u32 {
+        self.negative_soa()
+            .first()
+            .map_or(0, |e| e.ttl.as_secs() as u32)
+    }
+
+    // The types of the records owned by `name`, synthesized from a wildcard if needed.
+    pub fn types_at(&self, name: &str) -> Vec<u16> {
+        self.records_for(name, name)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|e| record_type(&e.resource))
+            .map(|e| e as u16)
+            .collect()
+    }
+
+    // A and AAAA records for names inside the zone, for the additional section.
+    fn glue(&self, records: &[Record]) -> Vec<Record> {
+        let mut glue = Vec::new();
+        for record in records {
+            if let Resource::NS(target)
+            | Resource::MX(MX {
+                exchange: target, ..
+            }) = &record.resource
+            {
+                let target = normalize(target);
+                glue.extend(self.at(&target, Type::A));
+                glue.extend(self.at(&target, Type::AAAA));
+            }
+        }
+        glue
+    }
+
+    // The topmost delegation between the apex and `name`, if any.
+    fn find_cut(&self, name: &str) -> Option<String> {
+        let mut cut = None;
+        let mut current = Some(name);
+        while let Some(e) = current {
+            if e == self.origin {
+                break;
+            }
+            if !self.at(e, Type::NS).is_empty() {
+                cut = Some(e.to_string());
+            }
+            current = parent(e);
+        }
+        cut
+    }
+
+    // The records owned by `name`, synthesized from a wildcard if needed. None if the name
+    // doesn't exist at all.
+    fn records_for(&self, name: &str, display_name: &str) -> Option<Vec<Record>> {
+        if let Some(records) = self.records.get(name) {
+            return Some(records.clone());
+        }
+        if self.names.contains(name) {
+            return Some(Vec::new());
+        }
+        let mut encloser = parent(name);
+        while let Some(e) = encloser {
+            if self.names.contains(e) {
+                let wildcard = self.records.get(&format!("*.{}", e))?;
+                let synthesized = wildcard
+                    .iter()
+                    .map(|record| Record {
+                        name: display_name.to_string(),
+                        ..record.clone()
+                    })
+                    .collect();
+                return Some(synthesized);
+            }
+            if e == self.origin {
+                break;
+            }
+            encloser = parent(e);
+        }
+        None
+    }
+
+    // Answers from the zone's records. `name_exists` tells that the queried name exists outside
+    // of them, e.g. as a JS binding for another type, so that it gets NODATA instead of NXDOMAIN.
+    pub fn answer(&self, question: &Question, name_exists: bool) -> ZoneAnswer {
+        let mut answer = ZoneAnswer {
+            records: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            rcode: Rcode::NoError,
+            authoritative: true,
+        };
+        let mut name = normalize(&question.name);
+        let mut display_name = question.name.clone();
+
+        // DS records at a delegation are the parent's to answer (RFC 4035 section 3.1.4.1)
+        let cut = self
+            .find_cut(&name)
+            .filter(|cut| !(question.r#type as u16 == RR_DS && *cut == name));
+        if let Some(cut) = cut {
+            answer.authoritative = false;
+            answer.authorities = self.at(&cut, Type::NS);
+            answer.additionals = self.glue(&answer.authorities);
+            return answer;
+        }
+
+        for link in 0..MAX_CNAME_CHAIN {
+            let records = match self.records_for(&name, &display_name) {
+                Some(e) => e,
+                None if link == 0 && name_exists => Vec::new(),
+                None => {
+                    answer.rcode = Rcode::NXDomain;
+                    answer.authorities = self.negative_soa();
+                    return answer;
+                }
+            };
+            let matching: Vec<Record> = records
+                .iter()
+                .filter(|e| record_type(&e.resource) == Some(question.r#type))
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                answer.records.extend(matching);
+                break;
+            }
+            let cname = records.iter().find_map(|e| match &e.resource {
+                Resource::CNAME(target) => Some((e.clone(), target.clone())),
+                _ => None,
+            });
+            match cname {
+                Some((record, target)) => {
+                    answer.records.push(record);
+                    name = normalize(&target);
+                    display_name = target;
+                    if !is_in_zone(&name, &self.origin) {
+                        break;
+                    }
+                }
+                None => {
+                    answer.authorities = self.negative_soa();
+                    return answer;
+                }
+            }
+        }
+
+        answer.authorities = self.apex_ns();
+        answer.additionals = self.glue(&answer.records);
+        answer.additionals.extend(self.glue(&answer.authorities));
+        answer
+    }
+}
+
+// All zones a bridge is authoritative for.
+#[derive(Default)]
+pub struct ZoneSet {
+    zones: Vec<Zone>,
+    // Networks allowed to transfer each zone, as (address, prefix length)
+    transfer_acls: HashMap<String, Vec<(IpAddr, u8)>>,
+    // TSIG keys allowed to transfer each zone, from any address
+    transfer_keys: HashMap<String, Vec<String>>,
+    update_policies: HashMap<String, UpdatePolicy>,
+    signers: HashMap<String, ZoneSigner>,
+}
+
+fn parse_network(text: &str) -> Result<(IpAddr, u8), String> {
+    let invalid = || format!("invalid address or network {:?}", text);
+    let (address, prefix) = match text.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (text, None),
+    };
+    let address: IpAddr = address.parse().map_err(|_| invalid())?;
+    let maximum = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(e) => e
+            .parse()
+            .ok()
+            .filter(|e| *e <= maximum)
+            .ok_or_else(invalid)?,
+        None => maximum,
+    };
+    Ok((address, prefix))
+}
+
+fn in_network(ip: IpAddr, network: &(IpAddr, u8)) -> bool {
+    let mask = |bits: u8, width: u8| -> u128 {
+        if bits == 0 {
+            0
+        } else {
+            u128::MAX << (width - bits)
+        }
+    };
+    match (ip, network.0) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = mask(network.1, 32);
+            (u32::from(ip) as u128) & mask == (u32::from(net) as u128) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = mask(network.1, 128);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+impl ZoneSet {
+    // Adds the zone, replacing the one with the same origin. If the serial went up, the changes
+    // are recorded for IXFR.
+    pub fn insert(&mut self, mut zone: Zone) {
+        if let Some(old) = self.get(&zone.origin) {
+            if serial_newer(zone.serial(), old.serial()) {
+                zone.history = old.history.clone();
+                zone.history.extend(old.diff(&zone));
+                let excess = zone.history.len().saturating_sub(MAX_HISTORY);
+                zone.history.drain(..excess);
+            }
+        }
+        self.zones.retain(|e| e.origin != zone.origin);
+        self.zones.push(zone);
+    }
+
+    pub fn get(&self, origin: &str) -> Option<&Zone> {
+        let origin = normalize(origin);
+        self.zones.iter().find(|zone| zone.origin == origin)
+    }
+
+    // Clients are addresses, networks, or `key:<name>` for requests signed with a TSIG key.
+    pub fn allow_transfer(&mut self, origin: &str, clients: &[String]) -> Result<(), String> {
+        let origin = normalize(origin);
+        for client in clients {
+            match client.strip_prefix("key:") {
+                Some(key) => self
+                    .transfer_keys
+                    .entry(origin.clone())
+                    .or_default()
+                    .push(normalize(key)),
+                None => self
+                    .transfer_acls
+                    .entry(origin.clone())
+                    .or_default()
+                    .push(parse_network(client)?),
+            }
+        }
+        Ok(())
+    }
+
+    // `key` is the name of the TSIG key the request was signed with, if any.
+    pub fn may_transfer(&self, origin: &str, client: IpAddr, key: Option<&str>) -> bool {
+        // IPv4 clients on dual-stack sockets show up as mapped IPv6 addresses
+        let client = match client {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(client),
+            ip => ip,
+        };
+        let origin = normalize(origin);
+        let by_key = match (key, self.transfer_keys.get(&origin)) {
+            (Some(key), Some(keys)) => keys.iter().any(|e| e == key),
+            _ => false,
+        };
+        by_key
+            || self
+                .transfer_acls
+                .get(&origin)
+                .map(|networks| networks.iter().any(|e| in_network(client, e)))
+                .unwrap_or(false)
+    }
+
+    // Accepts dynamic updates for a declared zone. Its saved contents, if any, replace the
+    // declared ones.
+    pub fn allow_update(&mut self, origin: &str, policy: UpdatePolicy) -> Result<(), String> {
+        let origin = normalize(origin);
+        let declared = self
+            .get(&origin)
+            .filter(|e| e.kind == ZoneKind::Declared)
+            .ok_or_else(|| format!("{} must be declared with declareZone() first", origin))?;
+        if let Some(file) = policy.file.as_ref().filter(|e| Path::new(e).exists()) {
+            let restored = restore(declared, load_zone(&origin, file)?)?;
+            self.zones.retain(|e| e.origin != origin);
+            self.zones.push(restored);
+        }
+        self.update_policies.insert(origin, policy);
+        Ok(())
+    }
+
+    pub fn update_policy(&self, origin: &str) -> Option<&UpdatePolicy> {
+        self.update_policies.get(&normalize(origin))
+    }
+
+    pub fn sign_zone(&mut self, origin: &str, signer: ZoneSigner) {
+        self.signers.insert(normalize(origin), signer);
+    }
+
+    pub fn signer(&self, origin: &str) -> Option<&ZoneSigner> {
+        self.signers.get(&normalize(origin))
+    }
+
+    // The most specific zone containing the name.
+    pub fn find(&self, name: &str) -> Option<&Zone> {
+        let name = normalize(name);
+        self.zones
+            .iter()
+            .filter(|zone| is_in_zone(&name, &zone.origin))
+            .max_by_key(|zone| zone.origin.len())
+    }
+}
+
+// ==================================== Master file parsing ====================================
+
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+struct Line {
+    number: usize,
+    // Lines starting with a blank reuse the previous owner name
+    continues_owner: bool,
+    tokens: Vec<Token>,
+}
+
+// Splits a master file into logical lines, joining parenthesized groups and dropping comments.
+fn tokenize(contents: &str) -> Result<Vec<Line>, String> {
+    let mut lines = Vec::new();
+    let mut chars = contents.chars().peekable();
+    let mut number = 1;
+    let mut depth = 0;
+    let mut current: Option<Line> = None;
+    let mut at_line_start = true;
+
+    while let Some(c) = chars.next() {
+        if at_line_start && depth == 0 {
+            at_line_start = false;
+            if let Some(line) = current.take() {
+                if !line.tokens.is_empty() {
+                    lines.push(line);
+                }
+            }
+            current = Some(Line {
+                number,
+                continues_owner: c == ' ' || c == '\t',
+                tokens: Vec::new(),
+            });
+        }
+        let line = current.as_mut().unwrap();
+        match c {
+            '\n' => {
+                number += 1;
+                at_line_start = depth == 0;
+            }
+            ' ' | '\t' | '\r' => {}
+            ';' => {
+                while chars.peek().map(|e| *e != '\n').unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            '(' => depth += 1,
+            ')' => {
+                if depth == 0 {
+                    return Err(format!("line {}: unbalanced parenthesis", number));
+                }
+                depth -= 1;
+                // A group closed on a later line ends with that line
+                at_line_start = false;
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.push(chars.next().unwrap_or('\\')),
+                        Some('\n') => return Err(format!("line {}: unterminated string", number)),
+                        Some(e) => text.push(e),
+                        None => return Err(format!("line {}: unterminated string", number)),
+                    }
+                }
+                line.tokens.push(Token { text, quoted: true });
+            }
+            _ => {
+                let mut text = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "();\"".contains(next) {
+                        break;
+                    }
+                    text.push(next);
+                    chars.next();
+                }
+                line.tokens.push(Token {
+                    text,
+                    quoted: false,
+                });
+            }
+        }
+    }
+    if depth != 0 {
+        return Err(format!("line {}: unbalanced parenthesis", number));
+    }
+    if let Some(line) = current {
+        if !line.tokens.is_empty() {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+// Parses a TTL, either in seconds or with BIND's unit suffixes, e.g. `1h30m`.
+pub fn parse_ttl(text: &str) -> Option<u32> {
+    if let Ok(seconds) = text.parse() {
+        return Some(seconds);
+    }
+    let mut total: u32 = 0;
+    let mut value: u32 = 0;
+    let mut has_digits = false;
+    for c in text.to_lowercase().chars() {
+        if let Some(digit) = c.to_digit(10) {
+            value = value.checked_mul(10)?.checked_add(digit)?;
+            has_digits = true;
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        if !has_digits {
+            return None;
+        }
+        total = total.checked_add(value.checked_mul(unit)?)?;
+        value = 0;
+        has_digits = false;
+    }
+    if has_digits {
+        return None;
+    }
+    Some(total)
+}
+
+fn parse_type(text: &str) -> Option<Type> {
+    Some(match text.to_uppercase().as_str() {
+        "A" => Type::A,
+        "AAAA" => Type::AAAA,
+        "CNAME" => Type::CNAME,
+        "NS" => Type::NS,
+        "PTR" => Type::PTR,
+        "MX" => Type::MX,
+        "TXT" => Type::TXT,
+        "SRV" => Type::SRV,
+        "SOA" => Type::SOA,
+        _ => return None,
+    })
+}
+
+// Makes a name from a zone file absolute. `@` stands for the origin.
+fn absolute(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_string()
+    } else if name.ends_with('.') {
+        name.to_string()
+    } else if origin == "." {
+        format!("{}.", name)
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+struct ParserState {
+    origin: String,
+    default_ttl: Option<u32>,
+    last_owner: Option<String>,
+    records: Vec<Record>,
+}
+
+fn parse_rdata(r#type: Type, data: &[Token], origin: &str) -> Result<Resource, String> {
+    let get = |i: usize| -> Result<&str, String> {
+        data.get(i)
+            .map(|e| e.text.as_str())
+            .ok_or_else(|| format!("missing {:?} data", r#type))
+    };
+    let number = |i: usize| -> Result<u16, String> {
+        get(i)?
+            .parse()
+            .map_err(|_| format!("invalid number {:?}", get(i).unwrap_or("")))
+    };
+    let duration = |i: usize| -> Result<Duration, String> {
+        let text = get(i)?;
+        parse_ttl(text)
+            .map(|e| Duration::from_secs(e.into()))
+            .ok_or_else(|| format!("invalid time {:?}", text))
+    };
+    let expected = match r#type {
+        Type::A | Type::AAAA | Type::CNAME | Type::NS | Type::PTR => 1,
+        Type::MX => 2,
+        Type::SRV => 4,
+        Type::SOA => 7,
+        _ => data.len(),
+    };
+    if data.len() != expected {
+        return Err(format!(
+            "{:?} takes {} fields, got {}",
+            r#type,
+            expected,
+            data.len()
+        ));
+    }
+    Ok(match r#type {
+        Type::A => Resource::A(get(0)?.parse().map_err(|_| "invalid IPv4 address")?),
+        Type::AAAA => Resource::AAAA(get(0)?.parse().map_err(|_| "invalid IPv6 address")?),
+        Type::CNAME => Resource::CNAME(absolute(get(0)?, origin)),
+        Type::NS => Resource::NS(absolute(get(0)?, origin)),
+        Type::PTR => Resource::PTR(absolute(get(0)?, origin)),
+        Type::MX => Resource::MX(MX {
+            preference: number(0)?,
+            exchange: absolute(get(1)?, origin),
+        }),
+        Type::SRV => Resource::SRV(SRV {
+            priority: number(0)?,
+            weight: number(1)?,
+            port: number(2)?,
+            name: absolute(get(3)?, origin),
+        }),
+        Type::TXT => {
+            if data.is_empty() {
+                return Err("TXT needs at least one string".to_string());
+            }
+            Resource::TXT(TXT(data
+                .iter()
+                .map(|e| e.text.as_bytes().to_vec())
+                .collect()))
+        }
+        Type::SOA => Resource::SOA(SOA {
+            mname: absolute(get(0)?, origin),
+            rname: absolute(get(1)?, origin),
+            serial: get(2)?.parse().map_err(|_| "invalid serial")?,
+            refresh: duration(3)?,
+            retry: duration(4)?,
+            expire: duration(5)?,
+            minimum: duration(6)?,
+        }),
+        _ => return Err(format!("unsupported type {:?}", r#type)),
+    })
+}
+
+fn parse_entry(line: &Line, state: &mut ParserState) -> Result<(), String> {
+    let mut tokens = line.tokens.iter();
+    let owner = if line.continues_owner {
+        state
+            .last_owner
+            .clone()
+            .ok_or("no previous owner name to continue")?
+    } else {
+        absolute(&tokens.next().unwrap().text, &state.origin)
+    };
+
+    let mut ttl = None;
+    let mut r#type = None;
+    for token in tokens.by_ref() {
+        if token.quoted {
+            return Err(format!("unexpected string {:?}", token.text));
+        }
+        if let Some(e) = parse_type(&token.text) {
+            r#type = Some(e);
+            break;
+        }
+        if token.text.eq_ignore_ascii_case("IN") {
+            continue;
+        }
+        if ["CH", "HS", "CS"].contains(&token.text.to_uppercase().as_str()) {
+            return Err(format!("unsupported class {}", token.text));
+        }
+        match parse_ttl(&token.text) {
+            Some(e) if ttl.is_none() => ttl = Some(e),
+            _ => return Err(format!("unknown type {:?}", token.text)),
+        }
+    }
+    let r#type = r#type.ok_or("missing type")?;
+    let data: Vec<Token> = tokens
+        .map(|e| Token {
+            text: e.text.clone(),
+            quoted: e.quoted,
+        })
+        .collect();
+    let resource = parse_rdata(r#type, &data, &state.origin)?;
+    let ttl = match (ttl, state.default_ttl, &resource) {
+        (Some(e), _, _) => e,
+        (None, Some(e), _) => e,
+        (None, None, Resource::SOA(soa)) => soa.minimum.as_secs() as u32,
+        _ => return Err("no TTL given and no $TTL set".to_string()),
+    };
+    if state.default_ttl.is_none() {
+        state.default_ttl = Some(ttl);
+    }
+
+    state.last_owner = Some(owner.clone());
+    state.records.push(Record {
+        name: owner,
+        class: Class::Internet,
+        ttl: Duration::from_secs(ttl.into()),
+        resource,
+    });
+    Ok(())
+}
+
+fn parse_file(path: &Path, state: &mut ParserState, depth: usize) -> Result<(), String> {
+    if depth > 8 {
+        return Err(format!("{}: too many nested $INCLUDEs", path.display()));
+    }
+    let contents =
+        read_to_string(path).map_err(|e| format!("cannot read {} ({})", path.display(), e))?;
+    let lines = tokenize(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let at = |line: &Line, e: String| format!("{}:{}: {}", path.display(), line.number, e);
+
+    for line in &lines {
+        let directive = &line.tokens[0].text;
+        if line.continues_owner || !directive.starts_with('$') {
+            parse_entry(line, state).map_err(|e| at(line, e))?;
+            continue;
+        }
+        let argument = line.tokens.get(1).map(|e| e.text.as_str());
+        match (directive.to_uppercase().as_str(), argument) {
+            ("$ORIGIN", Some(origin)) => state.origin = absolute(origin, &state.origin),
+            ("$TTL", Some(ttl)) => {
+                state.default_ttl =
+                    Some(parse_ttl(ttl).ok_or_else(|| at(line, format!("invalid TTL {:?}", ttl)))?)
+            }
+            ("$INCLUDE", Some(file)) => {
+                let included: PathBuf = path.parent().unwrap_or(Path::new(".")).join(file);
+                // The included file's $ORIGIN doesn't carry over to the including one
+                let saved_origin = state.origin.clone();
+                if let Some(origin) = line.tokens.get(2) {
+                    state.origin = absolute(&origin.text, &state.origin);
+                }
+                parse_file(&included, state, depth + 1)?;
+                state.origin = saved_origin;
+            }
+            _ => return Err(at(line, format!("invalid directive {}", directive))),
+        }
+    }
+    Ok(())
+}
+
+// Loads a zone from a master file. Relative names are relative to `origin` until an $ORIGIN.
+pub fn load_zone(origin: &str, file: &str) -> Result<Zone, String> {
+    let origin = normalize(origin);
+    let mut state = ParserState {
+        origin: origin.clone(),
+        default_ttl: None,
+        last_owner: None,
+        records: Vec::new(),
+    };
+    parse_file(Path::new(file), &mut state, 0)?;
+    Zone::new(&origin, ZoneKind::Loaded, state.records)
+}
+
+// Builds a zone with only its apex SOA and NS records, as given to declareZone().
+pub fn declare_zone(origin: &str, soa: &Value, nameservers: &[String]) -> Result<Zone, String> {
+    let origin = normalize(origin);
+    let seconds = |field: &str| -> Result<Duration, String> {
+        Ok(Duration::from_secs(
+            field_u64(soa, field).map_err(|e| e.to_string())?,
+        ))
+    };
+    let text = |field: &str| -> Result<String, String> {
+        Ok(normalize(field_str(soa, field).map_err(|e| e.to_string())?))
+    };
+    let serial = field_u64(soa, "serial").map_err(|e| e.to_string())?;
+    let ttl = seconds("ttl")?;
+    let mut records = vec![Record {
+        name: origin.clone(),
+        class: Class::Internet,
+        ttl,
+        resource: Resource::SOA(SOA {
+            mname: text("mname")?,
+            rname: text("rname")?,
+            serial: u32::try_from(serial).map_err(|_| "serial must fit in 32 bits")?,
+            refresh: seconds("refresh")?,
+            retry: seconds("retry")?,
+            expire: seconds("expire")?,
+            minimum: seconds("minimum")?,
+        }),
+    }];
+    if nameservers.is_empty() {
+        return Err(format!("{} needs at least one nameserver", origin));
+    }
+    for nameserver in nameservers {
+        records.push(Record {
+            name: origin.clone(),
+            class: Class::Internet,
+            ttl,
+            resource: Resource::NS(normalize(nameserver)),
+        });
+    }
+    Zone::new(&origin, ZoneKind::Declared, records)
+}
+
+// ===================================== Master file output =====================================
+
+fn quote(text: &[u8]) -> String {
+    let text = String::from_utf8_lossy(text);
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// The record's data in master file format.
+pub fn format_rdata(resource: &Resource) -> Option<String> {
+    Some(match resource {
+        Resource::A(ip) => ip.to_string(),
+        Resource::AAAA(ip) => ip.to_string(),
+        Resource::CNAME(name) | Resource::NS(name) | Resource::PTR(name) => name.clone(),
+        Resource::MX(mx) => format!("{} {}", mx.preference, mx.exchange),
+        Resource::TXT(txt) => txt.0.iter().map(|e| quote(e)).collect::<Vec<_>>().join(" "),
+        Resource::SRV(srv) => format!("{} {} {} {}", srv.priority, srv.weight, srv.port, srv.name),
+        Resource::SOA(soa) => format!(
+            "{} {} {} {} {} {} {}",
+            soa.mname,
+            soa.rname,
+            soa.serial,
+            soa.refresh.as_secs(),
+            soa.retry.as_secs(),
+            soa.expire.as_secs(),
+            soa.minimum.as_secs()
+        ),
+        _ => return None,
+    })
+}
+
+// Writes the zone as a master file that load_zone() can read back. The file is replaced
+// atomically, so a crash never leaves it half written.
+pub fn save_zone(zone: &Zone, file: &str) -> Result<(), String> {
+    let mut contents = format!(
+        "; {} - written by baDNS\n$ORIGIN {}\n",
+        zone.origin, zone.origin
+    );
+    let mut records: Vec<&Record> = zone.records().collect();
+    // SOA first, then grouped by owner
+    records.sort_by_key(|e| {
+        (
+            record_type(&e.resource) != Some(Type::SOA),
+            normalize(&e.name),
+        )
+    });
+    for record in records {
+        let (r#type, data) = match (
+            record_type(&record.resource),
+            format_rdata(&record.resource),
+        ) {
+            (Some(r#type), Some(data)) => (r#type, data),
+            _ => continue,
+        };
+        contents.push_str(&format!(
+            "{} {} IN {:?} {}\n",
+            record.name,
+            record.ttl.as_secs(),
+            r#type,
+            data
+        ));
+    }
+    let temporary = format!("{}.tmp", file);
+    write(&temporary, contents).map_err(|e| format!("Cannot write {}: {}", temporary, e))?;
+    rename(&temporary, file).map_err(|e| format!("Cannot replace {}: {}", file, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn record(name: &str, ttl: u64, resource: Resource) -> Record {
+        Record {
+            name: name.to_string(),
+            class: Class::Internet,
+            ttl: Duration::from_secs(ttl),
+            resource,
+        }
+    }
+
+    fn a(name: &str, ip: [u8; 4]) -> Record {
+        record(name, 300, Resource::A(Ipv4Addr::from(ip)))
+    }
+
+    fn question(name: &str, r#type: Type) -> Question {
+        Question {
+            name: name.to_string(),
+            r#type,
+            class: Class::Internet,
+        }
+    }
+
+    // The records of the given type owned by `name`.
+    fn find(zone: &Zone, name: &str, r#type: Type) -> Vec<Record> {
+        zone.records()
+            .filter(|e| e.name == name && record_type(&e.resource) == Some(r#type))
+            .cloned()
+            .collect()
+    }
+
+    // The `lab.` zone, with a wildcard, an empty non-terminal and a delegation to `sub.lab.`.
+    fn zone() -> Zone {
+        let soa = SOA {
+            mname: "ns.lab.".to_string(),
+            rname: "admin.lab.".to_string(),
+            serial: 1,
+            refresh: Duration::from_secs(3600),
+            retry: Duration::from_secs(600),
+            expire: Duration::from_secs(86400),
+            minimum: Duration::from_secs(60),
+        };
+        let records = vec![
+            record("lab.", 3600, Resource::SOA(soa)),
+            record("lab.", 3600, Resource::NS("ns.lab.".to_string())),
+            a("ns.lab.", [10, 0, 0, 53]),
+            a("www.lab.", [10, 0, 0, 1]),
+            record("alias.lab.", 300, Resource::CNAME("www.lab.".to_string())),
+            a("*.wild.lab.", [10, 0, 0, 2]),
+            a("host.empty.lab.", [10, 0, 0, 3]),
+            record("sub.lab.", 300, Resource::NS("ns.sub.lab.".to_string())),
+            a("ns.sub.lab.", [10, 0, 1, 53]),
+        ];
+        Zone::new("lab.", ZoneKind::Loaded, records).unwrap()
+    }
+
+    #[test]
+    fn ttls_take_units() {
+        assert_eq!(parse_ttl("3600"), Some(3600));
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("1W2d"), Some(777600));
+        assert_eq!(parse_ttl("45s"), Some(45));
+        assert_eq!(parse_ttl("1h30"), None);
+        assert_eq!(parse_ttl("h"), None);
+        assert_eq!(parse_ttl("1y"), None);
+    }
+
+    #[test]
+    fn master_file_directives() {
+        let directory = std::env::temp_dir().join(format!("badns-zone-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("lab.zone"),
+            "$TTL 1h\n\
+             @ IN SOA ns admin (\n\
+             \t2024010101 ; serial\n\
+             \t2h 30m 1w\n\
+             \t5m )\n\
+             \tIN NS ns\n\
+             ns A 10.0.0.53\n\
+             www 300 IN A 10.0.0.1 ; the web server\n\
+             \tTXT \"hello world\" \"a \\\"quoted\\\" string\"\n\
+             $ORIGIN sub.lab.\n\
+             host A 10.0.1.1\n\
+             $INCLUDE extra.zone inc\n\
+             after A 10.0.1.2\n",
+        )
+        .unwrap();
+        std::fs::write(directory.join("extra.zone"), "host 1d A 10.0.2.1\n").unwrap();
+        std::fs::write(
+            directory.join("unbalanced.zone"),
+            "@ SOA ns admin ( 1 2 3 4 5\n",
+        )
+        .unwrap();
+        std::fs::write(directory.join("no-ttl.zone"), "www A 10.0.0.1\n").unwrap();
+
+        let path = |file: &str| directory.join(file).to_string_lossy().to_string();
+        let zone = load_zone("lab", &path("lab.zone"));
+        let unbalanced = load_zone("lab.", &path("unbalanced.zone"));
+        let no_ttl = load_zone("lab.", &path("no-ttl.zone"));
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let zone = zone.unwrap();
+        assert_eq!(zone.origin, "lab.");
+        let soa = zone.soa().unwrap();
+        assert_eq!(soa.ttl.as_secs(), 3600);
+        match soa.resource {
+            Resource::SOA(soa) => {
+                assert_eq!(
+                    (soa.mname.as_str(), soa.rname.as_str()),
+                    ("ns.lab.", "admin.lab.")
+                );
+                assert_eq!(soa.serial, 2024010101);
+                assert_eq!(soa.refresh.as_secs(), 7200);
+                assert_eq!(soa.retry.as_secs(), 1800);
+                assert_eq!(soa.expire.as_secs(), 604800);
+                assert_eq!(soa.minimum.as_secs(), 300);
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            zone.apex_ns(),
+            vec![record("lab.", 3600, Resource::NS("ns.lab.".to_string()))]
+        );
+        assert_eq!(
+            find(&zone, "www.lab.", Type::A),
+            vec![a("www.lab.", [10, 0, 0, 1])]
+        );
+        // A line starting with a blank continues the previous owner, with the $TTL default
+        assert_eq!(
+            find(&zone, "www.lab.", Type::TXT),
+            vec![record(
+                "www.lab.",
+                3600,
+                Resource::TXT(TXT(vec![
+                    b"hello world".to_vec(),
+                    b"a \"quoted\" string".to_vec()
+                ]))
+            )]
+        );
+        assert_eq!(find(&zone, "host.sub.lab.", Type::A).len(), 1);
+        assert_eq!(
+            find(&zone, "host.inc.sub.lab.", Type::A),
+            vec![record(
+                "host.inc.sub.lab.",
+                86400,
+                Resource::A(Ipv4Addr::new(10, 0, 2, 1))
+            )]
+        );
+        // The $INCLUDE's origin doesn't carry over
+        assert_eq!(find(&zone, "after.sub.lab.", Type::A).len(), 1);
+
+        assert!(matches!(unbalanced, Err(e) if e.contains("unbalanced parenthesis")));
+        assert!(matches!(no_ttl, Err(e) if e.contains("no TTL given")));
+    }
+
+    #[test]
+    fn existing_names_are_answered() {
+        let zone = zone();
+        let answer = zone.answer(&question("www.lab.", Type::A), false);
+        assert_eq!(answer.rcode, Rcode::NoError);
+        assert!(answer.authoritative);
+        assert_eq!(answer.records, vec![a("www.lab.", [10, 0, 0, 1])]);
+        assert_eq!(answer.authorities, zone.apex_ns());
+        assert_eq!(answer.additionals, vec![a("ns.lab.", [10, 0, 0, 53])]);
+
+        let answer = zone.answer(&question("alias.lab.", Type::A), false);
+        assert_eq!(answer.records.len(), 2);
+        assert_eq!(answer.records[1], a("www.lab.", [10, 0, 0, 1]));
+    }
+
+    #[test]
+    fn negative_answers() {
+        let zone = zone();
+        let negative_soa = |answer: &ZoneAnswer| {
+            assert!(answer.records.is_empty());
+            assert_eq!(answer.authorities.len(), 1);
+            // The SOA's TTL is capped by its minimum
+            assert_eq!(answer.authorities[0].ttl.as_secs(), 60);
+        };
+
+        let nxdomain = zone.answer(&question("missing.lab.", Type::A), false);
+        assert_eq!(nxdomain.rcode, Rcode::NXDomain);
+        negative_soa(&nxdomain);
+
+        let nodata = zone.answer(&question("www.lab.", Type::MX), false);
+        assert_eq!(nodata.rcode, Rcode::NoError);
+        negative_soa(&nodata);
+
+        // Empty non-terminals exist
+        let empty = zone.answer(&question("empty.lab.", Type::A), false);
+        assert_eq!(empty.rcode, Rcode::NoError);
+        negative_soa(&empty);
+        assert_eq!(zone.negative_ttl(), 60);
+    }
+
+    #[test]
+    fn wildcards_are_synthesized() {
+        let zone = zone();
+        let answer = zone.answer(&question("Any.Wild.lab.", Type::A), false);
+        assert_eq!(answer.records, vec![a("Any.Wild.lab.", [10, 0, 0, 2])]);
+
+        // Not below existing names
+        let answer = zone.answer(&question("host.empty.lab.", Type::AAAA), false);
+        assert_eq!(answer.rcode, Rcode::NoError);
+        assert!(answer.records.is_empty());
+        let answer = zone.answer(&question("other.host.empty.lab.", Type::A), false);
+        assert_eq!(answer.rcode, Rcode::NXDomain);
+    }
+
+    #[test]
+    fn delegations_are_referred() {
+        let zone = zone();
+        for name in ["sub.lab.", "deep.host.sub.lab."] {
+            let answer = zone.answer(&question(name, Type::A), false);
+            assert_eq!(answer.rcode, Rcode::NoError);
+            assert!(!answer.authoritative);
+            assert!(answer.records.is_empty());
+            assert_eq!(
+                answer.authorities,
+                vec![record(
+                    "sub.lab.",
+                    300,
+                    Resource::NS("ns.sub.lab.".to_string())
+                )]
+            );
+            assert_eq!(answer.additionals, vec![a("ns.sub.lab.", [10, 0, 1, 53])]);
+        }
+    }
+
+    #[test]
+    fn declared_zones_deny_unbound_names() {
+        let soa = serde_json::json!({
+            "mname": "ns1.js.lab", "rname": "admin.js.lab", "serial": 7, "ttl": 600,
+            "refresh": 3600, "retry": 600, "expire": 86400, "minimum": 30,
+        });
+        let zone = declare_zone("JS.lab", &soa, &["ns1.js.lab".to_string()]).unwrap();
+        assert_eq!(zone.origin, "js.lab.");
+        assert_eq!(zone.serial(), 7);
+        assert_eq!(zone.negative_ttl(), 30);
+
+        let apex = zone.answer(&question("js.lab.", Type::NS), false);
+        assert_eq!(apex.records, zone.apex_ns());
+        // Names the bindings answer for get NODATA for the other types
+        let bound = zone.answer(&question("bound.js.lab.", Type::AAAA), true);
+        assert_eq!(bound.rcode, Rcode::NoError);
+        assert!(bound.records.is_empty());
+        assert_eq!(bound.authorities.len(), 1);
+        let unbound = zone.answer(&question("unbound.js.lab.", Type::A), false);
+        assert_eq!(unbound.rcode, Rcode::NXDomain);
+
+        assert!(declare_zone("js.lab.", &soa, &[]).is_err());
+        let mut wide = soa.clone();
+        wide["serial"] = serde_json::json!(1u64 << 32);
+        assert!(declare_zone("js.lab.", &wide, &["ns1.js.lab".to_string()]).is_err());
+        let mut missing = soa;
+        missing.as_object_mut().unwrap().remove("minimum");
+        assert!(declare_zone("js.lab.", &missing, &["ns1.js.lab".to_string()]).is_err());
+    }
+}